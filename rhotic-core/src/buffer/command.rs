@@ -0,0 +1,253 @@
+use std::{error::Error, fmt::Display, path::PathBuf};
+
+use super::stage::{Stage, StateCommand};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ArgKind {
+    Text,
+    Path,
+    Integer,
+    Bool,
+    // One of a fixed set of words.
+    Choice(&'static [&'static str])
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool
+}
+
+impl ArgSpec {
+    pub const fn new(name: &'static str, kind: ArgKind) -> Self {
+        Self { name, kind, optional: false }
+    }
+
+    pub const fn optional(name: &'static str, kind: ArgKind) -> Self {
+        Self { name, kind, optional: true }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum ArgValue {
+    Text(String),
+    Path(PathBuf),
+    Integer(i64),
+    Bool(bool)
+}
+
+// Arguments parsed against a function's specs, one per spec. Optional ones
+// that weren't given are None.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Args(pub Vec<Option<ArgValue>>);
+
+impl Args {
+    pub fn text(&self, i: usize) -> Option<&str> {
+        match self.0.get(i)? {
+            Some(ArgValue::Text(s)) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn path(&self, i: usize) -> Option<&PathBuf> {
+        match self.0.get(i)? {
+            Some(ArgValue::Path(p)) => Some(p),
+            _ => None
+        }
+    }
+
+    pub fn integer(&self, i: usize) -> Option<i64> {
+        match self.0.get(i)? {
+            Some(ArgValue::Integer(n)) => Some(*n),
+            _ => None
+        }
+    }
+
+    pub fn bool(&self, i: usize) -> Option<bool> {
+        match self.0.get(i)? {
+            Some(ArgValue::Bool(b)) => Some(*b),
+            _ => None
+        }
+    }
+}
+
+// A named function a stage can run, from a key binding or M-x. Functions
+// without required arguments can be bound to keys.
+pub struct Function<T> {
+    pub name: &'static str,
+    pub args: &'static [ArgSpec],
+    pub run: fn(&mut T, &Args) -> StateCommand
+}
+
+impl<T> Function<T> {
+    pub const fn new(name: &'static str, run: fn(&mut T, &Args) -> StateCommand) -> Self {
+        Self { name, args: &[], run }
+    }
+
+    pub const fn with_args(name: &'static str, args: &'static [ArgSpec], run: fn(&mut T, &Args) -> StateCommand) -> Self {
+        Self { name, args, run }
+    }
+
+    pub fn is_bindable(&self) -> bool {
+        self.args.iter().all(|a| a.optional)
+    }
+}
+
+pub fn parse_args(specs: &[ArgSpec], raw: &[&str]) -> Result<Args, ArgError> {
+    if raw.len() > specs.len() {
+        return Err(ArgError::TooMany { expected: specs.len(), found: raw.len() });
+    }
+
+    let mut out = Vec::with_capacity(specs.len());
+
+    for (i, spec) in specs.iter().enumerate() {
+        let raw = match raw.get(i) {
+            Some(r) => *r,
+            None if spec.optional => {
+                out.push(None);
+                continue;
+            },
+            None => return Err(ArgError::Missing(spec.name))
+        };
+
+        let invalid = || ArgError::Invalid { name: spec.name, value: raw.to_string(), kind: spec.kind };
+
+        out.push(Some(match spec.kind {
+            ArgKind::Text => ArgValue::Text(raw.to_string()),
+            ArgKind::Path => ArgValue::Path(PathBuf::from(raw)),
+            ArgKind::Integer => ArgValue::Integer(raw.parse().map_err(|_| invalid())?),
+            ArgKind::Bool => ArgValue::Bool(match raw.to_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => true,
+                "false" | "no" | "off" | "0" => false,
+                _ => return Err(invalid())
+            }),
+            ArgKind::Choice(choices) => match choices.iter().find(|c| c.eq_ignore_ascii_case(raw)) {
+                Some(c) => ArgValue::Text(c.to_string()),
+                None => return Err(invalid())
+            }
+        }));
+    }
+
+    Ok(Args(out))
+}
+
+// Runs the function named by the first word with the rest as its arguments.
+// None if there is no function with that name.
+pub fn call<T>(functions: &[Function<T>], stage: &mut T, line: &[&str]) -> Option<StateCommand> {
+    let (name, raw) = line.split_first()?;
+    let function = functions.iter().find(|f| f.name == *name)?;
+
+    Some(match parse_args(function.args, raw) {
+        Ok(args) => (function.run)(stage, &args),
+        Err(e) => StateCommand::Log(format!("{name}: {e}"))
+    })
+}
+
+// Handles `InputEvent::Command` for a stage: runs the named function, or
+// says that the stage has none by that name.
+pub fn run<T: Stage>(stage: &mut T, line: &[&str]) -> StateCommand {
+    match line.first() {
+        Some(name) => call(T::functions(), stage, line)
+            .unwrap_or_else(|| StateCommand::Log(format!("Unknown command \"{name}\" for the {}.", T::NAME))),
+        None => StateCommand::None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ArgError {
+    Missing(&'static str),
+    TooMany {
+        expected: usize,
+        found: usize
+    },
+    Invalid {
+        name: &'static str,
+        value: String,
+        kind: ArgKind
+    }
+}
+
+impl Error for ArgError {}
+
+impl Display for ArgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgError::Missing(name) => write!(f, "The {name} argument is missing."),
+            ArgError::TooMany { expected, found } => write!(f, "Takes {expected} arguments, but got {found}."),
+            ArgError::Invalid { name, value, kind } => match kind {
+                ArgKind::Integer => write!(f, "{name} should be a whole number, not \"{value}\"."),
+                ArgKind::Bool => write!(f, "{name} should be true or false, not \"{value}\"."),
+                ArgKind::Choice(choices) => write!(f, "{name} should be one of {}, not \"{value}\".", choices.join(", ")),
+                ArgKind::Text | ArgKind::Path => write!(f, "\"{value}\" isn't a valid {name}.")
+            }
+        }
+    }
+}
+
+// Scores how well `query` fuzzy-matches `candidate`: every char of the query
+// has to appear in order. Runs of consecutive chars and chars starting a word
+// score higher. None if it doesn't match at all.
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let mut score = 0;
+    let mut chars = candidate.char_indices().peekable();
+    let mut previous: Option<usize> = None;
+    let mut last_char = None;
+
+    for q in query.chars() {
+        let q = q.to_ascii_lowercase();
+
+        loop {
+            let (i, c) = chars.next()?;
+            let word_start = i == 0 || matches!(last_char, Some('_' | '-' | ' ' | '/' | '.'));
+            last_char = Some(c);
+
+            if c.to_ascii_lowercase() == q {
+                score += 1;
+                if word_start {
+                    score += 8;
+                }
+                if previous.is_some_and(|p| p + 1 == i) {
+                    score += 5;
+                }
+                previous = Some(i);
+                break;
+            }
+        }
+    }
+
+    // Prefer the shorter of equally good matches.
+    Some(score * 64 - candidate.len() as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SPECS: &[ArgSpec] = &[
+        ArgSpec::new("line", ArgKind::Integer),
+        ArgSpec::new("ending", ArgKind::Choice(&["lf", "crlf"])),
+        ArgSpec::optional("force", ArgKind::Bool)
+    ];
+
+    #[test]
+    fn parse() {
+        let args = parse_args(SPECS, &["12", "CRLF"]).unwrap();
+        assert_eq!(args.integer(0), Some(12));
+        assert_eq!(args.text(1), Some("crlf"));
+        assert_eq!(args.bool(2), None);
+
+        assert!(matches!(parse_args(SPECS, &["12"]), Err(ArgError::Missing("ending"))));
+        assert!(matches!(parse_args(SPECS, &["x", "lf"]), Err(ArgError::Invalid { .. })));
+        assert!(matches!(parse_args(SPECS, &["1", "lf", "yes", "4"]), Err(ArgError::TooMany { .. })));
+    }
+
+    #[test]
+    fn fuzzy() {
+        assert!(fuzzy_score("ml", "move_left").is_some());
+        assert!(fuzzy_score("lm", "move_left").is_none());
+        assert!(fuzzy_score("ml", "move_left") > fuzzy_score("ml", "xmxl"));
+        assert!(fuzzy_score("save", "save") > fuzzy_score("save", "save_as"));
+        assert!(fuzzy_score("", "anything").is_some());
+    }
+}
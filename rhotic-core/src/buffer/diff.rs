@@ -0,0 +1,143 @@
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum DiffKind {
+    Same,
+    Added,
+    Removed
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DiffLine<'a> {
+    pub kind: DiffKind,
+    pub text: &'a str
+}
+
+// Past this many added and removed lines, `diff_lines` gives up. Its
+// memory grows with their square, and a preview of that many is no help.
+pub const MAX_CHANGES: usize = 1000;
+
+// Line diff from `old` to `new`, using Myers' O(ND) algorithm, or None if
+// it takes more than `MAX_CHANGES` added and removed lines.
+pub fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Option<Vec<DiffLine<'a>>> {
+
+    // Common prefix and suffix are cheap to strip and are usually most of the file.
+    let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+
+    let (a, b) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = ((n + m) as usize).min(MAX_CHANGES);
+    let offset = max as isize + 1;
+
+    // Round d only reads diagonals -d - 1..=d + 1, so that much of `v` is
+    // kept for each, rather than all of it.
+    let mut v = vec![0isize; 2 * max + 3];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    let found = 'search: {
+        for d in 0..=max as isize {
+            trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+
+            for k in (-d..=d).step_by(2) {
+                let index = (k + offset) as usize;
+
+                let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+                    v[index + 1]
+                } else {
+                    v[index - 1] + 1
+                };
+                let mut y = x - k;
+
+                while x < n && y < m && a[x as usize] == b[y as usize] {
+                    x += 1;
+                    y += 1;
+                }
+
+                v[index] = x;
+
+                if x >= n && y >= m {
+                    break 'search true;
+                }
+            }
+        }
+        false
+    };
+    if !found {
+        return None;
+    }
+
+    // Walk the trace backwards to recover the edit script.
+    let mut middle = Vec::new();
+    let (mut x, mut y) = (n, m);
+
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            middle.push(DiffLine { kind: DiffKind::Same, text: a[x as usize] });
+        }
+
+        if d > 0 {
+            if x == prev_x {
+                middle.push(DiffLine { kind: DiffKind::Added, text: b[y as usize - 1] });
+            } else {
+                middle.push(DiffLine { kind: DiffKind::Removed, text: a[x as usize - 1] });
+            }
+        }
+
+        x = prev_x;
+        y = prev_y;
+    }
+    middle.reverse();
+
+    let mut out: Vec<DiffLine> = old[..prefix].iter().map(|t| DiffLine { kind: DiffKind::Same, text: t }).collect();
+    out.extend(middle);
+    out.extend(old[old.len() - suffix..].iter().map(|t| DiffLine { kind: DiffKind::Same, text: t }));
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff_lines, DiffKind::*, MAX_CHANGES};
+
+    #[test]
+    fn diff_round_trips() {
+        let old = ["a", "b", "c", "d", "e", "f"];
+        let new = ["a", "x", "c", "d", "f", "g"];
+
+        let diff = diff_lines(&old, &new).unwrap();
+
+        let rebuilt_old: Vec<&str> = diff.iter().filter(|l| l.kind != Added).map(|l| l.text).collect();
+        let rebuilt_new: Vec<&str> = diff.iter().filter(|l| l.kind != Removed).map(|l| l.text).collect();
+
+        assert_eq!(rebuilt_old, old);
+        assert_eq!(rebuilt_new, new);
+        assert_eq!(diff.iter().filter(|l| l.kind == Same).count(), 4);
+    }
+
+    #[test]
+    fn too_many_changes() {
+        let lines: Vec<String> = (0..100_000).map(|i| i.to_string()).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+        // Every other line changed, up to the limit and past it.
+        let changed: Vec<&str> = lines.iter().enumerate().map(|(i, l)| if i % 2 == 0 { "x" } else { l }).collect();
+        let diff = diff_lines(&lines[..MAX_CHANGES / 2], &changed[..MAX_CHANGES / 2]).unwrap();
+        assert_eq!(diff.iter().filter(|l| l.kind != Same).count(), MAX_CHANGES / 2);
+        assert_eq!(diff_lines(&lines, &changed), None);
+
+        assert_eq!(diff_lines(&lines, &[""]), None);
+    }
+}
@@ -0,0 +1,193 @@
+use std::{collections::HashMap, str::FromStr};
+
+use super::stage::Span;
+use crate::display::{font::Face, Rgba};
+
+// How lines are numbered in the gutter.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum LineNumbers {
+    None,
+    Absolute,
+    // Lines away from the cursor's.
+    Relative,
+    // Relative, but the cursor's line shows its own number.
+    Hybrid
+}
+
+impl FromStr for LineNumbers {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(LineNumbers::None),
+            "absolute" => Ok(LineNumbers::Absolute),
+            "relative" => Ok(LineNumbers::Relative),
+            "hybrid" => Ok(LineNumbers::Hybrid),
+            _ => anyhow::bail!("\"{s}\" is not a way to number lines. Use none, absolute, relative or hybrid.")
+        }
+    }
+}
+
+// A mark in the gutter next to a line, like a breakpoint or an error.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Sign {
+    pub text: char,
+    pub face: Face
+}
+
+// The column left of the text, with a slot for the signs of each subsystem
+// that puts them there, then the line numbers.
+pub struct Gutter {
+    pub numbers: LineNumbers,
+    pub number_face: Face,
+    // The number of the cursor's line.
+    pub current_face: Face,
+    // The slots, one column each, by name.
+    slots: Vec<String>,
+    // By slot, then line.
+    signs: HashMap<(usize, usize), Sign>
+}
+
+impl Default for Gutter {
+    fn default() -> Self {
+        Self {
+            numbers: LineNumbers::None,
+            number_face: Face { fore: Rgba::GRAY, back: Rgba::new(0, 0, 0, 0), ..Default::default() },
+            current_face: Face { back: Rgba::new(0, 0, 0, 0), ..Default::default() },
+            slots: Vec::new(),
+            signs: HashMap::new()
+        }
+    }
+}
+
+impl Gutter {
+
+    pub fn slots(&self) -> &[String] {
+        &self.slots
+    }
+
+    // Replaces the slots, dropping signs of slots that are gone.
+    pub fn set_slots(&mut self, slots: Vec<String>) {
+        let signs = std::mem::take(&mut self.signs);
+        self.signs = signs.into_iter()
+            .filter_map(|((slot, line), sign)| {
+                let slot = slots.iter().position(|s| *s == self.slots[slot])?;
+                Some(((slot, line), sign))
+            })
+            .collect();
+        self.slots = slots;
+    }
+
+    // Puts a sign in a slot next to a line, over the one there. Returns false
+    // when there is no such slot.
+    pub fn set_sign(&mut self, slot: &str, line: usize, sign: Sign) -> bool {
+        match self.slots.iter().position(|s| s == slot) {
+            Some(slot) => {
+                self.signs.insert((slot, line), sign);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn remove_sign(&mut self, slot: &str, line: usize) -> Option<Sign> {
+        let slot = self.slots.iter().position(|s| s == slot)?;
+        self.signs.remove(&(slot, line))
+    }
+
+    // Empties a slot, like before its subsystem puts all its signs again.
+    pub fn clear_signs(&mut self, slot: &str) {
+        if let Some(slot) = self.slots.iter().position(|s| s == slot) {
+            self.signs.retain(|(s, _), _| *s != slot);
+        }
+    }
+
+    // How many columns wide it is for a page of `count` lines, or 0 when
+    // there is nothing to show. Numbers get as many columns as the last line
+    // number has digits, so the width only changes when that does.
+    pub fn columns(&self, count: usize) -> usize {
+        let digits = match self.numbers {
+            LineNumbers::None => 0,
+            _ => count.max(1).to_string().len()
+        };
+
+        match self.slots.len() + digits {
+            0 => 0,
+            // A column to keep it off the text.
+            columns => columns + 1
+        }
+    }
+
+    // What it shows next to a line, with the faces of its parts.
+    pub fn text(&self, line: usize, cursor: usize, count: usize) -> (String, Vec<Span>) {
+        let mut text = String::new();
+        let mut spans = Vec::new();
+
+        for slot in 0..self.slots.len() {
+            match self.signs.get(&(slot, line)) {
+                Some(sign) => {
+                    text.push(sign.text);
+                    spans.push(Span { range: slot..slot + 1, face: sign.face });
+                },
+                None => text.push(' ')
+            }
+        }
+
+        let number = match self.numbers {
+            LineNumbers::None => None,
+            LineNumbers::Absolute => Some(line + 1),
+            LineNumbers::Hybrid if line == cursor => Some(line + 1),
+            LineNumbers::Relative | LineNumbers::Hybrid => Some(line.abs_diff(cursor))
+        };
+
+        if let Some(number) = number {
+            let digits = count.max(1).to_string().len();
+            let face = if line == cursor { self.current_face } else { self.number_face };
+            spans.push(Span { range: self.slots.len()..self.slots.len() + digits, face });
+            text.push_str(&format!("{number:>digits$}"));
+        }
+
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        (text, spans)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn numbers_and_signs() {
+        let mut gutter = Gutter { numbers: LineNumbers::Absolute, ..Default::default() };
+        assert_eq!(gutter.columns(9), 2);
+        assert_eq!(gutter.columns(1000), 5);
+        assert_eq!(gutter.text(4, 0, 120).0, "  5 ");
+
+        gutter.numbers = LineNumbers::Relative;
+        assert_eq!(gutter.text(4, 7, 120).0, "  3 ");
+        assert_eq!(gutter.text(7, 7, 120).0, "  0 ");
+        gutter.numbers = LineNumbers::Hybrid;
+        assert_eq!(gutter.text(7, 7, 120).0, "  8 ");
+
+        // Signs go in their slot, ahead of the number.
+        gutter.set_slots(vec![String::from("breakpoints"), String::from("vcs")]);
+        let sign = Sign { text: '+', face: Face::default() };
+        assert!(gutter.set_sign("vcs", 2, sign));
+        assert!(!gutter.set_sign("diagnostics", 2, sign));
+        assert_eq!(gutter.columns(120), 6);
+        assert_eq!(gutter.text(2, 7, 120).0, " +  5 ");
+
+        // Reordering the slots keeps the signs, and dropping one drops them.
+        gutter.set_slots(vec![String::from("vcs"), String::from("breakpoints")]);
+        assert_eq!(gutter.text(2, 7, 120).0, "+   5 ");
+        gutter.set_slots(vec![String::from("breakpoints")]);
+        assert_eq!(gutter.text(2, 7, 120).0, "   5 ");
+
+        gutter.numbers = LineNumbers::None;
+        gutter.set_slots(Vec::new());
+        assert_eq!(gutter.columns(120), 0);
+        assert_eq!(gutter.text(2, 7, 120).0, "");
+    }
+}
@@ -0,0 +1,537 @@
+use std::{fs, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use anyhow::bail;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+
+use crate::file::atomic_write;
+
+// A (line, index) position in a page.
+pub type Position = (usize, usize);
+
+// A single invertible change to a page. Indices are absolute char indices.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Edit {
+    Insert {
+        index: usize,
+        text: String
+    },
+    Remove {
+        index: usize,
+        text: String
+    }
+}
+
+impl Edit {
+    pub fn invert(&self) -> Edit {
+        match self {
+            Edit::Insert { index, text } => Edit::Remove { index: *index, text: text.clone() },
+            Edit::Remove { index, text } => Edit::Insert { index: *index, text: text.clone() }
+        }
+    }
+
+    // Whether the edit fits `rope`: inserts within it, and removes of the
+    // text that is there.
+    fn fits(&self, rope: &Rope) -> bool {
+        match self {
+            Edit::Insert { index, .. } => *index <= rope.len_chars(),
+            Edit::Remove { index, text } => {
+                let end = *index + text.chars().count();
+                end <= rope.len_chars() && rope.slice(*index..end) == text.as_str()
+            }
+        }
+    }
+
+    pub fn apply(&self, rope: &mut Rope) {
+        match self {
+            Edit::Insert { index, text } => rope.insert(*index, text),
+            Edit::Remove { index, text } => rope.remove(*index..*index + text.chars().count())
+        }
+    }
+
+    // Folds `next` into this edit when they are contiguous, so typing or
+    // deleting a word records one edit instead of one per char.
+    fn merge(&mut self, next: &Edit) -> bool {
+        match (self, next) {
+            (Edit::Insert { index, text }, Edit::Insert { index: next_index, text: next_text }) => {
+                if *index + text.chars().count() == *next_index {
+                    text.push_str(next_text);
+                    return true;
+                }
+                false
+            },
+            (Edit::Remove { index, text }, Edit::Remove { index: next_index, text: next_text }) => {
+                if *next_index + next_text.chars().count() == *index {
+                    text.insert_str(0, next_text);
+                    *index = *next_index;
+                    return true;
+                }
+                if *next_index == *index {
+                    text.push_str(next_text);
+                    return true;
+                }
+                false
+            },
+            _ => false
+        }
+    }
+}
+
+// A group of edits that is undone and redone as one step.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transaction {
+    pub edits: Vec<Edit>,
+    pub cursor_before: Position,
+    pub cursor_after: Position
+}
+
+impl Transaction {
+    fn new(cursor: Position) -> Self {
+        Self {
+            edits: Vec::new(),
+            cursor_before: cursor,
+            cursor_after: cursor
+        }
+    }
+
+    fn push(&mut self, edit: Edit) {
+        if let Some(last) = self.edits.last_mut() {
+            if last.merge(&edit) {
+                return;
+            }
+        }
+        self.edits.push(edit);
+    }
+}
+
+// One state of the page. `transaction` takes the parent's text to this one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UndoNode {
+    pub parent: usize,
+    pub children: Vec<usize>,
+    pub transaction: Transaction,
+    // Seconds since the unix epoch.
+    pub time: u64,
+    // The child that redo follows; the most recently visited branch.
+    redo_child: Option<usize>
+}
+
+// The edit history as a tree, like vim's undotree. Undoing and then making a
+// new edit starts a new branch instead of throwing the old one away.
+// Node 0 is the root, the text as it was when the history started.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct History {
+    nodes: Vec<UndoNode>,
+    current: usize,
+    #[serde(skip)]
+    open: Option<Transaction>
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            nodes: vec![UndoNode {
+                parent: 0,
+                children: Vec::new(),
+                transaction: Transaction::new((0, 0)),
+                time: now(),
+                redo_child: None
+            }],
+            current: 0,
+            open: None
+        }
+    }
+}
+
+impl History {
+
+    pub fn nodes(&self) -> &[UndoNode] {
+        &self.nodes
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.is_some()
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.current != 0 || self.has_pending()
+    }
+
+    // Whether the open transaction holds edits that aren't in the tree yet.
+    pub fn has_pending(&self) -> bool {
+        self.open.as_ref().is_some_and(|t| !t.edits.is_empty())
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.nodes[self.current].children.is_empty()
+    }
+
+    // Starts grouping edits. Does nothing if a transaction is already open.
+    pub fn begin(&mut self, cursor: Position) {
+        if self.open.is_none() {
+            self.open = Some(Transaction::new(cursor));
+        }
+    }
+
+    pub fn commit(&mut self, cursor: Position) {
+        if let Some(mut transaction) = self.open.take() {
+            transaction.cursor_after = cursor;
+            self.push(transaction);
+        }
+    }
+
+    // Records an edit. Edits made outside of a transaction become their own
+    // step, with the cursor placed at the edit.
+    pub fn record(&mut self, edit: Edit, before: Position, after: Position) {
+        match self.open.as_mut() {
+            Some(transaction) => {
+                transaction.cursor_after = after;
+                transaction.push(edit);
+            },
+            None => {
+                let mut transaction = Transaction::new(before);
+                transaction.cursor_after = after;
+                transaction.push(edit);
+                self.push(transaction);
+            }
+        }
+    }
+
+    fn push(&mut self, transaction: Transaction) {
+        if transaction.edits.is_empty() {
+            return;
+        }
+
+        let index = self.nodes.len();
+        self.nodes.push(UndoNode {
+            parent: self.current,
+            children: Vec::new(),
+            transaction,
+            time: now(),
+            redo_child: None
+        });
+
+        let parent = &mut self.nodes[self.current];
+        parent.children.push(index);
+        parent.redo_child = Some(index);
+        self.current = index;
+    }
+
+    // Reverts the current state on the rope and returns where the cursor was
+    // before it. An open transaction is closed first.
+    pub fn undo(&mut self, rope: &mut Rope) -> Option<Position> {
+        if let Some(transaction) = self.open.take() {
+            self.push(transaction);
+        }
+
+        if self.current == 0 {
+            return None;
+        }
+
+        let node = &self.nodes[self.current];
+        for edit in node.transaction.edits.iter().rev() {
+            edit.invert().apply(rope);
+        }
+
+        let cursor = node.transaction.cursor_before;
+        let (child, parent) = (self.current, node.parent);
+        self.nodes[parent].redo_child = Some(child);
+        self.current = parent;
+        Some(cursor)
+    }
+
+    pub fn redo(&mut self, rope: &mut Rope) -> Option<Position> {
+        if self.has_pending() {
+            return None;
+        }
+        self.open = None;
+
+        let node = &self.nodes[self.current];
+        let child = node.redo_child.or(node.children.last().copied())?;
+
+        for edit in self.nodes[child].transaction.edits.iter() {
+            edit.apply(rope);
+        }
+
+        self.current = child;
+        Some(self.nodes[child].transaction.cursor_after)
+    }
+
+    // Moves to any state in the tree by undoing up to the common ancestor and
+    // redoing down to `target`.
+    pub fn goto(&mut self, rope: &mut Rope, target: usize) -> Option<Position> {
+        if target >= self.nodes.len() {
+            return None;
+        }
+
+        if let Some(transaction) = self.open.take() {
+            self.push(transaction);
+        }
+
+        let (up, down) = self.path(self.current, target);
+        let mut cursor = None;
+
+        for node in up {
+            cursor = self.undo(rope);
+            debug_assert_eq!(self.nodes[node].parent, self.current);
+        }
+
+        for node in down {
+            self.nodes[self.current].redo_child = Some(node);
+            cursor = self.redo(rope);
+        }
+        cursor
+    }
+
+    // The text of state `target`, given the rope of the current state.
+    pub fn text_at(&self, rope: &Rope, target: usize) -> Rope {
+        let mut rope = rope.clone();
+        let (up, down) = self.path(self.current, target);
+
+        for node in up {
+            for edit in self.nodes[node].transaction.edits.iter().rev() {
+                edit.invert().apply(&mut rope);
+            }
+        }
+
+        for node in down {
+            for edit in self.nodes[node].transaction.edits.iter() {
+                edit.apply(&mut rope);
+            }
+        }
+        rope
+    }
+
+    // The nodes to undo out of, then the nodes to redo into.
+    fn path(&self, from: usize, to: usize) -> (Vec<usize>, Vec<usize>) {
+        let ancestors = |mut node: usize| {
+            let mut out = vec![node];
+            while node != 0 {
+                node = self.nodes[node].parent;
+                out.push(node);
+            }
+            out
+        };
+
+        let mut up = ancestors(from);
+        let mut down = ancestors(to);
+
+        while let (Some(a), Some(b)) = (up.last(), down.last()) {
+            if a != b {
+                break;
+            }
+            up.pop();
+            down.pop();
+        }
+
+        down.reverse();
+        (up, down)
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    // Writes the tree to the undo cache, tagged with a hash of the text it
+    // belongs to.
+    pub fn save(&self, file: &Path, rope: &Rope) -> anyhow::Result<()> {
+        self.write(&undo_file_path(file)?, rope)
+    }
+
+    // Loads the tree saved for `file`. Returns `None` if there is none, or if the
+    // file was changed by something else since, as the edits would no longer apply.
+    pub fn load(file: &Path, rope: &Rope) -> anyhow::Result<Option<Self>> {
+        Self::read(&undo_file_path(file)?, rope)
+    }
+
+    fn write(&self, path: &Path, rope: &Rope) -> anyhow::Result<()> {
+        let mut history = self.clone();
+        if let Some(transaction) = history.open.take() {
+            history.push(transaction);
+        }
+
+        let undo_file = UndoFile {
+            version: UNDO_FILE_VERSION,
+            hash: format!("{:016x}", content_hash(rope)),
+            history
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        atomic_write(path, toml::to_string(&undo_file)?.as_bytes())
+    }
+
+    fn read(path: &Path, rope: &Rope) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let undo_file: UndoFile = toml::from_str(&fs::read_to_string(path)?)?;
+
+        if undo_file.version != UNDO_FILE_VERSION || undo_file.hash != format!("{:016x}", content_hash(rope)) {
+            return Ok(None);
+        }
+
+        let history = undo_file.history;
+        if !history.is_valid(rope) {
+            bail!("Undo file {} is corrupt.", path.display());
+        }
+        Ok(Some(history))
+    }
+
+    // Whether a loaded tree is one that `push` could have built, with `rope`
+    // as its current text, so that moving around it can't panic. Parents
+    // come before their children, and every edit fits the text it applies to.
+    fn is_valid(&self, rope: &Rope) -> bool {
+        let nodes = &self.nodes;
+        if self.current >= nodes.len() || nodes.first().is_none_or(|root| root.parent != 0) {
+            return false;
+        }
+
+        let linked = nodes.iter().enumerate().all(|(index, node)| {
+            (index == 0 || (node.parent < index && nodes[node.parent].children.contains(&index)))
+                && node.children.iter().all(|c| *c > index && *c < nodes.len() && nodes[*c].parent == index)
+                && node.redo_child.is_none_or(|c| node.children.contains(&c))
+        });
+        if !linked {
+            return false;
+        }
+
+        // Undo from the current state to the root, then redo into every node.
+        let mut root = rope.clone();
+        let mut node = self.current;
+        while node != 0 {
+            for edit in nodes[node].transaction.edits.iter().rev() {
+                let edit = edit.invert();
+                if !edit.fits(&root) {
+                    return false;
+                }
+                edit.apply(&mut root);
+            }
+            node = nodes[node].parent;
+        }
+
+        let mut stack = vec![(0, root)];
+        while let Some((node, rope)) = stack.pop() {
+            for child in nodes[node].children.iter() {
+                let mut rope = rope.clone();
+                for edit in nodes[*child].transaction.edits.iter() {
+                    if !edit.fits(&rope) {
+                        return false;
+                    }
+                    edit.apply(&mut rope);
+                }
+                stack.push((*child, rope));
+            }
+        }
+        true
+    }
+}
+
+const UNDO_FILE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct UndoFile {
+    version: u32,
+    hash: String,
+    history: History
+}
+
+// Undo files live in `$XDG_CACHE_HOME/rhotic/undo`, named after the full path
+// of the file with the separators swapped for '%', the same as vim's undodir.
+pub fn undo_file_path(file: &Path) -> anyhow::Result<PathBuf> {
+    let cache = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".cache"),
+            None => bail!("Could not find a cache directory for undo files.")
+        }
+    };
+
+    Ok(undo_file_in(&cache, file))
+}
+
+fn undo_file_in(cache: &Path, file: &Path) -> PathBuf {
+    let file = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    let name = file.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "%");
+
+    cache.join("rhotic").join("undo").join(format!("{name}.toml"))
+}
+
+// FNV-1a, which unlike the std hasher is stable between builds.
+pub fn content_hash(rope: &Rope) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for chunk in rope.chunks() {
+        for b in chunk.bytes() {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn edit(history: &mut History, rope: &mut Rope, index: usize, text: &str) {
+        let edit = Edit::Insert { index, text: text.into() };
+        edit.apply(rope);
+        history.begin((0, index));
+        history.record(edit, (0, index), (0, index + text.chars().count()));
+        history.commit((0, index + text.chars().count()));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join("rhotic_undo_save_and_load");
+        let _ = fs::remove_dir_all(&dir);
+        let path = undo_file_in(&dir, Path::new("/work/notes.txt"));
+        assert_eq!(path, dir.join("rhotic").join("undo").join("%work%notes.txt.toml"));
+
+        let mut rope = Rope::from_str("ab");
+        let mut history = History::default();
+        edit(&mut history, &mut rope, 2, "c");
+        history.undo(&mut rope);
+        edit(&mut history, &mut rope, 0, "x");
+
+        // Nothing saved yet.
+        assert_eq!(History::read(&path, &rope).unwrap(), None);
+
+        history.write(&path, &rope).unwrap();
+        let loaded = History::read(&path, &rope).unwrap().unwrap();
+        assert_eq!(loaded, history);
+        assert_eq!(loaded.text_at(&rope, 1).to_string(), "abc");
+
+        // The text changed behind its back, so the edits no longer apply.
+        assert_eq!(History::read(&path, &Rope::from_str("xabd")).unwrap(), None);
+
+        // Hand edits that would break moving around the tree are caught.
+        let corrupt = |f: &dyn Fn(&mut History)| {
+            let mut corrupt = history.clone();
+            f(&mut corrupt);
+            corrupt.write(&path, &rope).unwrap();
+            History::read(&path, &rope).is_err()
+        };
+        assert!(corrupt(&|h| h.nodes[2].parent = 5));
+        assert!(corrupt(&|h| h.nodes[0].children.push(9)));
+        assert!(corrupt(&|h| h.nodes[1].transaction.edits[0] = Edit::Insert { index: 9, text: String::from("c") }));
+        assert!(corrupt(&|h| h.nodes[1].transaction.edits[0] = Edit::Remove { index: 0, text: String::from("q") }));
+        assert!(!corrupt(&|_| {}));
+
+        fs::write(&path, "not an undo file").unwrap();
+        assert!(History::read(&path, &rope).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
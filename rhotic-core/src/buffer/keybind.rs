@@ -0,0 +1,433 @@
+use std::{collections::{HashMap, HashSet}, error::Error, fmt::Display, str::FromStr, time::{Duration, Instant}};
+
+use toml::{Table, Value};
+
+use crate::{buffer::stage::InputEvent, display::event_loop::{Key, KeyParseError}};
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Default)]
+pub struct Modifiers {
+    pub control: bool,
+    pub alt: bool,
+    pub shift: bool
+}
+
+// A key pressed while holding some modifiers, like "C-x" or "M-<".
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct KeyChord {
+    pub key: Key,
+    pub modifiers: Modifiers
+}
+
+// Chords pressed one after another, like "C-x C-s" or "g g".
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub struct KeySequence(pub Vec<KeyChord>);
+
+impl KeyChord {
+    pub fn new(key: Key) -> Self {
+        Self { key, modifiers: Modifiers::default() }
+    }
+
+    // Whether typing this chord is just typing text.
+    pub fn is_text(&self) -> bool {
+        !self.modifiers.control && !self.modifiers.alt && self.key.produces_text()
+    }
+
+    // C-g and escape abandon a half typed sequence.
+    pub fn is_cancel(&self) -> bool {
+        let m = self.modifiers;
+        (self.key == Key::G && m.control && !m.alt) || (self.key == Key::Escape && m == Modifiers::default())
+    }
+}
+
+// Chars that need shift on a US layout, and the key they are on.
+const SHIFTED: &[(char, char)] = &[
+    ('~', '`'), ('!', '1'), ('@', '2'), ('#', '3'), ('$', '4'), ('%', '5'), ('^', '6'), ('&', '7'),
+    ('*', '8'), ('(', '9'), (')', '0'), ('_', '-'), ('+', '='), ('{', '['), ('}', ']'), ('|', '\\'),
+    (':', ';'), ('"', '\''), ('<', ','), ('>', '.'), ('?', '/')
+];
+
+// Modifiers are written as prefixes: "C-" control, "M-" or "A-" alt and "S-"
+// shift. Shifted characters imply shift, so "M-<" is alt, shift and comma.
+impl FromStr for KeyChord {
+    type Err = KeyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = Modifiers::default();
+        let mut rest = s;
+
+        loop {
+            let mut chars = rest.chars();
+            match (chars.next(), chars.next(), chars.next()) {
+                (Some(m), Some('-'), Some(_)) => {
+                    match m {
+                        'C' => modifiers.control = true,
+                        'M' | 'A' => modifiers.alt = true,
+                        'S' => modifiers.shift = true,
+                        _ => return Err(KeyParseError(s.to_string()))
+                    }
+                    rest = &rest[2..];
+                },
+                _ => break
+            }
+        }
+
+        let mut chars = rest.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            if c.is_ascii_uppercase() {
+                modifiers.shift = true;
+                return Ok(Self { key: c.to_ascii_lowercase().to_string().parse()?, modifiers });
+            }
+
+            if let Some((_, base)) = SHIFTED.iter().find(|(shifted, _)| *shifted == c) {
+                modifiers.shift = true;
+                return Ok(Self { key: base.to_string().parse()?, modifiers });
+            }
+        }
+
+        match rest.parse() {
+            Ok(key) => Ok(Self { key, modifiers }),
+            Err(_) => Err(KeyParseError(s.to_string()))
+        }
+    }
+}
+
+impl FromStr for KeySequence {
+    type Err = KeyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chords = s.split_whitespace().map(|c| c.parse()).collect::<Result<Vec<KeyChord>, _>>()?;
+
+        if chords.is_empty() {
+            return Err(KeyParseError(s.to_string()));
+        }
+        Ok(Self(chords))
+    }
+}
+
+impl Display for KeyChord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.modifiers.control {
+            write!(f, "C-")?;
+        }
+        if self.modifiers.alt {
+            write!(f, "M-")?;
+        }
+        if self.modifiers.shift {
+            write!(f, "S-")?;
+        }
+        write!(f, "{}", format!("{:?}", self.key).to_lowercase())
+    }
+}
+
+impl Display for KeySequence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        for chord in self.0.iter() {
+            if !first {
+                write!(f, " ")?;
+            }
+            first = false;
+            write!(f, "{chord}")?;
+        }
+        Ok(())
+    }
+}
+
+pub enum Lookup<F> {
+    Found(F),
+    Prefix,
+    None
+}
+
+// Bindings from key sequences to functions. Every proper prefix of a bound
+// sequence is a prefix keymap, which waits for more keys.
+#[derive(Clone)]
+pub struct Keymap<F> {
+    bindings: HashMap<Vec<KeyChord>, F>,
+    prefixes: HashSet<Vec<KeyChord>>
+}
+
+impl<F> Default for Keymap<F> {
+    fn default() -> Self {
+        Self {
+            bindings: HashMap::new(),
+            prefixes: HashSet::new()
+        }
+    }
+}
+
+impl<F: Clone> Keymap<F> {
+
+    pub fn insert(&mut self, sequence: KeySequence, f: F) {
+        for i in 1..sequence.0.len() {
+            self.prefixes.insert(sequence.0[..i].to_vec());
+        }
+        self.bindings.insert(sequence.0, f);
+    }
+
+    pub fn lookup(&self, chords: &[KeyChord]) -> Lookup<F> {
+        if let Some(f) = self.bindings.get(chords) {
+            return Lookup::Found(f.clone());
+        }
+        if self.prefixes.contains(chords) {
+            return Lookup::Prefix;
+        }
+        Lookup::None
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[KeyChord], &F)> {
+        self.bindings.iter().map(|(k, f)| (k.as_slice(), f))
+    }
+}
+
+pub enum MatchResult<F> {
+    // A whole sequence was typed.
+    Matched(F),
+    // The keys so far are a prefix of some binding.
+    Pending(KeySequence),
+    // A pending prefix was cancelled with C-g or escape.
+    Cancelled(KeySequence),
+    // The keys don't lead to any binding.
+    Unbound(KeySequence),
+    // Releases and modifier keys, which only update the matcher's state.
+    Ignored
+}
+
+// Matches incoming key events against a keymap, one chord at a time.
+// Modifiers are tracked from the press and release of the modifier keys.
+pub struct SequenceMatcher {
+    pending: Vec<KeyChord>,
+    modifiers: Modifiers,
+    altgr: bool,
+    last_press: Option<Instant>,
+    pub timeout: Duration
+}
+
+impl Default for SequenceMatcher {
+    fn default() -> Self {
+        Self {
+            pending: Vec::new(),
+            modifiers: Modifiers::default(),
+            altgr: false,
+            last_press: None,
+            timeout: Duration::from_millis(1000)
+        }
+    }
+}
+
+impl SequenceMatcher {
+
+    // While AltGr is held, keys type text rather than make chords, so it
+    // hides control and alt. Some platforms send AltGr with a control press.
+    pub fn modifiers(&self) -> Modifiers {
+        match self.altgr {
+            true => Modifiers { control: false, alt: false, ..self.modifiers },
+            false => self.modifiers
+        }
+    }
+
+    pub fn pending(&self) -> &[KeyChord] {
+        &self.pending
+    }
+
+    pub fn cancel(&mut self) {
+        self.pending.clear();
+    }
+
+    // The chord a key press would make with the modifiers held right now.
+    pub fn chord(&self, key: Key) -> KeyChord {
+        KeyChord { key, modifiers: self.modifiers() }
+    }
+
+    pub fn feed<F>(&mut self, event: &InputEvent, lookup: impl Fn(&[KeyChord]) -> Lookup<F>) -> MatchResult<F> {
+        use InputEvent::*;
+
+        let (key, pressed) = match event {
+            Press(k) | Echo(k) => (*k, true),
+            Release(k) => (*k, false),
+            _ => return MatchResult::Ignored
+        };
+
+        match key {
+            Key::Control => self.modifiers.control = pressed,
+            // Layouts may report AltGr's release as plain alt's.
+            Key::Alt => {
+                self.modifiers.alt = pressed;
+                self.altgr &= pressed;
+            },
+            Key::Shift => self.modifiers.shift = pressed,
+            Key::AltGr => self.altgr = pressed,
+            _ if pressed => return self.feed_chord(self.chord(key), lookup),
+            _ => {}
+        }
+        MatchResult::Ignored
+    }
+
+    pub fn feed_chord<F>(&mut self, chord: KeyChord, lookup: impl Fn(&[KeyChord]) -> Lookup<F>) -> MatchResult<F> {
+        let now = Instant::now();
+
+        if self.last_press.is_some_and(|last| now.duration_since(last) > self.timeout) {
+            self.pending.clear();
+        }
+        self.last_press = Some(now);
+
+        if !self.pending.is_empty() && chord.is_cancel() {
+            return MatchResult::Cancelled(KeySequence(std::mem::take(&mut self.pending)));
+        }
+
+        self.pending.push(chord);
+
+        match lookup(&self.pending) {
+            Lookup::Found(f) => {
+                self.pending.clear();
+                MatchResult::Matched(f)
+            },
+            Lookup::Prefix => MatchResult::Pending(KeySequence(self.pending.clone())),
+            Lookup::None => MatchResult::Unbound(KeySequence(std::mem::take(&mut self.pending)))
+        }
+    }
+}
+
+// Reads a `[keybinds]` table of `function = "keys"` entries, or
+// `function = ["keys", "keys"]` to bind several sequences. `lookup` turns a
+// function name into whatever the stage calls.
+pub fn parse_keybinds<F: Clone>(table: &Table, lookup: impl Fn(&str) -> Option<F>) -> Result<Keymap<F>, KeybindErrors> {
+
+    let mut out = Keymap::default();
+    let mut errors = Vec::new();
+
+    for (name, value) in table.iter() {
+
+        let function = match lookup(name) {
+            Some(f) => f,
+            None => {
+                errors.push(KeybindError::UnknownFunction(name.clone()));
+                continue;
+            }
+        };
+
+        let sequences = match value {
+            Value::String(s) => vec![s.as_str()],
+            Value::Array(a) => a.iter().filter_map(|v| v.as_str()).collect(),
+            _ => {
+                errors.push(KeybindError::InvalidValue(name.clone()));
+                continue;
+            }
+        };
+
+        for sequence in sequences {
+            match sequence.parse::<KeySequence>() {
+                Ok(s) => out.insert(s, function.clone()),
+                Err(e) => errors.push(KeybindError::UnknownKey { function: name.clone(), error: e })
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(out)
+    } else {
+        Err(KeybindErrors(errors))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum KeybindError {
+    UnknownFunction(String),
+    UnknownKey {
+        function: String,
+        error: KeyParseError
+    },
+    InvalidValue(String),
+    UnknownStage(String),
+    UnknownMode {
+        stage: String,
+        mode: String
+    }
+}
+
+impl Error for KeybindError {}
+
+impl Display for KeybindError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeybindError::UnknownFunction(name) => {
+                write!(f, "There is no function called \"{name}\" to bind.")
+            },
+            KeybindError::UnknownKey { function, error } => {
+                write!(f, "Can't bind {function}: {error}")
+            },
+            KeybindError::InvalidValue(name) => {
+                write!(f, "The binding for {name} should be a key string, or an array of them.")
+            },
+            KeybindError::UnknownStage(name) => {
+                write!(f, "There is no stage called \"{name}\" to bind keys in.")
+            },
+            KeybindError::UnknownMode { stage, mode } => {
+                write!(f, "The {stage} has no mode called \"{mode}\".")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeybindErrors(pub Vec<KeybindError>);
+
+impl Error for KeybindErrors {}
+
+impl Display for KeybindErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid keybinds:")?;
+        for e in self.0.iter() {
+            write!(f, "\n    {e}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_sequences() {
+        let seq: KeySequence = "C-x C-s".parse().unwrap();
+        assert_eq!(seq.0.len(), 2);
+        assert!(seq.0.iter().all(|c| c.modifiers.control && !c.modifiers.shift));
+        assert_eq!(seq.to_string(), "C-x C-s");
+
+        let chord: KeyChord = "M-<".parse().unwrap();
+        assert_eq!(chord.key, Key::Comma);
+        assert!(chord.modifiers.alt && chord.modifiers.shift);
+
+        assert_eq!("G".parse::<KeyChord>().unwrap().to_string(), "S-g");
+        assert_eq!("C--".parse::<KeyChord>().unwrap().key, Key::Minus);
+        assert!("C-nope".parse::<KeyChord>().is_err());
+        assert!("X-a".parse::<KeyChord>().is_err());
+    }
+
+    #[test]
+    fn match_sequences() {
+        let mut keymap = Keymap::default();
+        keymap.insert("C-x C-s".parse().unwrap(), 1);
+        keymap.insert("g g".parse().unwrap(), 2);
+
+        let mut matcher = SequenceMatcher::default();
+        let mut feed = |e| matcher.feed(&e, |c| keymap.lookup(c));
+
+        assert!(matches!(feed(InputEvent::Press(Key::Control)), MatchResult::Ignored));
+        assert!(matches!(feed(InputEvent::Press(Key::X)), MatchResult::Pending(_)));
+        assert!(matches!(feed(InputEvent::Press(Key::S)), MatchResult::Matched(1)));
+        assert!(matches!(feed(InputEvent::Press(Key::X)), MatchResult::Pending(_)));
+        assert!(matches!(feed(InputEvent::Press(Key::G)), MatchResult::Cancelled(_)));
+        assert!(matches!(feed(InputEvent::Release(Key::Control)), MatchResult::Ignored));
+
+        assert!(matches!(feed(InputEvent::Press(Key::G)), MatchResult::Pending(_)));
+        assert!(matches!(feed(InputEvent::Press(Key::G)), MatchResult::Matched(2)));
+        assert!(matches!(feed(InputEvent::Press(Key::G)), MatchResult::Pending(_)));
+        assert!(matches!(feed(InputEvent::Press(Key::A)), MatchResult::Unbound(s) if s.0.len() == 2));
+    }
+}
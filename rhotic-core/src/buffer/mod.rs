@@ -1,5 +1,11 @@
 
 pub mod text_buffer;
+pub mod history;
+pub mod diff;
+pub mod keybind;
 pub mod stage;
+pub mod command;
 pub mod textstage;
+pub mod view;
+pub mod gutter;
 
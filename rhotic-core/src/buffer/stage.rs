@@ -1,14 +1,118 @@
-use fontdue::{layout::{Layout, TextStyle, GlyphPosition}, Metrics};
-use winit::{window::Window, event::MouseScrollDelta, keyboard::SmolStr};
+use std::ops::Range;
 
-use crate::{display::{event_loop::{Key}, text_render::Canvas, font::FontManager, image::MonoImage, Rgba}};
+use fontdue::{layout::{Layout, GlyphPosition, LinePosition}, Metrics};
+use winit::{event::MouseScrollDelta, keyboard::SmolStr};
+
+use crate::{display::{event_loop::{Key}, text_render::Canvas, font::{FontManager, Face, Underline}, glyph_cache::GlyphImage, shaping, Rgba}, state::minibuffer::Prompt};
 
 use toml::Table;
 
-pub trait Stage where Self: Sized {
+use super::command::{Function, ArgSpec};
+use super::view::{TextView, LineLayout};
+
+pub trait Stage where Self: Sized + 'static {
     fn init(input: &[&str]) -> anyhow::Result<Self>;
     fn send_event(&mut self, input: InputEvent) -> StateCommand;
     const NAME: &'static str;
+
+    // Whether closing the stage now would lose work.
+    fn has_unsaved_changes(&self) -> bool {
+        false
+    }
+
+    // Counts the edits made, so a warning about unsaved changes can tell if
+    // there were new ones since.
+    fn edits(&self) -> usize {
+        0
+    }
+
+    // A message from starting up, like a problem that didn't stop it.
+    fn take_message(&mut self) -> Option<String> {
+        None
+    }
+
+    // Modes that can have their own keymap, like `["Text Stage".insert]`.
+    const MODES: &'static [&'static str] = &[];
+
+    // The current mode, one of `MODES`.
+    fn mode(&self) -> Option<&'static str> {
+        None
+    }
+
+    // The named functions of this stage, for key bindings and M-x. They reach
+    // the stage as `InputEvent::Command(&[name, args..])`, see `command::run`.
+    fn functions() -> &'static [Function<Self>] {
+        &[]
+    }
+
+    // The text of a status line segment, like "file" or "position", or None
+    // to leave it out. See `status.toml` for the segments.
+    fn status_segment(&self, _segment: &str) -> Option<String> {
+        None
+    }
+
+    // Names keys can be bound to: the functions without required arguments.
+    fn function_names() -> Vec<&'static str> {
+        Self::functions().iter().filter(|f| f.is_bindable()).map(|f| f.name).collect()
+    }
+}
+
+// The object-safe part of a `Stage`, so the State can host stages of any type
+// side by side. Every stage that renders with a `FontManager` implements it.
+pub trait DynStage {
+    fn send_event(&mut self, input: InputEvent) -> StateCommand;
+    fn name(&self) -> &'static str;
+    fn mode(&self) -> Option<&'static str>;
+    fn has_unsaved_changes(&self) -> bool;
+    fn edits(&self) -> usize;
+    fn take_message(&mut self) -> Option<String>;
+    fn function_names(&self) -> Vec<&'static str>;
+    fn status_segment(&self, segment: &str) -> Option<String>;
+    // Every function, with the specs of its arguments.
+    fn function_specs(&self) -> Vec<(&'static str, &'static [ArgSpec])>;
+    fn render(&mut self, canvas: &mut Canvas, v: &mut FontManager);
+}
+
+impl<T> DynStage for T where T: Stage + for<'a> Render<&'a mut FontManager> {
+    fn send_event(&mut self, input: InputEvent) -> StateCommand {
+        Stage::send_event(self, input)
+    }
+
+    fn name(&self) -> &'static str {
+        T::NAME
+    }
+
+    fn mode(&self) -> Option<&'static str> {
+        Stage::mode(self)
+    }
+
+    fn has_unsaved_changes(&self) -> bool {
+        Stage::has_unsaved_changes(self)
+    }
+
+    fn edits(&self) -> usize {
+        Stage::edits(self)
+    }
+
+    fn take_message(&mut self) -> Option<String> {
+        Stage::take_message(self)
+    }
+
+    fn function_names(&self) -> Vec<&'static str> {
+        T::function_names()
+    }
+
+    fn status_segment(&self, segment: &str) -> Option<String> {
+        Stage::status_segment(self, segment)
+    }
+
+    fn function_specs(&self) -> Vec<(&'static str, &'static [ArgSpec])> {
+        T::functions().iter().map(|f| (f.name, f.args)).collect()
+    }
+
+    fn render(&mut self, canvas: &mut Canvas, v: &mut FontManager) {
+        Render::render(self, canvas, v)
+    }
 }
 
 pub enum InputEvent<'a> {
@@ -18,12 +122,25 @@ pub enum InputEvent<'a> {
     MouseMove(usize, usize),
     Scroll(MouseScrollDelta),
     Text(SmolStr),
+    // Text an input method is still composing, with the cursor's byte offset
+    // in it. It is replaced by the next preedit, or by the committed `Text`.
+    Preedit(SmolStr, Option<usize>),
     Command(&'a [&'a str])
 }
 
 pub enum StateCommand {
-    StartStage(String),
+    // Opens the stage with this `Stage::NAME`, passing it the arguments, and
+    // focuses it.
+    StartStage(String, Vec<String>),
+    // Opens a stage the sender built itself, and focuses it.
+    OpenStage(Box<dyn DynStage>),
+    // Closes the focused stage and runs the command line in the stage that
+    // opened it with `OpenStage`.
+    Return(Vec<String>),
+    // Shows a message in the minibuffer.
     Log(String),
+    // Asks for a line in the minibuffer, then runs the answer.
+    Prompt(Prompt),
     None,
     // Add log command?
 }
@@ -40,13 +157,74 @@ pub trait Configurable {
     const CONFIG_FILE_NAME: &'static str;
 }
 
+// Reads `./config/<CONFIG_FILE_NAME>`, falling back to the defaults when
+// there is no such file.
+pub fn load_configuration<T: Configurable>() -> anyhow::Result<Table> {
+    let path = format!("./config/{}", T::CONFIG_FILE_NAME);
+
+    if !std::path::Path::new(&path).exists() {
+        return Ok(T::default_configuration());
+    }
+
+    Ok(crate::file::toml::Toml::open(path)?.table)
+}
+
 pub trait Render<V = ()> {
-    fn render(&mut self, canvas: &mut Canvas<&Window, &Window>, v: V);
+    fn render(&mut self, canvas: &mut Canvas, v: V);
 }
 
 pub trait TextStage {
     fn get_display_text(&self) -> String;
+    // The cursor's display column and line.
     fn get_cursor(&self) -> (usize, usize, CursorLook);
+
+    // Styled parts of the display text, for highlighting and diagnostics.
+    // Text outside every span is drawn in the `FontManager`'s default face.
+    fn get_spans(&self) -> Vec<Span> {
+        Vec::new()
+    }
+
+    // Where the stage keeps its scroll position and laid out lines.
+    fn view(&mut self) -> &mut TextView;
+
+    // Moves the cursor to a line and display column, for when the view moves
+    // it, like paging does. Stages without a movable cursor ignore it.
+    fn set_cursor(&mut self, _line: usize, _column: usize) {}
+
+    // Only the lines on screen are drawn, one at a time. These default to
+    // cutting up `get_display_text`; stages with long text should override
+    // them, so a frame doesn't cost more than its lines.
+    fn line_count(&self) -> usize {
+        self.get_display_text().split('\n').count()
+    }
+
+    // One display line, without its line break.
+    fn get_display_line(&self, line: usize) -> String {
+        self.get_display_text().split('\n').nth(line).unwrap_or_default().to_string()
+    }
+
+    // The spans over one line, counting chars from its start.
+    fn get_line_spans(&self, line: usize) -> Vec<Span> {
+        let text = self.get_display_text();
+        let mut lines = text.split('\n');
+        let start: usize = lines.by_ref().take(line).map(|l| l.chars().count() + 1).sum();
+        let len = lines.next().map_or(0, |l| l.chars().count());
+
+        self.get_spans().into_iter()
+            .filter_map(|span| {
+                let range = span.range.start.max(start)..span.range.end.min(start + len);
+                (range.start < range.end).then(|| Span { range: range.start - start..range.end - start, face: span.face })
+            })
+            .collect()
+    }
+}
+
+// A run of the display text drawn in its own face. The range counts chars of
+// `get_display_text`. Where spans overlap, the later one wins.
+#[derive(Clone, PartialEq, Hash, Debug)]
+pub struct Span {
+    pub range: Range<usize>,
+    pub face: Face
 }
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -58,150 +236,217 @@ pub enum CursorLook {
 }
 
 impl<T: TextStage> Render<&mut FontManager> for T {
-    fn render(&mut self, canvas: &mut Canvas<&Window, &Window>, v: &mut FontManager) {
-        use CursorLook::*;
+    fn render(&mut self, canvas: &mut Canvas, v: &mut FontManager) {
+        // The view is taken out while the stage hands it lines.
+        let mut view = std::mem::take(self.view());
+        let lines = view.lines(self, canvas.width() as f32, canvas.height() as f32, v);
+        let (left, indicator) = (view.left, view.indicator());
+        let gutter = view.gutter_width() as usize;
+        let paged = view.take_cursor();
+        let default_back = v.back;
 
-        let layout = layout(self.get_display_text(), v);
-        let glyphs = layout.glyphs();
-        let (mut dx, mut dy) = (0,0);
+        for (_, label, y) in view.gutter_labels() {
+            draw_label(canvas, label, 0.0, *y, default_back, v);
+        }
+        *self.view() = view;
+
+        if let Some((line, column)) = paged {
+            self.set_cursor(line, column);
+        }
         let (cx, cy, ctype) = self.get_cursor();
 
-        for glyph in glyphs {
+        // The text goes right of the gutter.
+        let canvas = &mut canvas.viewport(gutter as isize, 0, canvas.width().saturating_sub(gutter), canvas.height());
 
-            const CURSOR_COLOR: Rgba = Rgba::new_opaque(0x60, 0xAF, 0xFF);
+        // The cell of the char at `column`: one advance wide and as tall as
+        // its row, which the line's layout moves it to when it wraps.
+        let cell = |column: usize, line: &LineLayout, y: f32| {
+            let (row, x, width) = line.position(column);
+            let top = y + row as f32 * line.row_height;
 
-            let cursor_render = dy == cy && dx == cx;
-            let (metrics, image) = get_image(glyph, v);
+            (
+                (x - left) as isize,
+                top as isize,
+                width as usize,
+                line.row_height as usize,
+                (top + line.baseline) as isize
+            )
+        };
 
-            let cursor_left_bound = glyph.x as isize - metrics.xmin as isize;
-            let cursor_width = metrics.advance_width as usize;
-            let line_position = layout.lines().unwrap()[dy];
-            let line_top_bound = (line_position.baseline_y - line_position.max_ascent) as isize;
-            let line_height = line_position.max_new_line_size as usize;
+        // Backgrounds go first, so a glyph that overhangs its cell isn't cut
+        // off by the next glyph's background.
+        for (_, line, y) in &lines {
+            for (column, glyph) in line.glyphs.iter().enumerate() {
+                let back = glyph.user_data.back;
+                if back[3] != 0 && back != default_back {
+                    let (x, y, width, height, _) = cell(column, line, *y);
+                    canvas.draw_rectangle(x, y, width, height, back);
+                }
+            }
+        }
 
-            if ctype != Block && glyph.char_data.rasterize() {
-                canvas.draw_monochrome_image::<MonoImage, u8>(
-                    glyph.x as isize,
-                    glyph.y as isize,
-                    image,
-                    Rgba::DARK_GRAY,
-                    Rgba::WHITE
-                );
+        for (number, line, y) in &lines {
+            // Continued rows start with the wrap indicator, on their baseline.
+            if let Some(indicator) = &indicator {
+                for row in 1..line.rows.len() {
+                    let top = y + row as f32 * line.row_height + line.baseline - indicator.baseline;
+                    draw_label(canvas, indicator, 0.0, top, default_back, v);
+                }
             }
 
-            if cursor_render {
-                match ctype {
-                VerticalBar => {
-                    canvas.draw_rectangle(
-                        cursor_left_bound,
-                        line_top_bound,
-                        2,
-                        line_height,
-                        CURSOR_COLOR
-                    );
-                },
-                HorizontalBar => {
-                    canvas.draw_rectangle(
-                        cursor_left_bound,
-                        line_top_bound + line_height as isize - 2,
-                        cursor_width,
-                        2,
-                        CURSOR_COLOR
-                    );
-                },
-                Block => {
-                    canvas.draw_rectangle(
-                        cursor_left_bound,
-                        line_top_bound,
-                        cursor_width,
-                        line_height,
-                        CURSOR_COLOR
-                    );
-                },
-                Box => {
-                    // left line
-                    canvas.draw_rectangle(
-                        cursor_left_bound,
-                        line_top_bound,
-                        1,
-                        line_height,
-                        CURSOR_COLOR
-                    );
+            for (dx, glyph) in line.glyphs.iter().enumerate() {
 
-                    // bottom line
-                    canvas.draw_rectangle(
-                        cursor_left_bound,
-                        line_top_bound + line_height as isize - 1,
-                        cursor_width,
-                        1,
-                        CURSOR_COLOR
-                    );
+                let face = glyph.user_data;
+                let back = if face.back[3] == 0 { default_back } else { face.back };
+                let (ox, oy) = line.offset(line.row_of(dx));
+                let (gx, gy) = ((glyph.x + ox - left) as isize, (glyph.y + y + oy) as isize);
 
-                    // top line
-                    canvas.draw_rectangle(
-                        cursor_left_bound,
-                        line_top_bound,
-                        cursor_width,
-                        1,
-                        CURSOR_COLOR
-                    );
+                let cursor_render = *number == cy && dx == cx;
+                let (cursor_left_bound, line_top_bound, cursor_width, line_height, baseline) = cell(dx, line, *y);
+                let (_metrics, image) = get_image(glyph, v);
+
+                // A block cursor is drawn under its glyph, so that one comes last.
+                let under_block = ctype == CursorLook::Block && cursor_render;
+
+                if !under_block && glyph.char_data.rasterize() {
+                    if face.is_oblique() {
+                        canvas.draw_oblique_image(gx, gy, &image, back, face.fore);
+                    } else {
+                        canvas.draw_monochrome_image::<GlyphImage, u8>(
+                            gx,
+                            gy,
+                            &image,
+                            back,
+                            face.fore
+                        );
+                    }
+                }
+
+                draw_underline(canvas, cursor_left_bound, baseline + 2, cursor_width, face.underline);
+
+                if cursor_render {
+                    draw_cursor(canvas, ctype, cursor_left_bound, line_top_bound, cursor_width, line_height);
+                }
 
-                    // right line
-                    canvas.draw_rectangle(
-                        cursor_left_bound + cursor_width as isize - 1,
-                        line_top_bound,
-                        1,
-                        line_height,
-                        CURSOR_COLOR
+                if under_block && glyph.char_data.rasterize() {
+                    canvas.draw_monochrome_image::<GlyphImage, u8>(
+                        gx,
+                        gy,
+                        &image,
+                        CURSOR_COLOR,
+                        face.fore
                     );
                 }
             }
-            }
 
-            if ctype == Block && glyph.char_data.rasterize() {
-                canvas.draw_monochrome_image::<MonoImage, u8>(
-                    glyph.x as isize,
-                    glyph.y as isize,
-                    image,
-                    CURSOR_COLOR,
-                    Rgba::WHITE
-                );
+            // A cursor past the last glyph sits after it.
+            if *number == cy && cx >= line.glyphs.len() {
+                let (x, top, width, height, _) = cell(cx, line, *y);
+                draw_cursor(canvas, ctype, x, top, width, height);
             }
+        }
+    }
+}
 
-            if glyph.parent == '\n' {
-                dy += 1;
-                dx = 0;
-            } else {
-                dx += 1;
-            }
+// Draws laid out text that isn't part of the page, like the gutter, with
+// its top left at (x, y).
+fn draw_label(canvas: &mut Canvas, label: &LineLayout, x: f32, y: f32, default_back: Rgba, v: &mut FontManager) {
+    for glyph in label.glyphs.iter().filter(|g| g.char_data.rasterize()) {
+        let face = glyph.user_data;
+        let back = if face.back[3] == 0 { default_back } else { face.back };
+        let (_metrics, image) = get_image(glyph, v);
+        canvas.draw_monochrome_image::<GlyphImage, u8>(
+            (glyph.x + x) as isize,
+            (glyph.y + y) as isize,
+            &image,
+            back,
+            face.fore
+        );
+    }
+}
+
+const CURSOR_COLOR: Rgba = Rgba::new_opaque(0x60, 0xAF, 0xFF);
+
+fn draw_cursor(canvas: &mut Canvas, look: CursorLook, x: isize, top: isize, width: usize, height: usize) {
+    match look {
+        CursorLook::VerticalBar => canvas.draw_rectangle(x, top, 2, height, CURSOR_COLOR),
+        CursorLook::HorizontalBar => canvas.draw_rectangle(x, top + height as isize - 2, width, 2, CURSOR_COLOR),
+        CursorLook::Block => canvas.draw_rectangle(x, top, width, height, CURSOR_COLOR),
+        CursorLook::Box => {
+            canvas.draw_rectangle(x, top, 1, height, CURSOR_COLOR);
+            canvas.draw_rectangle(x, top + height as isize - 1, width, 1, CURSOR_COLOR);
+            canvas.draw_rectangle(x, top, width, 1, CURSOR_COLOR);
+            canvas.draw_rectangle(x + width as isize - 1, top, 1, height, CURSOR_COLOR);
         }
     }
 }
 
-pub fn layout(text: String, font_manager: &FontManager) -> Layout {
-    let mut layout = Layout::new(fontdue::layout::CoordinateSystem::PositiveYDown);
+fn draw_underline(canvas: &mut Canvas, x: isize, y: isize, width: usize, underline: Underline) {
+    match underline {
+        Underline::None => {},
+        Underline::Normal(color) => canvas.draw_rectangle(x, y, width, 1, color),
+        // A zigzag two pixels high, continuing across glyphs as it is
+        // anchored to the canvas rather than the glyph.
+        Underline::Squiggly(color) => for i in 0..width as isize {
+            let offset = [0, 1, 2, 1][(x + i).rem_euclid(4) as usize];
+            canvas.draw_rectangle(x + i, y + offset, 1, 1, color);
+        }
+    }
+}
 
-    let text = TextStyle {
-        text: text.as_str(),
-        px: font_manager.scale,
-        font_index: 0,
-        user_data: ()
-    };
-    layout.append(&[&font_manager.fonts[0]], &text);
-    layout
+// Text laid out by fontdue, with its glyphs shaped.
+pub struct TextLayout {
+    layout: Layout<Face>,
+    glyphs: Vec<GlyphPosition<Face>>
 }
 
-pub fn get_image<'a, T: Clone + Copy>(glyph: &GlyphPosition<T>, font_manager: &'a mut FontManager) -> &'a (Metrics, MonoImage) {
+impl TextLayout {
+    // One glyph per char of the text, line breaks included.
+    pub fn glyphs(&self) -> &[GlyphPosition<Face>] {
+        &self.glyphs
+    }
 
-    font_manager.cache.entry(glyph.key).or_insert({
-        let (metrics, raster) = font_manager.fonts[0].rasterize_indexed(glyph.key.glyph_index, glyph.key.px);
+    pub fn lines(&self) -> Option<&Vec<LinePosition>> {
+        self.layout.lines()
+    }
+}
 
-        let new_image = MonoImage {
-            bytes: raster,
-            width: glyph.width,
-            height: glyph.height
+// Lays out text in runs of the same face, with the spans' faces over the
+// default one. Faces with ligatures are shaped.
+pub fn layout(text: &str, spans: &[Span], font_manager: &FontManager) -> TextLayout {
+    let mut layout = Layout::new(fontdue::layout::CoordinateSystem::PositiveYDown);
+    let default = font_manager.default_face();
+
+    // Which face each char gets: 0 for the default, or 1 + its span.
+    let mut faces = vec![0; text.chars().count()];
+    for (i, span) in spans.iter().enumerate() {
+        let end = span.range.end.min(faces.len());
+        for face in faces.iter_mut().take(end).skip(span.range.start) {
+            *face = i + 1;
+        }
+    }
+
+    let bytes: Vec<usize> = text.char_indices().map(|(b, _)| b).chain(std::iter::once(text.len())).collect();
+    let mut start = 0;
+
+    while start < faces.len() {
+        let end = faces[start..].iter().position(|f| *f != faces[start]).map_or(faces.len(), |len| start + len);
+        let face = match faces[start] {
+            0 => default,
+            i => spans[i - 1].face
         };
-        (metrics, new_image)
 
-    })
+        font_manager.append(&mut layout, &face.to_style(&text[bytes[start]..bytes[end]], font_manager));
+        start = end;
+    }
+
+    let mut glyphs = layout.glyphs().clone();
+    shaping::shape(font_manager, &mut glyphs, |face| face.ligatures);
+    TextLayout { layout, glyphs }
+}
+
+// The glyph's coverage, rasterized the first time it's drawn at its size.
+pub fn get_image<'a, T: Clone + Copy>(glyph: &GlyphPosition<T>, font_manager: &'a mut FontManager) -> (Metrics, GlyphImage<'a>) {
+    let font = &font_manager.fonts[glyph.font_index];
+    font_manager.cache.get_or_insert_with(glyph.key, || font.rasterize_indexed(glyph.key.glyph_index, glyph.key.px))
 }
@@ -1,245 +1,475 @@
-use std::{error::Error, fmt::Display};
+use std::{borrow::Cow, cell::{Ref, RefCell}, error::Error, fmt::Display, fs, path::Path};
 
 use fontdue::layout::Layout;
+use ropey::Rope;
 
+use super::history::{Edit, History, Position};
+use crate::file::{atomic_write, encoding::{self, TextFormat}};
 
 
-
+// Text is stored in a rope, so edits and line/char/byte lookups are O(log n)
+// no matter how large the file is. Lines are split on '\n' only.
 pub struct Page {
-    text: Vec<String>,
+    text: Rope,
+    history: History,
+    // The undo state and format that were last written to disk.
+    saved_state: usize,
+    format: TextFormat,
+    saved_format: TextFormat,
+    // Counts every change to the text, so a warning can tell if there were
+    // new ones since.
+    edits: usize,
+    display: RefCell<Option<String>>,
     layout: Layout
 }
 
 impl Page {
 
+    // The number of lines. A page without text still has one.
     pub fn len(&self) -> usize {
-        self.text.len()
+        self.text.len_lines()
+    }
+
+    // Whether the page has no text, though it still has its one line.
+    pub fn is_empty(&self) -> bool {
+        self.text.len_chars() == 0
+    }
+
+    pub fn rope(&self) -> &Rope {
+        &self.text
+    }
+
+    // Length of the line in chars, not counting the line break.
+    pub fn line_len(&self, line: usize) -> Option<usize> {
+        if line >= self.len() {
+            return None;
+        }
+
+        let l = self.text.line(line);
+        let len = l.len_chars();
+
+        if len != 0 && l.char(len - 1) == '\n' {
+            Some(len - 1)
+        } else {
+            Some(len)
+        }
+    }
+
+    pub fn line_to_char(&self, line: usize) -> usize {
+        self.text.line_to_char(line)
+    }
+
+    pub fn line_to_byte(&self, line: usize) -> usize {
+        self.text.line_to_byte(line)
+    }
+
+    pub fn char_to_line(&self, char_index: usize) -> usize {
+        self.text.char_to_line(char_index)
+    }
+
+    pub fn char_to_byte(&self, char_index: usize) -> usize {
+        self.text.char_to_byte(char_index)
     }
 
-    pub fn insert_line(&mut self, mut line: usize, text: &str) {
-        if line == self.text.len() {
+    pub fn byte_to_char(&self, byte_index: usize) -> usize {
+        self.text.byte_to_char(byte_index)
+    }
+
+    // Converts a (line, index) position into an absolute char index.
+    pub fn position_to_char(&self, line: usize, index: usize) -> Option<usize> {
+        if index > self.line_len(line)? {
+            return None;
+        }
+        Some(self.text.line_to_char(line) + index)
+    }
+
+    // Converts an absolute char index into a (line, index) position.
+    pub fn char_to_position(&self, char_index: usize) -> (usize, usize) {
+        let char_index = char_index.min(self.text.len_chars());
+        let line = self.text.char_to_line(char_index);
+        (line, char_index - self.text.line_to_char(line))
+    }
+
+    pub fn insert_line(&mut self, line: usize, text: &str) {
+        if line == self.len() {
             self.push_line(text);
             return;
         }
 
-        text.split('\n').for_each(|x| {
-            self.text.insert(line, x.into());
-            line += 1;
-        });
+        if line > self.len() {
+            return;
+        }
+
+        let index = self.text.line_to_char(line);
+        self.insert_text(index, text);
+        self.insert_text(index + text.chars().count(), "\n");
     }
 
     pub fn push_line(&mut self, text: &str) {
-        text.split('\n').for_each(|x| {
-            self.text.push(x.into())
-        });
+        let end = self.text.len_chars();
+        self.insert_text(end, "\n");
+        self.insert_text(end + 1, text);
     }
 
     pub fn remove_line(&mut self, line: usize) -> String {
-        self.text.remove(line)
+        let len = self.len();
+        if line >= len {
+            return String::new();
+        }
+
+        let start = self.text.line_to_char(line);
+        let content_end = start + self.line_len(line).unwrap_or(0);
+        let removed = self.text.slice(start..content_end).to_string();
+
+        if line + 1 < len {
+            self.remove_text(start, self.text.line_to_char(line + 1));
+        } else if line != 0 {
+            // The last line has no line break of its own, so take the one before it.
+            self.remove_text(start - 1, content_end);
+        } else {
+            self.remove_text(start, content_end);
+        }
+
+        removed
     }
 
-    pub fn get_line(&self, line: usize) -> Option<&str> {
-        self.text.get(line).map(|x| x.as_str())
+    pub fn get_line(&self, line: usize) -> Option<Cow<'_, str>> {
+        let len = self.line_len(line)?;
+        let start = self.text.line_to_char(line);
+        Some(self.text.slice(start..start + len).into())
     }
 
     pub fn pop_line(&mut self, _line: usize) -> Option<String> {
-        self.text.pop()
+        Some(self.remove_line(self.len() - 1))
     }
 
     pub fn insert_char(&mut self, line: usize, index: usize, c: char) -> Result<(), InsertCharError> {
 
-        let mut_line = match self.text.get_mut(line) {
+        let len = match self.line_len(line) {
             Some(l) => l,
             None => {
-                return Err(InsertCharError::LineLookupOutOfBounds { index: line, length: self.text.len() })
+                return Err(InsertCharError::LineLookupOutOfBounds { index: line, length: self.len() })
             }
         };
 
-        if index == mut_line.chars().count() {
-            self.push_char(line, c);
-            return Ok(());
-        }
-
-        if c == '\n' {
-            let byte_index = match mut_line.char_indices().nth(index) {
-                Some(b) => b,
-                None => return Err(
-                    InsertCharError::CharIndexingOutOfBounds { index, length: mut_line.chars().count() }
-                )
-            }.0;
-
-            let temp = mut_line.clone();
-
-            let (left, right) = temp.split_at(byte_index);
-            mut_line.clear();
-            mut_line.push_str(left);
-            self.insert_line(line + 1, right);
-
-            return Ok(());
+        if index > len {
+            return Err(InsertCharError::CharIndexingOutOfBounds { index, length: len });
         }
 
-        for (byte_index, _) in mut_line.char_indices().nth(index) {
-            mut_line.insert(byte_index, c);
-        }
+        let start = self.text.line_to_char(line);
+        let mut buf = [0; 4];
+        self.insert_text(start + index, c.encode_utf8(&mut buf));
         Ok(())
     }
 
     pub fn push_char(&mut self, line: usize, c: char) {
-        if c == '\n' {
-            self.insert_line(line + 1, "");
-            return;
-        }
-
-        match self.text.get_mut(line) {
+        let len = match self.line_len(line) {
             Some(l) => l,
             None => return
-        }
-        .push(c);
+        };
+
+        let _ = self.insert_char(line, len, c);
     }
 
     pub fn remove_char(&mut self, line: usize, index: usize) -> Option<char> {
 
-        let l = self.text.get_mut(line)?;
-        let byte_index = l.char_indices().nth(index)?.0;
+        if index >= self.line_len(line)? {
+            return None;
+        }
 
-        let rem = l.remove(byte_index);
+        let char_index = self.text.line_to_char(line) + index;
+        let rem = self.text.char(char_index);
+        self.remove_text(char_index, char_index + 1);
 
         Some(rem)
     }
 
     pub fn get_char(&self, line: usize, index: usize) -> Option<char> {
-        let l = self.get_line(line)?;
-        l.chars().nth(index)
+        if index >= self.line_len(line)? {
+            return None;
+        }
+        Some(self.text.char(self.text.line_to_char(line) + index))
     }
 
     pub fn pop_char(&mut self, line: usize) -> Option<char> {
-        let l = self.text.get_mut(line)?;
-
-        let c = l.pop();
-        c
+        let len = self.line_len(line)?;
+        self.remove_char(line, len.checked_sub(1)?)
     }
 
-    pub fn push_str(&mut self, mut line: usize, s: &str) {
-        let mut splits = s.split('\n');
+    pub fn push_str(&mut self, line: usize, s: &str) {
+        let len = match self.line_len(line) {
+            Some(l) => l,
+            None => return
+        };
 
-        match splits.next() {
-            Some(s) => {
-                let l = match self.text.get_mut(line) {
-                    Some(l) => l,
-                    None => return
-                };
+        self.insert_str(line, len, s);
+    }
 
-                l.push_str(s);
-            },
+    pub fn insert_str(&mut self, line: usize, index: usize, s: &str) {
+        let char_index = match self.position_to_char(line, index) {
+            Some(c) => c,
             None => return
+        };
+
+        self.insert_text(char_index, s);
+    }
+
+    pub fn get_str(&self, line: usize, start: usize, end: usize) -> Option<Cow<'_, str>> {
+        if start > end || end >= self.line_len(line)? {
+            return None;
         }
 
-        for s in splits {
-            line += 1;
-            self.insert_line(line, s);
+        let line_start = self.text.line_to_char(line);
+        Some(self.text.slice(line_start + start..=line_start + end).into())
+    }
+
+    pub fn remove_str(&mut self, line: usize, start: usize, end: usize) -> Option<String> {
+        let len = self.line_len(line)?;
+        let line_start = self.text.line_to_char(line);
+
+        let end = (end + 1).min(len);
+        if start >= end {
+            return Some(String::new());
         }
+
+        let out = self.text.slice(line_start + start..line_start + end).to_string();
+        self.remove_text(line_start + start, line_start + end);
+        Some(out)
     }
 
-    pub fn insert_str(&mut self, mut line: usize, index: usize, s: &str) {
-        let l = match self.text.get_mut(line) {
-            Some(l) => l,
-            None => return
-        };
+    pub fn clear(&mut self) {
+        let end = self.text.len_chars();
+        self.remove_text(0, end);
+    }
 
-        if l.chars().count() == index {
-            self.push_str(line, s);
+    // Every edit to the page funnels through `insert_text` and `remove_text`,
+    // which record it in the history.
+    pub fn insert_text(&mut self, char_index: usize, text: &str) {
+        if text.is_empty() || char_index > self.text.len_chars() {
             return;
         }
 
-        let byte_index = match l.char_indices().nth(index){
-            Some(b) => b,
-            None => return
-        }.0;
-        let temp = l.clone();
-        let (left, right) = temp.split_at(byte_index);
+        let before = self.char_to_position(char_index);
+        self.text.insert(char_index, text);
+        self.changed();
 
-        let mut splits = s.split('\n');
+        let after = self.char_to_position(char_index + text.chars().count());
+        self.history.record(Edit::Insert { index: char_index, text: text.into() }, before, after);
+    }
 
-        match splits.next() {
-            Some(s) => {
-                l.clear();
-                l.push_str(left);
-                l.push_str(s);
-            },
-            None => return
+    pub fn remove_text(&mut self, start: usize, end: usize) {
+        let end = end.min(self.text.len_chars());
+        if start >= end {
+            return;
         }
 
-        for s in splits {
-            line += 1;
-            self.insert_line(line, s);
-        }
+        let before = self.char_to_position(end);
+        let removed = self.text.slice(start..end).to_string();
+        self.text.remove(start..end);
+        self.changed();
 
-        let l = match self.text.get_mut(line) {
-            Some(s) => s,
-            None => return
-        };
-        l.push_str(right);
+        let after = self.char_to_position(start);
+        self.history.record(Edit::Remove { index: start, text: removed }, before, after);
     }
 
-    pub fn get_str(&self, line: usize, start: usize, end: usize) -> Option<&str> {
-        let l = self.get_line(line)?;
+    fn changed(&mut self) {
+        self.edits += 1;
+        self.display.replace(None);
+    }
 
-        let start = l.char_indices().nth(start)?.0;
-        let end = l.char_indices().nth(end)?.0;
+    pub fn edits(&self) -> usize {
+        self.edits
+    }
 
-        l.get(start..=end)
+    // Groups every edit until `commit_transaction` into a single undo step.
+    pub fn begin_transaction(&mut self, cursor: Position) {
+        self.history.begin(cursor);
     }
 
-    pub fn remove_str(&mut self, line: usize, start: usize, end: usize) -> Option<String> {
-        let l = self.text.get_mut(line)?;
-
-        let temp = l.clone();
-        let chars = temp.chars();
-        l.clear();
-        let mut counter = 0;
-        let range = start..=end;
-        let mut out = String::new();
-
-        for c in chars {
-            if range.contains(&counter) {
-                counter += 1;
-                out.push(c);
-                continue;
-            }
-            counter += 1;
-            l.push(c);
+    pub fn commit_transaction(&mut self, cursor: Position) {
+        self.history.commit(cursor);
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.history.is_open()
+    }
+
+    // Returns the cursor position from before the undone edits.
+    pub fn undo(&mut self) -> Option<Position> {
+        let cursor = self.history.undo(&mut self.text)?;
+        self.changed();
+        Some(cursor)
+    }
+
+    // Returns the cursor position from after the redone edits.
+    pub fn redo(&mut self) -> Option<Position> {
+        let cursor = self.history.redo(&mut self.text)?;
+        self.changed();
+        Some(cursor)
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    // Jumps to any state in the undo tree, on any branch.
+    pub fn goto_state(&mut self, state: usize) -> Option<Position> {
+        let cursor = self.history.goto(&mut self.text, state)?;
+        self.changed();
+        Some(cursor)
+    }
+
+    pub fn save_history(&self, file: &Path) -> anyhow::Result<()> {
+        self.history.save(file, &self.text)
+    }
+
+    // Restores the undo tree saved for `file`, if it still matches the text.
+    pub fn load_history(&mut self, file: &Path) -> anyhow::Result<bool> {
+        match History::load(file, &self.text)? {
+            Some(history) => {
+                self.history = history;
+                Ok(true)
+            },
+            None => Ok(false)
         }
-        Some(out)
     }
 
-    pub fn clear(&mut self) {
-        self.text.clear()
+    // The display string is cached until the next edit, so an idle page
+    // isn't rebuilt every frame.
+    pub fn as_string(&self) -> Ref<'_, str> {
+        if self.display.borrow().is_none() {
+            let mut s = String::with_capacity(self.text.len_bytes() + self.len());
+
+            for line in 0..self.len() {
+                if line != 0 {
+                    s.push('\n');
+                }
+                s.push_str(&self.display_line(line));
+            }
+            self.display.replace(Some(s));
+        }
+
+        Ref::map(self.display.borrow(), |s| s.as_deref().unwrap_or_default())
     }
 
-    pub fn as_string(&self) -> String {
+    // One line of `as_string`: tabs are 4 spaces, and a space at the end
+    // leaves room for the cursor.
+    pub fn display_line(&self, line: usize) -> String {
         let mut s = String::new();
 
-        let mut line_iter = self.text.iter();
+        if line < self.len() {
+            for chunk in self.text.line(line).chunks() {
+                s.push_str(&chunk.replace('\t', "    ").replace('\n', ""));
+            }
+        }
+        s.push(' ');
+        s
+    }
+
+    // The column `index` is drawn at in `as_string`, where tabs take 4 columns.
+    pub fn display_column(&self, line: usize, index: usize) -> usize {
+        let len = self.line_len(line).unwrap_or(0);
+        let start = self.text.line_to_char(line.min(self.len() - 1));
 
-        if let Some(line) = line_iter.next() {
-            s.push_str(line);
-            s.push(' ');
+        self.text.slice(start..start + index.min(len)).chars().map(|c| if c == '\t' { 4 } else { 1 }).sum()
+    }
+
+    // The char of a line drawn at a display column, the other way around from
+    // `display_column`. Columns past the end give the line's length.
+    pub fn column_index(&self, line: usize, column: usize) -> usize {
+        let len = self.line_len(line).unwrap_or(0);
+        let start = self.text.line_to_char(line.min(self.len() - 1));
+
+        let mut at = 0;
+        for (index, c) in self.text.slice(start..start + len).chars().enumerate() {
+            at += if c == '\t' { 4 } else { 1 };
+            if at > column {
+                return index;
+            }
         }
+        len
+    }
+
+    // Like `column_index`, but columns past the end give indices past it too,
+    // one a column, the way the cursor is kept past the end of short lines.
+    pub fn index_at_column(&self, line: usize, column: usize) -> usize {
+        let len = self.line_len(line).unwrap_or(0);
+        let width = self.display_column(line, len);
 
-        for line in line_iter {
-            s.push('\n');
-            s.push_str(line);
-            s.push(' ');
+        match column.checked_sub(width) {
+            Some(past) => len + past,
+            None => self.column_index(line, column)
         }
+    }
+}
+
+impl Page {
 
-        s.replace('\t', "    ")
+    // Loads a file, detecting its encoding and line endings. The text is kept
+    // with '\n' line breaks and the format is restored when saving. Also
+    // returns a warning if the undo history couldn't be restored.
+    pub fn load(path: &Path) -> anyhow::Result<(Self, Option<String>)> {
+        let (text, format) = encoding::decode(&fs::read(path)?);
+        let mut page = Page::from(text.as_str());
+        page.format = format;
+        page.saved_format = format;
+
+        let warning = page.load_history(path).err()
+            .map(|e| format!("Could not restore the undo history of {}: {e}", path.display()));
+        page.saved_state = page.history.current();
+
+        Ok((page, warning))
+    }
+
+    // Writes the page to `path` through a temp file and rename, and stores
+    // the undo tree alongside it. Returns a warning if only the tree failed.
+    pub fn save(&mut self, path: &Path) -> anyhow::Result<Option<String>> {
+        let bytes = encoding::encode(&self.text.to_string(), &self.format)?;
+
+        atomic_write(path, &bytes)?;
+        self.format.mixed_line_endings = false;
+        self.saved_state = self.history.current();
+        self.saved_format = self.format;
+
+        Ok(self.save_history(path).err()
+            .map(|e| format!("Could not save the undo history of {}: {e}", path.display())))
+    }
+
+    // Whether the text differs from what was last loaded or saved. Undoing back
+    // to the saved state clears it.
+    pub fn is_modified(&self) -> bool {
+        self.history.current() != self.saved_state || self.history.has_pending() || self.format != self.saved_format
+    }
+
+    pub fn format(&self) -> TextFormat {
+        self.format
+    }
+
+    // Changes how the page is written on the next save.
+    pub fn set_format(&mut self, format: TextFormat) {
+        self.format = format;
+    }
+}
+
+impl From<&str> for Page {
+    fn from(value: &str) -> Self {
+        Self {
+            text: Rope::from_str(value),
+            ..Default::default()
+        }
     }
 }
 
 impl Default for Page {
     fn default() -> Self {
         Self {
-            text: vec![String::new()],
+            text: Rope::new(),
+            history: Default::default(),
+            saved_state: 0,
+            format: Default::default(),
+            saved_format: Default::default(),
+            edits: 0,
+            display: RefCell::new(None),
             layout: Layout::new(fontdue::layout::CoordinateSystem::PositiveYDown)
         }
     }
@@ -271,3 +501,91 @@ impl Display for InsertCharError {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Page;
+
+    fn lines(page: &Page) -> Vec<String> {
+        (0..page.len()).map(|l| page.get_line(l).unwrap().into_owned()).collect()
+    }
+
+    #[test]
+    fn insert_and_split_lines() {
+        let mut page = Page::default();
+        assert_eq!((page.len(), page.is_empty()), (1, true));
+        page.insert_str(0, 0, "hello world");
+        assert!(!page.is_empty());
+        page.insert_char(0, 5, '\n').unwrap();
+
+        assert_eq!(lines(&page), ["hello", " world"]);
+
+        page.push_line("last");
+        page.insert_line(1, "middle");
+        assert_eq!(lines(&page), ["hello", "middle", " world", "last"]);
+        assert!(page.insert_char(9, 0, 'x').is_err());
+        assert!(page.insert_char(0, 9, 'x').is_err());
+    }
+
+    #[test]
+    fn remove_lines_and_chars() {
+        let mut page = Page::from("one\ntwo\nthree");
+
+        assert_eq!(page.remove_line(2), "three");
+        assert_eq!(lines(&page), ["one", "two"]);
+        assert_eq!(page.remove_char(0, 1), Some('n'));
+        assert_eq!(page.remove_char(0, 2), None);
+        assert_eq!(page.remove_char(99, 0), None);
+        assert_eq!(page.pop_char(1), Some('o'));
+        assert_eq!(page.remove_str(1, 0, 5).as_deref(), Some("tw"));
+        assert_eq!(lines(&page), ["oe", ""]);
+    }
+
+    #[test]
+    fn multibyte_indexing() {
+        let mut page = Page::from("añb\n日本");
+
+        assert_eq!(page.line_len(0), Some(3));
+        assert_eq!(page.get_char(1, 1), Some('本'));
+        assert_eq!(page.get_str(0, 1, 2).as_deref(), Some("ñb"));
+        assert_eq!(page.char_to_position(5), (1, 1));
+
+        page.insert_str(1, 1, "\tx\n");
+        assert_eq!(lines(&page), ["añb", "日\tx", "本"]);
+        assert_eq!(&*page.as_string(), "añb \n日    x \n本 ");
+        assert_eq!(page.display_column(1, 3), 6);
+        assert_eq!(page.display_column(0, 9), 3);
+    }
+
+    #[test]
+    fn undo_redo_transactions() {
+        let mut page = Page::from("ab");
+
+        page.begin_transaction((0, 2));
+        page.push_char(0, 'c');
+        page.push_char(0, '\n');
+        page.push_str(1, "de");
+        page.commit_transaction((1, 2));
+
+        page.remove_char(0, 0);
+        assert_eq!(lines(&page), ["bc", "de"]);
+
+        assert_eq!(page.undo(), Some((0, 1)));
+        assert_eq!(lines(&page), ["abc", "de"]);
+        assert_eq!(page.undo(), Some((0, 2)));
+        assert_eq!(lines(&page), ["ab"]);
+        assert_eq!(page.undo(), None);
+
+        assert_eq!(page.redo(), Some((1, 2)));
+        assert_eq!(lines(&page), ["abc", "de"]);
+
+        page.undo();
+        page.insert_char(0, 0, 'x').unwrap();
+        assert_eq!(lines(&page), ["xab"]);
+
+        // The old branch is still in the tree.
+        page.goto_state(1);
+        assert_eq!(lines(&page), ["abc", "de"]);
+        assert_eq!(page.history().text_at(page.rope(), 3).to_string(), "xab");
+    }
+}
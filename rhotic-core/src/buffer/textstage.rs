@@ -4,13 +4,169 @@
 
 
 
-use super::{stage::{Stage, TextStage, InputEvent, StateCommand}};
+use std::{path::{Path, PathBuf}, str::FromStr, time::{Duration, Instant}};
+
+use toml::{Table, Value};
+
+use super::stage::{Stage, TextStage, Span, InputEvent, StateCommand, Configurable, load_configuration};
+
+use super::text_buffer::Page;
+use super::view::{TextView, Wrap};
+use super::gutter::LineNumbers;
+use super::history::Position;
+use super::command::{self, Function, ArgSpec, ArgKind};
+use crate::{display::{font::{Face, Underline}, Rgba, event_loop::Key}, file::encoding::TextFormat, state::minibuffer::{Prompt, Completion, Submit}, undotree::UndoTreeView};
 
 use rhotic_macro::text_and_render;
 
 #[text_and_render]
 pub struct TextEdit {
     pub mode: Mode,
+    pub path: Option<PathBuf>,
+    // The page's edit count when warning that unsaved changes would be lost,
+    // so that asking a second time with no edits between goes ahead.
+    discard_warned: Option<usize>,
+    // A problem from opening the file, for the minibuffer.
+    message: Option<String>,
+    // Text being composed by an input method, drawn at the cursor until it is
+    // committed, and the cursor's char offset in it.
+    preedit: Option<(String, usize)>,
+    preedit_face: Face,
+    view: TextView,
+    // Whether up and down go by rows on screen or by lines of the page, which
+    // differ where lines wrap.
+    line_motion: LineMotion,
+    // The x in its row that moving by rows aims for, and where that left the
+    // cursor. Moving it any other way drops the goal.
+    goal: Option<(f32, Position)>,
+    // Where the selection started, as a line and char. It runs from there to
+    // the cursor.
+    anchor: Option<Position>,
+    selection_face: Face,
+    // Where the mouse is in the pane.
+    mouse: (usize, usize),
+    // When and where the left button was last pressed, and how many presses
+    // in a row that makes.
+    last_click: Option<(Instant, (usize, usize), usize)>,
+    // While the left button is held, what the selection grows by, and the
+    // start and end of the one first pressed on.
+    drag: Option<(Unit, Position, Position)>,
+}
+
+const ENCODINGS: &[&str] = &["utf-8", "utf-8-bom", "utf-16le", "utf-16be", "latin-1"];
+const LINE_ENDINGS: &[&str] = &["lf", "crlf", "cr"];
+const WRAPS: &[&str] = &["none", "word", "char"];
+const LINE_NUMBERS: &[&str] = &["none", "absolute", "relative", "hybrid"];
+
+// The functions of the text stage, for the `["Text Stage"]` keymap and M-x.
+pub const FUNCTIONS: &[Function<TextEdit>] = &[
+    Function::new("insert_mode", |t, _| { t.insert_mode(); StateCommand::None }),
+    Function::new("command_mode", |t, _| { t.command_mode(); StateCommand::None }),
+    Function::new("backspace", |t, _| { t.backspace(); StateCommand::None }),
+    Function::new("move_left", |t, _| { t.move_cursor_left(); StateCommand::None }),
+    Function::new("move_right", |t, _| { t.move_cursor_right(); StateCommand::None }),
+    Function::new("move_up", |t, _| { t.move_up(); StateCommand::None }),
+    Function::new("move_down", |t, _| { t.move_down(); StateCommand::None }),
+    Function::new("move_up_line", |t, _| { t.move_cursor_up(); StateCommand::None }),
+    Function::new("move_down_line", |t, _| { t.move_cursor_down(); StateCommand::None }),
+    Function::new("move_up_row", |t, _| { t.move_row(false); StateCommand::None }),
+    Function::new("move_down_row", |t, _| { t.move_row(true); StateCommand::None }),
+    Function::new("undo", |t, _| { t.undo(); StateCommand::None }),
+    Function::new("redo", |t, _| { t.redo(); StateCommand::None }),
+    Function::new("page_down", |t, _| { t.page_down(); StateCommand::None }),
+    Function::new("page_up", |t, _| { t.page_up(); StateCommand::None }),
+    Function::new("recenter", |t, _| { t.view.recenter(); StateCommand::None }),
+    Function::with_args("set_wrap", &[ArgSpec::new("wrap", ArgKind::Choice(WRAPS))], |t, a| {
+        t.view.wrap = a.text(0).unwrap_or_default().parse().unwrap_or(Wrap::None);
+        StateCommand::None
+    }),
+    Function::with_args("set_line_numbers", &[ArgSpec::new("line numbers", ArgKind::Choice(LINE_NUMBERS))], |t, a| {
+        t.view.gutter.numbers = a.text(0).unwrap_or_default().parse().unwrap_or(LineNumbers::None);
+        StateCommand::None
+    }),
+    Function::new("select_line", |t, _| { t.select_line(t.cursor_y); StateCommand::None }),
+    Function::new("deselect", |t, _| { t.anchor = None; StateCommand::None }),
+    Function::new("goto_first_line", |t, _| { t.cursor_y = 0; StateCommand::None }),
+    Function::new("goto_last_line", |t, _| { t.cursor_y = t.page.len() - 1; StateCommand::None }),
+    Function::with_args("goto_line", &[ArgSpec::new("line", ArgKind::Integer)], |t, a| {
+        let line = a.integer(0).unwrap_or(1).max(1) as usize;
+        t.cursor_y = line.min(t.page.len()) - 1;
+        StateCommand::None
+    }),
+    Function::new("undo_tree", |t, _| StateCommand::OpenStage(Box::new(t.undo_tree()))),
+    Function::with_args("goto_state", &[ArgSpec::new("state", ArgKind::Integer)], |t, a| match usize::try_from(a.integer(0).unwrap_or(-1)) {
+        Ok(state) if t.goto_state(state) => StateCommand::None,
+        _ => StateCommand::Log(String::from("There is no such state in the undo tree."))
+    }),
+    // Saving a page without a file asks where to put it.
+    Function::new("save", |t, _| match t.path {
+        Some(_) => report(t.save()),
+        None => t.prompt_path("Save as", "save_as")
+    }),
+    Function::with_args("save_as", &[ArgSpec::optional("path", ArgKind::Path)], |t, a| match a.path(0) {
+        Some(path) => report(t.save_as(path)),
+        None => t.prompt_path("Save as", "save_as")
+    }),
+    Function::with_args("open", &[ArgSpec::optional("path", ArgKind::Path)], |t, a| match a.path(0) {
+        Some(path) => report(t.open(path, false)),
+        None => t.prompt_path("Open", "open")
+    }),
+    Function::with_args("open!", &[ArgSpec::optional("path", ArgKind::Path)], |t, a| match a.path(0) {
+        Some(path) => report(t.open(path, true)),
+        None => t.prompt_path("Open", "open!")
+    }),
+    Function::new("revert", |t, _| report(t.revert(false))),
+    Function::new("revert!", |t, _| report(t.revert(true))),
+    Function::with_args("set_encoding", &[ArgSpec::new("encoding", ArgKind::Choice(ENCODINGS))], |t, a| report(t.convert(|f| {
+        f.encoding = a.text(0).unwrap_or_default().parse()?;
+        Ok(())
+    }))),
+    Function::with_args("set_line_ending", &[ArgSpec::new("line ending", ArgKind::Choice(LINE_ENDINGS))], |t, a| report(t.convert(|f| {
+        f.line_ending = a.text(0).unwrap_or_default().parse()?;
+        Ok(())
+    }))),
+    Function::with_args("set_trailing_newline", &[ArgSpec::new("trailing newline", ArgKind::Bool)], |t, a| report(t.convert(|f| {
+        f.trailing_newline = a.bool(0).unwrap_or(true);
+        Ok(())
+    }))),
+];
+
+fn report(result: anyhow::Result<Option<String>>) -> StateCommand {
+    match result {
+        Ok(None) => StateCommand::None,
+        Ok(Some(message)) => StateCommand::Log(message),
+        Err(e) => StateCommand::Log(e.to_string())
+    }
+}
+
+// Presses this close in time and pixels count as a double or triple click.
+const MULTI_CLICK_TIME: Duration = Duration::from_millis(400);
+const MULTI_CLICK_DISTANCE: usize = 4;
+
+// What clicking once, twice or three times selects.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Unit {
+    Char,
+    Word,
+    Line
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum LineMotion {
+    Visual,
+    Logical
+}
+
+impl FromStr for LineMotion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "visual" => Ok(LineMotion::Visual),
+            "logical" => Ok(LineMotion::Logical),
+            _ => anyhow::bail!("\"{s}\" is not a line motion. Use visual or logical.")
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -24,24 +180,309 @@ impl Stage for TextEdit {
 
     const NAME: &'static str = "Text Stage";
 
-    fn init(_init_args: &[&str]) -> anyhow::Result<Self> {
-        Ok(Self {
-            page: Default::default(),
+    // Opens the file at the first argument, or an empty page without one.
+    // A path that doesn't exist yet is created on the first save.
+    fn init(init_args: &[&str]) -> anyhow::Result<Self> {
+
+        let path = init_args.first().map(PathBuf::from);
+
+        let (mut page, message) = match &path {
+            Some(p) if p.exists() => Page::load(p)?,
+            _ => (Page::default(), None)
+        };
+        page.begin_transaction((0, 0));
+
+        let mut stage = Self {
+            page,
             cursor_x: 0,
             cursor_y: 0,
-            mode: Mode::Insert
+            mode: Mode::Insert,
+            path,
+            discard_warned: None,
+            message,
+            preedit: None,
+            view: TextView::default(),
+            line_motion: LineMotion::Visual,
+            goal: None,
+            anchor: None,
+            selection_face: Face { fore: Rgba::WHITE, back: Rgba::new_opaque(0x26, 0x4F, 0x78), ..Default::default() },
+            mouse: (0, 0),
+            last_click: None,
+            drag: None,
+            preedit_face: Face {
+                underline: Underline::Normal(Rgba::new_opaque(0x60, 0xAF, 0xFF)),
+                ..Default::default()
+            }
+        };
+
+        stage.configure(load_configuration::<Self>()?)?;
+        Ok(stage)
+    }
+
+
+    fn send_event(&mut self, input: InputEvent) -> StateCommand {
+        use InputEvent::*;
+
+        // Keys are resolved by the State's keymaps, so they arrive as commands.
+        match input {
+            Text(t) => {
+                self.input_text(&t);
+                StateCommand::None
+            },
+            Preedit(t, cursor) => {
+                self.preedit = (!t.is_empty() && self.mode == Mode::Insert).then(|| {
+                    let cursor = t.get(..cursor.unwrap_or(t.len())).map_or(0, |s| s.chars().count());
+                    (t.to_string(), cursor)
+                });
+                StateCommand::None
+            },
+            Scroll(delta) => {
+                self.view.scroll(delta);
+                StateCommand::None
+            },
+            MouseMove(x, y) => {
+                self.mouse = (x, y);
+                self.drag_to_mouse();
+                StateCommand::None
+            },
+            Press(Key::M1) => {
+                self.click(Instant::now());
+                StateCommand::None
+            },
+            Release(Key::M1) => {
+                self.drag = None;
+                // A click without a drag only moves the cursor.
+                if self.selection().is_none() {
+                    self.anchor = None;
+                }
+                StateCommand::None
+            },
+            Command(args) => command::run(self, args),
+            _ => StateCommand::None
+        }
+    }
+
+    fn has_unsaved_changes(&self) -> bool {
+        self.page.is_modified()
+    }
+
+    fn edits(&self) -> usize {
+        self.page.edits()
+    }
+
+    fn take_message(&mut self) -> Option<String> {
+        self.message.take()
+    }
+
+    const MODES: &'static [&'static str] = &["insert", "command"];
+
+    fn mode(&self) -> Option<&'static str> {
+        Some(match self.mode {
+            Mode::Insert => "insert",
+            Mode::Command => "command"
         })
     }
 
+    fn functions() -> &'static [Function<Self>] {
+        FUNCTIONS
+    }
 
-    fn send_event(&mut self, _input: InputEvent) -> StateCommand {
-        StateCommand::None
+    fn status_segment(&self, segment: &str) -> Option<String> {
+        match segment {
+            "file" => Some(self.path.as_ref().map_or(String::from("[No file]"), |p| p.display().to_string())),
+            "modified" => self.page.is_modified().then(|| String::from("[+]")),
+            // 1-based, with the column counted in display columns.
+            "position" => Some(format!("{}:{}", self.cursor_y + 1, self.page.display_column(self.cursor_y, self.cursor_x) + 1)),
+            "encoding" => {
+                let format = self.page.format();
+                Some(format!("{} {}", format.encoding, format.line_ending).to_uppercase())
+            },
+            _ => None
+        }
+    }
+}
+
+impl Configurable for TextEdit {
+    fn configure(&mut self, config: Table) -> anyhow::Result<()> {
+        // Keys used to be bound here, before keymap.toml.
+        if config.contains_key("keybinds") {
+            anyhow::bail!("text.toml can't bind keys anymore. Move its `[keybinds]` to `[\"Text Stage\"]` in keymap.toml.");
+        }
+
+        let lines = |key: &str| match config.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) if *n >= 0 => Ok(Some(*n as usize)),
+            Some(_) => Err(anyhow::anyhow!("`{key}` must be a number of lines."))
+        };
+
+        if let Some(n) = lines("scroll_off")? {
+            self.view.scroll_off = n;
+        }
+        if let Some(n) = lines("side_scroll_off")? {
+            self.view.side_scroll_off = n;
+        }
+        if let Some(n) = lines("wheel_lines")? {
+            self.view.wheel_lines = n;
+        }
+
+        let text = |key: &str| match config.get(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.as_str())),
+            Some(_) => Err(anyhow::anyhow!("`{key}` must be a string."))
+        };
+
+        if let Some(wrap) = text("wrap")? {
+            self.view.wrap = wrap.parse()?;
+        }
+        if let Some(indicator) = text("wrap_indicator")? {
+            self.view.wrap_indicator = indicator.to_string();
+        }
+        if let Some(motion) = text("line_motion")? {
+            self.line_motion = motion.parse()?;
+        }
+        if let Some(numbers) = text("line_numbers")? {
+            self.view.gutter.numbers = numbers.parse()?;
+        }
+
+        match config.get("sign_slots") {
+            None => {},
+            Some(Value::Array(slots)) => self.view.gutter.set_slots(slots.iter()
+                .map(|slot| slot.as_str().map(String::from))
+                .collect::<Option<_>>()
+                .ok_or_else(|| anyhow::anyhow!("`sign_slots` must be a list of names."))?),
+            Some(_) => anyhow::bail!("`sign_slots` must be a list of names.")
+        }
+
+        if let Some(Value::Table(faces)) = config.get("faces") {
+            if let Some(Value::Table(preedit)) = faces.get("preedit") {
+                self.preedit_face = Face::try_from(preedit.clone())?;
+            }
+            if let Some(Value::Table(indicator)) = faces.get("wrap_indicator") {
+                self.view.indicator_face = Face::try_from(indicator.clone())?;
+            }
+            if let Some(Value::Table(number)) = faces.get("line_number") {
+                self.view.gutter.number_face = Face::try_from(number.clone())?;
+            }
+            if let Some(Value::Table(current)) = faces.get("current_line_number") {
+                self.view.gutter.current_face = Face::try_from(current.clone())?;
+            }
+            if let Some(Value::Table(selection)) = faces.get("selection") {
+                self.selection_face = Face::try_from(selection.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn default_configuration() -> Table {
+        include_str!("../../config/text.toml").parse().unwrap_or_default()
     }
+
+    const CONFIG_FILE_NAME: &'static str = "text.toml";
 }
 
 impl TextStage for TextEdit {
     fn get_display_text(&self) -> String {
-        self.page.as_string()
+        let text = self.page.as_string();
+
+        let preedit = match &self.preedit {
+            Some((p, _)) => p,
+            None => return text.to_string()
+        };
+
+        let column = self.page.display_column(self.cursor_y, self.cursor_x);
+        let mut out = String::with_capacity(text.len() + preedit.len());
+
+        for (i, line) in text.split('\n').enumerate() {
+            if i != 0 {
+                out.push('\n');
+            }
+
+            if i == self.cursor_y {
+                let at = line.char_indices().nth(column).map_or(line.len(), |(b, _)| b);
+                out.push_str(&line[..at]);
+                out.push_str(preedit);
+                out.push_str(&line[at..]);
+            } else {
+                out.push_str(line);
+            }
+        }
+        out
+    }
+
+    // The selection is highlighted, and the preedit is underlined, like input
+    // methods expect.
+    fn get_spans(&self) -> Vec<Span> {
+        let mut start = 0;
+        let mut spans = Vec::new();
+
+        for (line, text) in self.get_display_text().split('\n').enumerate() {
+            spans.extend(self.get_line_spans(line).into_iter()
+                .map(|span| Span { range: start + span.range.start..start + span.range.end, face: span.face }));
+            start += text.chars().count() + 1;
+        }
+        spans
+    }
+
+    fn view(&mut self) -> &mut TextView {
+        &mut self.view
+    }
+
+    fn line_count(&self) -> usize {
+        self.page.len()
+    }
+
+    fn get_display_line(&self, line: usize) -> String {
+        let text = self.page.display_line(line);
+
+        match &self.preedit {
+            Some((preedit, _)) if line == self.cursor_y => {
+                let column = self.page.display_column(self.cursor_y, self.cursor_x);
+                let at = text.char_indices().nth(column).map_or(text.len(), |(b, _)| b);
+                format!("{}{preedit}{}", &text[..at], &text[at..])
+            },
+            _ => text
+        }
+    }
+
+    fn get_line_spans(&self, line: usize) -> Vec<Span> {
+        let mut spans = Vec::new();
+        let preedit = self.preedit.as_ref()
+            .filter(|_| line == self.cursor_y)
+            .map(|(preedit, _)| (self.page.display_column(self.cursor_y, self.cursor_x), preedit.chars().count()));
+
+        // Columns at or after the cursor are pushed right by the preedit.
+        let column = |index: usize| {
+            let column = self.page.display_column(line, index);
+            match preedit {
+                Some((at, len)) if column >= at => column + len,
+                _ => column
+            }
+        };
+
+        if let Some(((start_line, start), (end_line, end))) = self.selection() {
+            if (start_line..=end_line).contains(&line) {
+                let start = if line == start_line { column(start) } else { 0 };
+                // Lines the selection runs past end with their line break.
+                let end = if line == end_line {
+                    column(end)
+                } else {
+                    self.page.display_line(line).chars().count() + preedit.map_or(0, |(_, len)| len)
+                };
+                if start < end {
+                    spans.push(Span { range: start..end, face: self.selection_face });
+                }
+            }
+        }
+
+        if let Some((at, len)) = preedit {
+            spans.push(Span { range: at..at + len, face: self.preedit_face });
+        }
+        spans
+    }
+
+    fn set_cursor(&mut self, line: usize, column: usize) {
+        self.cursor_y = line.min(self.page.len() - 1);
+        self.cursor_x = self.page.index_at_column(self.cursor_y, column);
     }
 
     fn get_cursor(&self) -> (usize, usize, super::stage::CursorLook) {
@@ -49,13 +490,8 @@ impl TextStage for TextEdit {
         use Mode::*;
         (
             {
-                let len = self.page.get_line(self.cursor_y).unwrap_or("").chars().count();
-
-                if self.cursor_x > len {
-                    len
-                } else {
-                    self.cursor_x
-                }
+                let column = self.page.display_column(self.cursor_y, self.cursor_x);
+                column + self.preedit.as_ref().map_or(0, |(_, c)| *c)
             },
             self.cursor_y,
             match self.mode {
@@ -67,6 +503,230 @@ impl TextStage for TextEdit {
 }
 
 impl TextEdit {
+
+    fn prompt_path(&self, label: &str, command: &str) -> StateCommand {
+        StateCommand::Prompt(Prompt {
+            label: label.to_string(),
+            default: self.path.as_ref().map(|p| p.display().to_string()),
+            completion: Completion::Files,
+            submit: Submit::Args(vec![command.to_string()])
+        })
+    }
+
+    pub fn save(&mut self) -> anyhow::Result<Option<String>> {
+        let path = match &self.path {
+            Some(p) => p.clone(),
+            None => anyhow::bail!("This page has no file yet. Use save_as with a path.")
+        };
+
+        // Close the insert session so the saved text is a state in the undo tree.
+        let cursor = (self.cursor_y, self.cursor_x);
+        if self.page.in_transaction() {
+            self.page.commit_transaction(cursor);
+        }
+
+        let mixed = self.page.format().mixed_line_endings;
+        let result = self.page.save(&path);
+
+        if self.mode == Mode::Insert {
+            self.page.begin_transaction(cursor);
+        }
+        let warning = result?;
+
+        self.discard_warned = None;
+        let message = match mixed {
+            true => format!("Wrote {}, with its mixed line endings all {}.", path.display(), self.page.format().line_ending),
+            false => format!("Wrote {}", path.display())
+        };
+        Ok(Some(match warning {
+            Some(warning) => format!("{message}. {warning}"),
+            None => message
+        }))
+    }
+
+    // A view of the page's undo tree, from the text as it is now.
+    pub fn undo_tree(&mut self) -> UndoTreeView {
+        // Close the insert session so the text is a state in the tree.
+        let cursor = (self.cursor_y, self.cursor_x);
+        if self.page.in_transaction() {
+            self.page.commit_transaction(cursor);
+        }
+
+        let name = self.path.as_ref().map_or(String::from("[No file]"), |p| p.display().to_string());
+        let view = UndoTreeView::new(name, self.page.rope().clone(), self.page.history().clone());
+
+        if self.mode == Mode::Insert {
+            self.page.begin_transaction(cursor);
+        }
+        view
+    }
+
+    pub fn save_as(&mut self, path: &Path) -> anyhow::Result<Option<String>> {
+        let old = self.path.replace(path.to_path_buf());
+
+        let result = self.save();
+        if result.is_err() {
+            self.path = old;
+        }
+        result
+    }
+
+    // Replaces the page with the file at `path`. Unsaved changes are only
+    // thrown away if `force` is set or this is the second time asking.
+    pub fn open(&mut self, path: &Path, force: bool) -> anyhow::Result<Option<String>> {
+        if let Some(warning) = self.check_discard(force) {
+            return Ok(Some(warning));
+        }
+
+        let (mut page, warning) = if path.exists() {
+            Page::load(path)?
+        } else {
+            (Page::default(), None)
+        };
+
+        if self.mode == Mode::Insert {
+            page.begin_transaction((0, 0));
+        }
+
+        let format = page.format();
+        self.page = page;
+        self.path = Some(path.to_path_buf());
+        (self.cursor_x, self.cursor_y) = (0, 0);
+        self.anchor = None;
+
+        let mixed = format.mixed_line_endings.then(|| format!(
+            "{} mixes line endings. Saving makes them all {}.", path.display(), format.line_ending
+        ));
+        Ok(match (mixed, warning) {
+            (Some(mixed), Some(warning)) => Some(format!("{mixed} {warning}")),
+            (mixed, warning) => mixed.or(warning)
+        })
+    }
+
+    pub fn revert(&mut self, force: bool) -> anyhow::Result<Option<String>> {
+        match self.path.clone() {
+            Some(path) => self.open(&path, force),
+            None => anyhow::bail!("This page has no file to revert to.")
+        }
+    }
+
+    // Explicitly changes the encoding or line endings the file is saved with.
+    fn convert<F: FnOnce(&mut TextFormat) -> anyhow::Result<()>>(&mut self, f: F) -> anyhow::Result<Option<String>> {
+        let mut format = self.page.format();
+        f(&mut format)?;
+        self.page.set_format(format);
+
+        Ok(Some(format!(
+            "Will save as {}, {} line endings, {} trailing newline.",
+            format.encoding,
+            format.line_ending,
+            if format.trailing_newline { "with a" } else { "without a" }
+        )))
+    }
+
+    fn check_discard(&mut self, force: bool) -> Option<String> {
+        if force || self.discard_warned == Some(self.page.edits()) || !self.page.is_modified() {
+            self.discard_warned = None;
+            return None;
+        }
+
+        self.discard_warned = Some(self.page.edits());
+        Some(format!(
+            "{} has unsaved changes! Save them, or repeat the command to discard them.",
+            self.path.as_ref().map_or(String::from("This page"), |p| p.display().to_string())
+        ))
+    }
+
+    // The selected text's start and end, as lines and chars, if any is.
+    pub fn selection(&self) -> Option<(Position, Position)> {
+        let anchor = self.anchor?;
+        let cursor = (self.cursor_y, self.cursor_x.min(self.page.line_len(self.cursor_y).unwrap_or(0)));
+        (anchor != cursor).then(|| (anchor.min(cursor), anchor.max(cursor)))
+    }
+
+    // Selects a line with its line break, leaving the cursor at the start of
+    // the next one. The last line has no break, so the cursor ends it.
+    pub fn select_line(&mut self, line: usize) {
+        let (start, end) = self.unit_at(Unit::Line, (line.min(self.page.len() - 1), 0));
+        self.anchor = Some(start);
+        (self.cursor_y, self.cursor_x) = end;
+    }
+
+    // Places the cursor where the mouse is, pressed at `now`. Clicking twice selects a word and
+    // three times a line, as does clicking a line's number. Holding the button
+    // then extends the selection by those.
+    pub fn click(&mut self, now: Instant) {
+        let (x, y) = (self.mouse.0 as f32, self.mouse.1 as f32);
+
+        let clicks = match self.last_click {
+            Some((time, (px, py), clicks)) if now.duration_since(time) <= MULTI_CLICK_TIME
+                && px.abs_diff(self.mouse.0) <= MULTI_CLICK_DISTANCE
+                && py.abs_diff(self.mouse.1) <= MULTI_CLICK_DISTANCE => clicks % 3 + 1,
+            _ => 1
+        };
+        self.last_click = Some((now, self.mouse, clicks));
+
+        let (unit, position) = match self.view.gutter_line_at(x, y) {
+            Some(line) => (Unit::Line, (line, 0)),
+            None => match self.mouse_position() {
+                Some(position) => ([Unit::Char, Unit::Word, Unit::Line][clicks - 1], position),
+                None => return
+            }
+        };
+
+        let (start, end) = self.unit_at(unit, position);
+        self.anchor = Some(start);
+        (self.cursor_y, self.cursor_x) = end;
+        self.drag = Some((unit, start, end));
+    }
+
+    // Grows the selection from what was clicked to the unit under the mouse.
+    fn drag_to_mouse(&mut self) {
+        let (unit, start, end) = match self.drag {
+            Some(drag) => drag,
+            None => return
+        };
+        let position = match self.mouse_position() {
+            Some(position) => position,
+            None => return
+        };
+
+        let (to_start, to_end) = self.unit_at(unit, position);
+        let (anchor, cursor) = if to_start < start { (end, to_start) } else { (start, to_end.max(end)) };
+        self.anchor = Some(anchor);
+        (self.cursor_y, self.cursor_x) = cursor;
+    }
+
+    // The line and char under the mouse, or the nearest drawn.
+    fn mouse_position(&self) -> Option<Position> {
+        let (line, column) = self.view.nearest_position(self.mouse.0 as f32, self.mouse.1 as f32)?;
+        Some((line, self.page.column_index(line, column)))
+    }
+
+    // The start and end of the char, word or line at a position. A word is a
+    // run of word chars, of spaces or of other chars.
+    fn unit_at(&self, unit: Unit, (line, index): Position) -> (Position, Position) {
+        match unit {
+            Unit::Char => ((line, index), (line, index)),
+            Unit::Word => {
+                let chars: Vec<char> = self.page.get_line(line).unwrap_or_default().chars().collect();
+                let class = |c: char| if c.is_alphanumeric() || c == '_' { 0 } else if c.is_whitespace() { 1 } else { 2 };
+
+                let index = index.min(chars.len().saturating_sub(1));
+                let kind = match chars.get(index) {
+                    Some(c) => class(*c),
+                    None => return ((line, 0), (line, 0))
+                };
+
+                let start = chars[..index].iter().rposition(|c| class(*c) != kind).map_or(0, |i| i + 1);
+                let end = chars[index..].iter().position(|c| class(*c) != kind).map_or(chars.len(), |i| index + i);
+                ((line, start), (line, end))
+            },
+            Unit::Line if line + 1 < self.page.len() => ((line, 0), (line + 1, 0)),
+            Unit::Line => ((line, 0), (line, self.page.line_len(line).unwrap_or(0)))
+        }
+    }
+
     // Forces the cursor in bounds of the text.
     fn validate_cursor(&mut self) {
 
@@ -74,7 +734,7 @@ impl TextEdit {
             self.cursor_y = self.page.len() - 1;
         }
 
-        let c = self.page.get_line(self.cursor_y).unwrap().chars().count();
+        let c = self.page.line_len(self.cursor_y).unwrap();
 
         if c < self.cursor_x {
             self.cursor_x = c;
@@ -90,23 +750,171 @@ impl TextEdit {
         false
     }
 
+    pub fn move_cursor_right(&mut self) -> bool {
+        self.validate_cursor();
+        if self.cursor_x < self.page.line_len(self.cursor_y).unwrap_or(0) {
+            self.cursor_x += 1;
+            return true;
+        }
+        false
+    }
+
+    // Vertical motion keeps `cursor_x` past the end of short lines, so the
+    // column is remembered when moving back onto a longer one.
+    pub fn move_cursor_up(&mut self) -> bool {
+        if self.cursor_y != 0 {
+            self.cursor_y = (self.cursor_y - 1).min(self.page.len() - 1);
+            return true;
+        }
+        false
+    }
+
+    pub fn move_up(&mut self) -> bool {
+        match self.line_motion {
+            LineMotion::Visual => self.move_row(false),
+            LineMotion::Logical => self.move_cursor_up()
+        }
+    }
+
+    pub fn move_down(&mut self) -> bool {
+        match self.line_motion {
+            LineMotion::Visual => self.move_row(true),
+            LineMotion::Logical => self.move_cursor_down()
+        }
+    }
+
+    // Moves to the row above or below on screen, keeping to the x it started
+    // at. Between lines that don't wrap, that is moving by lines. Rows the
+    // view didn't lay out, like after a jump off screen, are as good as lines.
+    pub fn move_row(&mut self, down: bool) -> bool {
+        let line = self.cursor_y;
+        let len = self.page.line_len(line).unwrap_or(0);
+        // `cursor_x` may be past the end, which goes on a space a column.
+        let column = self.page.display_column(line, self.cursor_x) + self.cursor_x.saturating_sub(len);
+
+        let goal = match self.goal {
+            Some((x, at)) if at == (self.cursor_y, self.cursor_x) => Some(x),
+            _ => self.view.row_x(line, column)
+        };
+
+        let target = if down { line + 1 } else { line.wrapping_sub(1) };
+        let wraps = |line: usize| self.view.rows(line).is_some_and(|rows| rows > 1);
+
+        let moved = match goal {
+            Some(x) if wraps(line) || wraps(target) => match self.view.row_motion(line, column, x, down) {
+                Some((line, column)) => {
+                    self.cursor_y = line;
+                    self.cursor_x = self.page.index_at_column(line, column);
+                    true
+                },
+                None => self.move_line(down)
+            },
+            _ => self.move_line(down)
+        };
+
+        self.goal = goal.map(|x| (x, (self.cursor_y, self.cursor_x)));
+        moved
+    }
+
+    fn move_line(&mut self, down: bool) -> bool {
+        if down {
+            self.move_cursor_down()
+        } else {
+            self.move_cursor_up()
+        }
+    }
+
+    // Scrolls a screenful down, taking the cursor along, on the next frame.
+    pub fn page_down(&mut self) {
+        self.view.page_by(1);
+    }
+
+    pub fn page_up(&mut self) {
+        self.view.page_by(-1);
+    }
+
+    pub fn move_cursor_down(&mut self) -> bool {
+        if self.cursor_y + 1 < self.page.len() {
+            self.cursor_y += 1;
+            return true;
+        }
+        false
+    }
+
     pub fn insert_mode(&mut self) -> bool {
         if self.mode == Mode::Command {
             self.mode = Mode::Insert;
+            self.page.begin_transaction((self.cursor_y, self.cursor_x));
         }
         true
     }
 
+    // Leaving insert mode closes the insert session's undo transaction.
     pub fn command_mode(&mut self) -> bool {
+        self.page.commit_transaction((self.cursor_y, self.cursor_x));
         self.mode = Mode::Command;
         true
     }
 
+    pub fn undo(&mut self) -> bool {
+        let moved = match self.page.undo() {
+            Some((y, x)) => {
+                (self.cursor_y, self.cursor_x) = (y, x);
+                self.anchor = None;
+                true
+            },
+            None => false
+        };
+
+        if self.mode == Mode::Insert {
+            self.page.begin_transaction((self.cursor_y, self.cursor_x));
+        }
+        self.validate_cursor();
+        moved
+    }
+
+    // Restores any state of the undo tree, including ones on other branches.
+    pub fn goto_state(&mut self, state: usize) -> bool {
+        let moved = match self.page.goto_state(state) {
+            Some((y, x)) => {
+                (self.cursor_y, self.cursor_x) = (y, x);
+                self.anchor = None;
+                true
+            },
+            None => false
+        };
+
+        if self.mode == Mode::Insert {
+            self.page.begin_transaction((self.cursor_y, self.cursor_x));
+        }
+        self.validate_cursor();
+        moved
+    }
+
+    pub fn redo(&mut self) -> bool {
+        let moved = match self.page.redo() {
+            Some((y, x)) => {
+                (self.cursor_y, self.cursor_x) = (y, x);
+                self.anchor = None;
+                true
+            },
+            None => false
+        };
+
+        if self.mode == Mode::Insert {
+            self.page.begin_transaction((self.cursor_y, self.cursor_x));
+        }
+        self.validate_cursor();
+        moved
+    }
+
     pub fn backspace(&mut self) -> bool {
 
         if self.mode == Mode::Command {
             return self.move_cursor_left();
         }
+        self.anchor = None;
+        self.validate_cursor();
 
         if self.cursor_x != 0 {
             self.cursor_x -= 1;
@@ -115,19 +923,20 @@ impl TextEdit {
         } else if self.cursor_y != 0 {
             let line = self.page.remove_line(self.cursor_y);
             self.cursor_y -= 1;
-            self.cursor_x = self.page.get_line(self.cursor_y).unwrap_or("").chars().count();
+            self.cursor_x = self.page.line_len(self.cursor_y).unwrap_or(0);
             self.page.push_str(self.cursor_y, line.as_str());
         }
         true
     }
 
+    // Typing drops the selection, rather than replacing it.
     fn input_text(&mut self, text: &str) {
         self.validate_cursor();
         if self.mode == Mode::Insert {
+            self.anchor = None;
 
             for c in text.chars() {
                 match c {
-                    '\u{8}' | '\u{1b}' => {},
                     '\r' | '\n' => {
                         match self.page.insert_char(self.cursor_y, self.cursor_x, '\n') {
                             Ok(_) => {},
@@ -139,6 +948,7 @@ impl TextEdit {
                         self.cursor_y += 1;
                         self.cursor_x = 0;
                     },
+                    c if c.is_control() && c != '\t' => {},
                     _  => {
                         let res = self.page.insert_char(self.cursor_y, self.cursor_x, c);
                         match res {
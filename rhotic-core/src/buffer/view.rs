@@ -0,0 +1,1128 @@
+use std::{collections::{HashMap, hash_map::DefaultHasher}, hash::{Hash, Hasher}, ops::Range, rc::Rc, str::FromStr};
+
+use fontdue::layout::GlyphPosition;
+use winit::event::MouseScrollDelta;
+
+use super::{gutter::Gutter, stage::{layout, Span, TextStage}};
+use crate::display::{font::{Face, FontManager}, Rgba};
+
+// Where long lines break onto more rows, if at all.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub enum Wrap {
+    None,
+    // Between words, or anywhere in a word longer than a row.
+    Word,
+    Char
+}
+
+impl FromStr for Wrap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Wrap::None),
+            "word" => Ok(Wrap::Word),
+            "char" => Ok(Wrap::Char),
+            _ => anyhow::bail!("\"{s}\" is not a way to wrap. Use none, word or char.")
+        }
+    }
+}
+
+// A row on screen, as a line and which of its rows.
+pub type RowPosition = (usize, usize);
+
+// The part of a text stage that is on screen, and the layouts of the lines
+// shown lately. Only the lines on screen are laid out, and a line is laid out
+// again only once its text, spans or the font change.
+//
+// Lines that wrap take several rows, and the view scrolls by rows. The lines
+// of the last frame are kept, so events can map between the screen and the
+// page the way it was drawn.
+pub struct TextView {
+    // The first line on screen, and how many of its rows are scrolled off.
+    pub top: usize,
+    pub top_row: usize,
+    // How far the text is scrolled to the right, in pixels. Wrapped text
+    // doesn't scroll sideways.
+    pub left: f32,
+    // Rows kept between the cursor and the top and bottom while the view
+    // follows the cursor.
+    pub scroll_off: usize,
+    // The same for columns between it and the sides, when scrolling sideways.
+    pub side_scroll_off: usize,
+    // Lines one notch of the wheel scrolls by.
+    pub wheel_lines: usize,
+    pub wrap: Wrap,
+    // Drawn at the start of the rows a wrapped line continues on.
+    pub wrap_indicator: String,
+    pub indicator_face: Face,
+    pub gutter: Gutter,
+    // Wheel scrolling down that doesn't add up to a whole row yet, in pixels.
+    pending: f32,
+    // Rows to scroll down by on the next frame.
+    pending_rows: isize,
+    // Screenfuls to page down by on the next frame, and where that left the
+    // cursor, for the stage to pick up.
+    pending_pages: isize,
+    paged_cursor: Option<(usize, usize)>,
+    // Whether the view scrolls to keep the cursor on screen. The wheel stops
+    // that until the cursor moves.
+    follow: bool,
+    cursor: (usize, usize),
+    recenter: bool,
+    // Sizes from the last frame, for turning wheel notches into pixels.
+    line_height: f32,
+    column_width: f32,
+    // The width lines wrap at, less the indent of continued rows.
+    width: f32,
+    indent: f32,
+    indicator: Option<Rc<LineLayout>>,
+    // The gutter's width, which the text is drawn right of, and what it shows
+    // next to each line drawn, with the y it is drawn at.
+    gutter_width: f32,
+    gutter_labels: Vec<(usize, Rc<LineLayout>, f32)>,
+    // The lines drawn last frame.
+    visible: Range<usize>,
+    rows_shown: usize,
+    // Those lines and one more on each side, with the y of their first row.
+    shown: Vec<(usize, Rc<LineLayout>, f32)>,
+    cache: LineCache
+}
+
+impl Default for TextView {
+    fn default() -> Self {
+        Self {
+            top: 0,
+            top_row: 0,
+            left: 0.0,
+            scroll_off: 0,
+            side_scroll_off: 0,
+            wheel_lines: 3,
+            wrap: Wrap::None,
+            wrap_indicator: String::from("\u{21AA} "),
+            indicator_face: Face { fore: Rgba::GRAY, back: Rgba::new(0, 0, 0, 0), ..Default::default() },
+            gutter: Gutter::default(),
+            pending: 0.0,
+            pending_rows: 0,
+            pending_pages: 0,
+            paged_cursor: None,
+            follow: true,
+            cursor: (0, 0),
+            recenter: false,
+            line_height: 20.0,
+            column_width: 10.0,
+            width: 0.0,
+            indent: 0.0,
+            indicator: None,
+            gutter_width: 0.0,
+            gutter_labels: Vec::new(),
+            visible: 0..0,
+            rows_shown: 0,
+            shown: Vec::new(),
+            cache: LineCache::default()
+        }
+    }
+}
+
+// One display line, laid out with its top at y = 0 as if it didn't wrap,
+// and the rows it wraps onto.
+pub struct LineLayout {
+    pub glyphs: Vec<GlyphPosition<Face>>,
+    // Where the glyphs sit, from the top of their row.
+    pub baseline: f32,
+    pub row_height: f32,
+    // Of all its rows.
+    pub height: f32,
+    // The first column of each row, and its x before wrapping.
+    pub rows: Vec<(usize, f32)>,
+    // How far rows after the first are moved right, past the wrap indicator.
+    pub indent: f32,
+    // Each glyph's cell, as its x and width before wrapping.
+    cells: Vec<(f32, f32)>,
+    space: f32
+}
+
+impl LineLayout {
+    // Where the cell of the char at `column` starts before wrapping, and how
+    // wide it is. Past the last char, cells are a space wide.
+    pub fn cell(&self, column: usize) -> (f32, f32) {
+        match self.cells.get(column) {
+            Some(cell) => *cell,
+            None => {
+                let end = self.cells.last().map_or(0.0, |(x, width)| x + width);
+                (end + (column - self.cells.len()) as f32 * self.space, self.space)
+            }
+        }
+    }
+
+    // The row the char at `column` is on. Columns past the end are on the
+    // last row.
+    pub fn row_of(&self, column: usize) -> usize {
+        self.rows.partition_point(|(start, _)| *start <= column).saturating_sub(1)
+    }
+
+    // How far the glyphs of a row are moved from where they were laid out.
+    pub fn offset(&self, row: usize) -> (f32, f32) {
+        let indent = if row == 0 { 0.0 } else { self.indent };
+        (indent - self.rows[row].1, row as f32 * self.row_height)
+    }
+
+    // The row of the char at `column`, and its cell's x and width there.
+    pub fn position(&self, column: usize) -> (usize, f32, f32) {
+        let row = self.row_of(column);
+        let (x, width) = self.cell(column);
+        (row, x + self.offset(row).0, width)
+    }
+
+    // The column of the char whose cell is at `x` on a row, or the last one
+    // of the row when `x` is past it.
+    pub fn column_at(&self, row: usize, x: f32) -> usize {
+        let start = self.rows[row].0;
+        let end = self.rows.get(row + 1).map_or(self.cells.len(), |(start, _)| *start);
+        let dx = self.offset(row).0;
+
+        (start..end)
+            .find(|c| {
+                let (cx, width) = self.cell(*c);
+                x < cx + dx + width
+            })
+            .unwrap_or(end.saturating_sub(1).max(start))
+    }
+
+    // Like `column_at`, but past the end of the last row the columns go on,
+    // a space wide each, for keeping the goal of vertical motion.
+    pub fn goal_column(&self, row: usize, x: f32) -> usize {
+        let column = self.column_at(row, x);
+        let (cx, width) = self.cell(column);
+        let end = cx + self.offset(row).0 + width;
+
+        if row + 1 == self.rows.len() && x >= end && self.space > 0.0 {
+            column + 1 + ((x - end) / self.space) as usize
+        } else {
+            column
+        }
+    }
+
+    // Breaks the line into rows no wider than `width`, with rows after the
+    // first `indent` narrower.
+    fn wrap(&mut self, wrap: Wrap, width: f32, indent: f32) {
+        self.rows = vec![(0, 0.0)];
+        self.indent = indent;
+
+        let mut column = 0;
+        while wrap != Wrap::None && column < self.cells.len() {
+            let (start, start_x) = *self.rows.last().unwrap_or(&(0, 0.0));
+            let available = if self.rows.len() == 1 { width } else { width - indent };
+            let (x, cell_width) = self.cells[column];
+            let space = self.glyphs[column].parent.is_whitespace();
+
+            // Spaces may hang past the edge, so rows don't start with them.
+            if x + cell_width - start_x <= available || column == start || (wrap == Wrap::Word && space) {
+                column += 1;
+                continue;
+            }
+
+            let at = match wrap {
+                Wrap::Word => (start + 1..=column).rev()
+                    .find(|c| self.glyphs[c - 1].parent.is_whitespace() && !self.glyphs[*c].parent.is_whitespace())
+                    .unwrap_or(column),
+                _ => column
+            };
+            self.rows.push((at, self.cells[at].0));
+            column = at;
+        }
+
+        self.height = self.rows.len() as f32 * self.row_height;
+    }
+}
+
+// Line layouts by a hash of everything that goes into them.
+#[derive(Default)]
+struct LineCache {
+    entries: HashMap<u64, (Rc<LineLayout>, u64)>,
+    frame: u64,
+    laid_out: u64
+}
+
+// Layouts kept beyond the ones on screen, so scrolling back is cheap too.
+const CACHED_LINES: usize = 1024;
+// Columns one sideways notch of the wheel scrolls by.
+const WHEEL_COLUMNS: f32 = 4.0;
+
+impl TextView {
+
+    pub fn visible(&self) -> Range<usize> {
+        self.visible.clone()
+    }
+
+    // How many lines were laid out so far, for seeing that the cache works.
+    pub fn laid_out(&self) -> u64 {
+        self.cache.laid_out
+    }
+
+    // How many rows a page up or down moves by: a screenful, less a row to
+    // keep for context.
+    pub fn page(&self) -> usize {
+        self.rows_shown.saturating_sub(1).max(1)
+    }
+
+    // What is drawn at the start of continued rows, when lines wrap.
+    pub fn indicator(&self) -> Option<Rc<LineLayout>> {
+        self.indicator.clone()
+    }
+
+    pub fn gutter_width(&self) -> f32 {
+        self.gutter_width
+    }
+
+    // What the gutter shows next to each line drawn last frame, with the y
+    // to draw it at.
+    pub fn gutter_labels(&self) -> &[(usize, Rc<LineLayout>, f32)] {
+        &self.gutter_labels
+    }
+
+    // Scrolls by a wheel or touchpad movement. Positive deltas show more of
+    // what's above and to the left. The cursor stays where it is, even off
+    // screen, until it moves.
+    pub fn scroll(&mut self, delta: MouseScrollDelta) {
+        let (dx, dy) = match delta {
+            MouseScrollDelta::LineDelta(x, y) => (
+                x * WHEEL_COLUMNS * self.column_width,
+                y * self.wheel_lines as f32 * self.line_height
+            ),
+            MouseScrollDelta::PixelDelta(p) => (p.x as f32, p.y as f32)
+        };
+
+        self.follow = false;
+        if self.wrap == Wrap::None {
+            self.left = (self.left - dx).max(0.0);
+        }
+        self.pending -= dy;
+    }
+
+    // Scrolls by whole rows on the next frame, down for positive ones.
+    pub fn scroll_rows(&mut self, rows: isize) {
+        self.pending_rows += rows;
+    }
+
+    // Pages down by `page()` rows on the next frame, or up for negative
+    // pages, taking the cursor along by as many rows.
+    pub fn page_by(&mut self, pages: isize) {
+        self.pending_pages += pages;
+        self.follow = true;
+    }
+
+    // Where paging moved the cursor, as its line and display column. The
+    // stage is told once, after the frame that paged.
+    pub fn take_cursor(&mut self) -> Option<(usize, usize)> {
+        self.paged_cursor.take()
+    }
+
+    // Puts the cursor's row in the middle of the view on the next frame.
+    pub fn recenter(&mut self) {
+        self.recenter = true;
+        self.follow = true;
+    }
+
+    // The line and column of the char drawn at (x, y) of the pane last
+    // frame. Left of the text, that is the line's first char.
+    pub fn position_at(&self, x: f32, y: f32) -> Option<(usize, usize)> {
+        let (line, layout, top) = self.shown.iter().find(|(_, layout, top)| y >= *top && y < top + layout.height)?;
+        let row = (((y - top) / layout.row_height) as usize).min(layout.rows.len() - 1);
+        Some((*line, layout.column_at(row, x - self.gutter_width + self.left)))
+    }
+
+    // Like `position_at`, but above or below the lines drawn, the nearest
+    // row of them, for following a drag out of the pane.
+    pub fn nearest_position(&self, x: f32, y: f32) -> Option<(usize, usize)> {
+        let drawn: Vec<_> = self.shown.iter().filter(|(line, _, _)| self.visible.contains(line)).collect();
+        let (_, _, first) = drawn.first()?;
+        let (_, last, top) = drawn.last()?;
+
+        let y = y.min(top + last.height - 1.0).max(first.max(0.0));
+        self.position_at(x, y)
+    }
+
+    // The line whose number was drawn at (x, y) of the pane last frame, if
+    // that is in the gutter.
+    pub fn gutter_line_at(&self, x: f32, y: f32) -> Option<usize> {
+        if x >= self.gutter_width {
+            return None;
+        }
+        self.position_at(x, y).map(|(line, _)| line)
+    }
+
+    // Where a char was drawn last frame in the pane: its cell's x and the
+    // top of its row.
+    pub fn screen_position(&self, line: usize, column: usize) -> Option<(f32, f32)> {
+        let (_, layout, top) = self.shown.iter().find(|(l, _, _)| *l == line)?;
+        let (row, x, _) = layout.position(column);
+        Some((x - self.left + self.gutter_width, top + row as f32 * layout.row_height))
+    }
+
+    // How many rows a line took last frame, if it was laid out.
+    pub fn rows(&self, line: usize) -> Option<usize> {
+        self.shown.iter().find(|(l, _, _)| *l == line).map(|(_, layout, _)| layout.rows.len())
+    }
+
+    // The x of the char at `column` in its row last frame, from the left of
+    // the text.
+    pub fn row_x(&self, line: usize, column: usize) -> Option<f32> {
+        let (_, layout, _) = self.shown.iter().find(|(l, _, _)| *l == line)?;
+        Some(layout.position(column).1)
+    }
+
+    // The line and column a row above or below the char at `column`, at `x`
+    // in that row, going by last frame's rows. Past the end of a line, columns
+    // go on, so the goal isn't lost on short lines. None when that row wasn't
+    // laid out, or there is none.
+    pub fn row_motion(&self, line: usize, column: usize, x: f32, down: bool) -> Option<(usize, usize)> {
+        let i = self.shown.iter().position(|(l, _, _)| *l == line)?;
+        let layout = &self.shown[i].1;
+        let row = layout.row_of(column);
+
+        let (line, target, row) = match (down, row) {
+            (true, row) if row + 1 < layout.rows.len() => (line, layout, row + 1),
+            (true, _) => {
+                let (next, target, _) = self.shown.get(i + 1)?;
+                (*next, target, 0)
+            },
+            (false, 0) => {
+                let (previous, target, _) = self.shown.get(i.checked_sub(1)?)?;
+                (*previous, target, target.rows.len() - 1)
+            },
+            (false, row) => (line, layout, row - 1)
+        };
+
+        Some((line, target.goal_column(row, x)))
+    }
+
+    // The layout of a line of `stage`, from the cache if it didn't change.
+    pub fn line<T: TextStage + ?Sized>(&mut self, stage: &T, line: usize, v: &FontManager) -> Rc<LineLayout> {
+        let text = stage.get_display_line(line);
+        let spans = stage.get_line_spans(line);
+        self.layout(&text, &spans, true, v)
+    }
+
+    fn layout(&mut self, text: &str, spans: &[Span], wrap: bool, v: &FontManager) -> Rc<LineLayout> {
+        let wrap = if wrap { self.wrap } else { Wrap::None };
+
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        spans.hash(&mut hasher);
+        v.scale.to_bits().hash(&mut hasher);
+        v.default_face().hash(&mut hasher);
+        if wrap != Wrap::None {
+            wrap.hash(&mut hasher);
+            self.width.to_bits().hash(&mut hasher);
+            self.indent.to_bits().hash(&mut hasher);
+        }
+        let key = hasher.finish();
+
+        let frame = self.cache.frame;
+        if let Some((layout, used)) = self.cache.entries.get_mut(&key) {
+            *used = frame;
+            return layout.clone();
+        }
+
+        self.cache.laid_out += 1;
+        let mut layout = line_layout(text, spans, v);
+        layout.wrap(wrap, self.width, self.indent);
+
+        let layout = Rc::new(layout);
+        self.cache.entries.insert(key, (layout.clone(), frame));
+        layout
+    }
+
+    // Lays out the lines that show in a view of `width` by `height` pixels,
+    // after scrolling as asked, or else to keep the cursor on screen. Returns
+    // each line with its number and the y of its first row, which is above
+    // the view for a line partly scrolled off. Their x is from the left of
+    // the text, right of the gutter.
+    pub fn lines<T: TextStage + ?Sized>(&mut self, stage: &T, width: f32, height: f32, v: &FontManager) -> Vec<(usize, Rc<LineLayout>, f32)> {
+        self.cache.frame += 1;
+        self.line_height = v.line_height() as f32;
+        self.column_width = v.fonts[0].metrics(' ', v.scale).advance_width.ceil().max(1.0);
+
+        let count = stage.line_count();
+        self.gutter_width = (self.gutter.columns(count) as f32 * self.column_width).min(width);
+        let width = width - self.gutter_width;
+
+        self.width = width;
+        self.indicator = None;
+        self.indent = 0.0;
+        if self.wrap != Wrap::None {
+            self.left = 0.0;
+            if !self.wrap_indicator.is_empty() {
+                let text = self.wrap_indicator.clone();
+                let span = Span { range: 0..text.chars().count(), face: self.indicator_face };
+                let indicator = self.layout(&text, &[span], false, v);
+                self.indent = indicator.cell(indicator.glyphs.len()).0.min(width / 2.0);
+                self.indicator = Some(indicator);
+            }
+        }
+
+        let (mut column, mut cursor, _) = stage.get_cursor();
+        cursor = cursor.min(count.saturating_sub(1));
+
+        if (column, cursor) != self.cursor {
+            self.cursor = (column, cursor);
+            self.follow = true;
+        }
+
+        self.top = self.top.min(count.saturating_sub(1));
+        let rows = self.line(stage, self.top, v).rows.len();
+        self.top_row = self.top_row.min(rows - 1);
+        self.scroll_pending(stage, count, v);
+
+        if self.pending_pages != 0 {
+            (cursor, column) = self.scroll_pages(stage, count, (cursor, column), v);
+            self.cursor = (column, cursor);
+            self.paged_cursor = Some((cursor, column));
+        }
+
+        let cursor = (cursor, self.line(stage, cursor, v).row_of(column));
+        if self.recenter {
+            self.recenter = false;
+            self.center(stage, cursor, height, v);
+        } else if self.follow {
+            self.follow_cursor(stage, count, cursor, height, v);
+        }
+
+        if self.follow && self.wrap == Wrap::None {
+            self.follow_column(stage, column, cursor.0, width, v);
+        }
+
+        let mut out = Vec::new();
+        let mut line = self.top;
+        let mut y = -(self.top_row as f32) * self.line(stage, line, v).row_height;
+        self.rows_shown = 0;
+
+        while line < count && y < height {
+            let layout = self.line(stage, line, v);
+            self.rows_shown += (0..layout.rows.len())
+                .filter(|row| (0.0..height).contains(&(y + *row as f32 * layout.row_height)))
+                .count();
+
+            let line_height = layout.height;
+            out.push((line, layout, y));
+            y += line_height;
+            line += 1;
+        }
+        self.visible = self.top..line;
+
+        // Labels line up with the first row of their line, on its baseline.
+        self.gutter_labels.clear();
+        if self.gutter_width > 0.0 {
+            for (number, layout, y) in &out {
+                let (text, spans) = self.gutter.text(*number, cursor.0, count);
+                let label = self.layout(&text, &spans, false, v);
+                let y = y + layout.baseline - label.baseline;
+                self.gutter_labels.push((*number, label, y));
+            }
+        }
+
+        self.shown = out.clone();
+        if line < count {
+            let below = self.line(stage, line, v);
+            self.shown.push((line, below, y));
+        }
+        if let Some(above) = self.top.checked_sub(1) {
+            let layout = self.line(stage, above, v);
+            let top = out.first().map_or(0.0, |(_, _, y)| *y) - layout.height;
+            self.shown.insert(0, (above, layout, top));
+        }
+
+        // Past the limit, only the lines of this frame are kept.
+        if self.cache.entries.len() > CACHED_LINES.max(self.shown.len() + self.gutter_labels.len() + 1) {
+            let frame = self.cache.frame;
+            self.cache.entries.retain(|_, (_, used)| *used == frame);
+        }
+
+        out
+    }
+
+    fn top_position(&self) -> RowPosition {
+        (self.top, self.top_row)
+    }
+
+    fn set_top(&mut self, (line, row): RowPosition) {
+        self.top = line;
+        self.top_row = row;
+    }
+
+    fn row_height<T: TextStage + ?Sized>(&mut self, stage: &T, line: usize, v: &FontManager) -> f32 {
+        self.line(stage, line, v).row_height
+    }
+
+    fn next_row<T: TextStage + ?Sized>(&mut self, stage: &T, (line, row): RowPosition, count: usize, v: &FontManager) -> Option<RowPosition> {
+        if row + 1 < self.line(stage, line, v).rows.len() {
+            Some((line, row + 1))
+        } else {
+            (line + 1 < count).then_some((line + 1, 0))
+        }
+    }
+
+    fn previous_row<T: TextStage + ?Sized>(&mut self, stage: &T, (line, row): RowPosition, v: &FontManager) -> Option<RowPosition> {
+        match (line, row) {
+            (0, 0) => None,
+            (line, 0) => Some((line - 1, self.line(stage, line - 1, v).rows.len() - 1)),
+            (line, row) => Some((line, row - 1))
+        }
+    }
+
+    // Applies wheel and paging scrolls. Leftover wheel pixels are kept for
+    // the next movement.
+    fn scroll_pending<T: TextStage + ?Sized>(&mut self, stage: &T, count: usize, v: &FontManager) {
+        while self.pending_rows != 0 {
+            let next = if self.pending_rows > 0 {
+                self.next_row(stage, self.top_position(), count, v)
+            } else {
+                self.previous_row(stage, self.top_position(), v)
+            };
+
+            match next {
+                Some(position) => {
+                    self.set_top(position);
+                    self.pending_rows -= self.pending_rows.signum();
+                },
+                None => self.pending_rows = 0
+            }
+        }
+
+        loop {
+            let top = self.top_position();
+            let (next, height) = if self.pending > 0.0 {
+                (self.next_row(stage, top, count, v), self.row_height(stage, top.0, v))
+            } else if self.pending < 0.0 {
+                let previous = self.previous_row(stage, top, v);
+                (previous, previous.map_or(0.0, |p| self.row_height(stage, p.0, v)))
+            } else {
+                break;
+            };
+
+            match next {
+                None => {
+                    self.pending = 0.0;
+                    break;
+                },
+                Some(_) if self.pending.abs() < height => break,
+                Some(position) => {
+                    self.pending -= height * self.pending.signum();
+                    self.set_top(position);
+                }
+            }
+        }
+    }
+
+    // Scrolls by the pending pages, and moves the cursor as many rows, to the
+    // same x in its row. It ends up no nearer the edge it moves away from
+    // than the scroll-off margin.
+    fn scroll_pages<T: TextStage + ?Sized>(&mut self, stage: &T, count: usize, (line, column): (usize, usize), v: &FontManager) -> (usize, usize) {
+        let pages = std::mem::take(&mut self.pending_pages);
+        let (page, down) = (self.page(), pages > 0);
+        let (row, x, _) = self.line(stage, line, v).position(column);
+
+        let step = |view: &mut Self, at: RowPosition| if down {
+            view.next_row(stage, at, count, v).unwrap_or(at)
+        } else {
+            view.previous_row(stage, at, v).unwrap_or(at)
+        };
+
+        let (mut top, mut cursor) = (self.top_position(), (line, row));
+        for _ in 0..page * pages.unsigned_abs() {
+            top = step(self, top);
+            cursor = step(self, cursor);
+        }
+        self.set_top(top);
+
+        // The margin is counted from the top, down to the top row it allows
+        // paging down, or the bottom row paging up.
+        let mut edge = top;
+        let off = self.scroll_off.min(page / 2);
+        let margin = if down { off } else { page - off };
+        for _ in 0..margin {
+            edge = self.next_row(stage, edge, count, v).unwrap_or(edge);
+        }
+        let cursor = if down { cursor.max(edge) } else { cursor.min(edge) };
+
+        (cursor.0, self.line(stage, cursor.0, v).goal_column(cursor.1, x))
+    }
+
+    // Scrolls as little as keeps `scroll_off` rows around the cursor's. In a
+    // view too small for that, the margin shrinks. Only rows between the
+    // cursor and the new top are looked at, however far the cursor went.
+    fn follow_cursor<T: TextStage + ?Sized>(&mut self, stage: &T, count: usize, cursor: RowPosition, height: f32, v: &FontManager) {
+        let fit = (height / self.line_height) as usize;
+        let off = self.scroll_off.min(fit.saturating_sub(1) / 2);
+
+        let mut above = cursor;
+        for _ in 0..off {
+            match self.previous_row(stage, above, v) {
+                Some(position) => above = position,
+                None => break
+            }
+        }
+        if above < self.top_position() {
+            self.set_top(above);
+            return;
+        }
+
+        let mut last = cursor;
+        for _ in 0..off {
+            match self.next_row(stage, last, count, v) {
+                Some(position) => last = position,
+                None => break
+            }
+        }
+
+        let mut top = last;
+        let mut used = self.row_height(stage, last.0, v);
+        while top > self.top_position() {
+            let previous = match self.previous_row(stage, top, v) {
+                Some(position) => position,
+                None => break
+            };
+            let above = self.row_height(stage, previous.0, v);
+            if used + above > height {
+                break;
+            }
+            used += above;
+            top = previous;
+        }
+        self.set_top(top.min(cursor));
+    }
+
+    // Scrolls so the cursor's row is as near the middle as rows allow.
+    fn center<T: TextStage + ?Sized>(&mut self, stage: &T, cursor: RowPosition, height: f32, v: &FontManager) {
+        let mut top = cursor;
+        let mut used = self.row_height(stage, cursor.0, v) / 2.0;
+
+        while let Some(previous) = self.previous_row(stage, top, v) {
+            let above = self.row_height(stage, previous.0, v);
+            if used + above > height / 2.0 {
+                break;
+            }
+            used += above;
+            top = previous;
+        }
+        self.set_top(top);
+    }
+
+    // Scrolls sideways as little as keeps `side_scroll_off` columns around
+    // the cursor. A line that fits with the cursor isn't scrolled at all.
+    fn follow_column<T: TextStage + ?Sized>(&mut self, stage: &T, column: usize, cursor: usize, width: f32, v: &FontManager) {
+        let line = self.line(stage, cursor, v);
+        let (x, cell_width) = line.cell(column);
+        let (end, _) = line.cell(line.cells.len());
+
+        if end.max(x + cell_width) <= width {
+            self.left = 0.0;
+            return;
+        }
+
+        let fit = (width / self.column_width) as usize;
+        let margin = self.side_scroll_off.min(fit.saturating_sub(1) / 2) as f32 * self.column_width;
+
+        if x - margin < self.left {
+            self.left = (x - margin).max(0.0);
+        } else if x + cell_width + margin > self.left + width {
+            self.left = x + cell_width + margin - width;
+        }
+    }
+}
+
+fn line_layout(text: &str, spans: &[Span], v: &FontManager) -> LineLayout {
+    let layout = layout(text, spans, v);
+    // Layout rounds advances up to whole pixels, so cells do too.
+    let space = v.fonts[0].metrics(' ', v.scale).advance_width.ceil();
+
+    let cells = layout.glyphs().iter()
+        .map(|glyph| {
+            let metrics = v.fonts[glyph.font_index].metrics_indexed(glyph.key.glyph_index, glyph.key.px);
+            (glyph.x - metrics.xmin as f32, metrics.advance_width.ceil())
+        })
+        .collect();
+
+    let (baseline, height) = match layout.lines().and_then(|lines| lines.first()) {
+        Some(line) => (line.baseline_y, line.max_new_line_size),
+        // Empty lines have no glyphs to measure, so they get the font's.
+        None => {
+            let metrics = v.fonts[0].horizontal_line_metrics(v.scale);
+            (metrics.map_or(v.scale, |m| m.ascent), metrics.map_or(v.scale, |m| m.new_line_size))
+        }
+    };
+
+    LineLayout {
+        glyphs: layout.glyphs().to_vec(),
+        baseline,
+        row_height: height,
+        height,
+        rows: vec![(0, 0.0)],
+        indent: 0.0,
+        cells,
+        space
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    use crate::{buffer::{gutter::LineNumbers, stage::{InputEvent, Stage}, text_buffer::Page, textstage::TextEdit}, display::event_loop::Key};
+
+    #[test]
+    fn only_visible_lines_are_laid_out() {
+        let fonts = FontManager::new().unwrap();
+        let mut stage = TextEdit::init(&[]).unwrap();
+        let text: String = (0..100_000).map(|i| format!("line {i}\n")).collect();
+        stage.page = Page::from(text.as_str());
+
+        let height = fonts.line_height() as f32 * 10.0;
+        let mut view = TextView::default();
+
+        let lines = view.lines(&stage, 400.0, height, &fonts);
+        assert_eq!(view.visible(), 0..lines.len());
+        assert!(lines.len() <= 11);
+        assert!(view.laid_out() <= 11);
+
+        // The same lines again come from the cache.
+        let laid_out = view.laid_out();
+        view.lines(&stage, 400.0, height, &fonts);
+        assert_eq!(view.laid_out(), laid_out);
+
+        // Jumping to the end scrolls there, laying out about a screenful.
+        stage.cursor_y = 100_000;
+        view.lines(&stage, 400.0, height, &fonts);
+        assert_eq!(view.visible().end, 100_001);
+        assert!(view.top > 99_980);
+        assert!(view.laid_out() <= laid_out + 12);
+    }
+
+    #[test]
+    fn scrolling() {
+        let fonts = FontManager::new().unwrap();
+        let mut stage = TextEdit::init(&[]).unwrap();
+        let text: String = (0..100).map(|i| format!("{i}{}\n", "-".repeat(i))).collect();
+        stage.page = Page::from(text.as_str());
+        stage.view().scroll_off = 2;
+
+        let line = fonts.line_height() as f32;
+        let frame = |stage: &mut TextEdit| {
+            let mut view = std::mem::take(stage.view());
+            view.lines(stage, 300.0, line * 10.0, &fonts);
+            let visible = view.visible();
+            *stage.view() = view;
+            paged(stage);
+            visible
+        };
+
+        // Two lines stay below the cursor, then above it.
+        stage.cursor_y = 8;
+        assert_eq!(frame(&mut stage), 1..11);
+        stage.cursor_y = 2;
+        assert_eq!(frame(&mut stage), 0..10);
+        stage.cursor_y = 50;
+        frame(&mut stage);
+        stage.cursor_y = 45;
+        assert_eq!(frame(&mut stage), 43..53);
+
+        // Wheel notches scroll lines, and pixels add up to lines.
+        stage.view().scroll(MouseScrollDelta::LineDelta(0.0, -1.0));
+        assert_eq!(frame(&mut stage), 46..56);
+        stage.view().scroll(MouseScrollDelta::PixelDelta((0.0, -line as f64 * 0.6).into()));
+        assert_eq!(frame(&mut stage), 46..56);
+        stage.view().scroll(MouseScrollDelta::PixelDelta((0.0, -line as f64 * 0.6).into()));
+        assert_eq!(frame(&mut stage), 47..57);
+        stage.view().scroll(MouseScrollDelta::LineDelta(0.0, 100.0));
+        assert_eq!(frame(&mut stage), 0..10);
+
+        // Moving the cursor brings it back into view, and recentering centers it.
+        stage.cursor_x = 1;
+        assert_eq!(frame(&mut stage), 38..48);
+        stage.view().recenter();
+        assert_eq!(frame(&mut stage), 41..51);
+
+        // Long lines scroll sideways to keep the cursor in view.
+        stage.cursor_y = 99;
+        stage.cursor_x = 100;
+        frame(&mut stage);
+        let left = stage.view().left;
+        assert!(left > 0.0);
+        stage.cursor_x = 0;
+        frame(&mut stage);
+        assert_eq!(stage.view().left, 0.0);
+
+        // Columns kept off the side scroll further, but a line that fits with
+        // the cursor at its end isn't scrolled at all.
+        stage.view().side_scroll_off = 100;
+        stage.cursor_x = 100;
+        frame(&mut stage);
+        assert!(stage.view().left > left);
+        stage.cursor_y = 15;
+        stage.cursor_x = 17;
+        frame(&mut stage);
+        assert_eq!(stage.view().left, 0.0);
+        stage.view().side_scroll_off = 0;
+
+        // Paging moves the view by a screenful less a line, and the cursor
+        // along, past the margin.
+        stage.cursor_y = 0;
+        frame(&mut stage);
+        stage.page_down();
+        let visible = frame(&mut stage);
+        assert_eq!((stage.cursor_y, visible), (11, 9..19));
+        stage.page_up();
+        let visible = frame(&mut stage);
+        assert_eq!((stage.cursor_y, visible), (2, 0..10));
+    }
+
+    #[test]
+    fn wrapping() {
+        let fonts = FontManager::new().unwrap();
+        let mut stage = TextEdit::init(&[]).unwrap();
+        stage.page = Page::from("one two three four\nx");
+        stage.view().wrap_indicator = String::new();
+        stage.view().gutter.numbers = LineNumbers::None;
+
+        let column = fonts.fonts[0].metrics(' ', fonts.scale).advance_width.ceil();
+        let frame = |stage: &mut TextEdit, wrap: Wrap| {
+            let mut view = std::mem::take(stage.view());
+            view.wrap = wrap;
+            let lines = view.lines(stage, column * 10.5, 400.0, &fonts);
+            *stage.view() = view;
+            paged(stage);
+            lines
+        };
+        let starts = |lines: &[(usize, Rc<LineLayout>, f32)]| lines[0].1.rows.iter().map(|(start, _)| *start).collect::<Vec<_>>();
+
+        // Words move to the next row whole, and the spaces before them hang.
+        assert_eq!(starts(&frame(&mut stage, Wrap::Char)), vec![0, 10]);
+        let lines = frame(&mut stage, Wrap::Word);
+        assert_eq!(starts(&lines), vec![0, 8]);
+        let (row_height, line) = (lines[0].1.row_height, lines[0].1.clone());
+        assert_eq!(lines[1].2, row_height * 2.0);
+
+        // Continued rows start past the indicator, and are that much narrower.
+        stage.view().wrap_indicator = String::from("> ");
+        assert_eq!(starts(&frame(&mut stage, Wrap::Char)), vec![0, 10, 18]);
+        stage.view().wrap_indicator = String::new();
+        frame(&mut stage, Wrap::Word);
+
+        // The screen and the page map both ways.
+        let view = stage.view();
+        assert_eq!(view.position_at(column * 2.5, row_height * 1.5), Some((0, 10)));
+        assert_eq!(view.position_at(column * 2.5, row_height * 2.5), Some((1, 1)));
+        assert_eq!(view.position_at(column * 2.5, row_height * 3.5), None);
+        let (x, y) = view.screen_position(0, 10).unwrap();
+        assert_eq!((x, y), (line.cell(10).0 - line.rows[1].1, row_height));
+
+        // Up and down go by rows, or by lines.
+        stage.cursor_x = 2;
+        stage.move_row(true);
+        assert_eq!((stage.cursor_y, stage.cursor_x), (0, 10));
+        stage.move_row(true);
+        assert_eq!((stage.cursor_y, stage.cursor_x), (1, 2));
+        stage.cursor_x = 0;
+        stage.move_row(false);
+        assert_eq!((stage.cursor_y, stage.cursor_x), (0, 8));
+        stage.cursor_y = 1;
+        stage.move_cursor_up();
+        assert_eq!(stage.cursor_y, 0);
+    }
+
+    #[test]
+    fn vertical_motion_keeps_the_goal_column() {
+        let fonts = FontManager::new().unwrap();
+        let mut stage = TextEdit::init(&[]).unwrap();
+        stage.page = Page::from("a long line here\nab\nanother long line");
+        stage.view().wrap_indicator = String::new();
+        stage.view().gutter.numbers = LineNumbers::None;
+
+        let column = fonts.fonts[0].metrics(' ', fonts.scale).advance_width.ceil();
+        let moves = |stage: &mut TextEdit, down: bool| {
+            frame_rows(stage, column, 5.0, &fonts);
+            stage.move_row(down);
+            (stage.cursor_y, stage.cursor_x)
+        };
+
+        // Without wrapping, a short line doesn't lose the column.
+        stage.cursor_x = 10;
+        assert_eq!(moves(&mut stage, true), (1, 10));
+        assert_eq!(moves(&mut stage, true), (2, 10));
+
+        // Backspace on the short line deletes from its end.
+        stage.move_row(false);
+        stage.backspace();
+        assert_eq!(stage.page.rope().line(1).to_string(), "a\n");
+        stage.page.insert_char(1, 1, 'b').unwrap();
+        (stage.cursor_y, stage.cursor_x) = (0, 10);
+
+        // With it, rows keep the x of the first one, through short lines too.
+        stage.view().wrap = Wrap::Char;
+        (stage.cursor_y, stage.cursor_x) = (0, 12);
+        assert_eq!(moves(&mut stage, true), (1, 2));
+        assert_eq!(moves(&mut stage, true), (2, 2));
+        assert_eq!(moves(&mut stage, false), (1, 2));
+        assert_eq!(moves(&mut stage, false), (0, 12));
+
+        // Moving the cursor otherwise takes a new goal.
+        stage.cursor_x = 15;
+        assert_eq!(moves(&mut stage, true), (1, 5));
+        assert_eq!(moves(&mut stage, true), (2, 5));
+    }
+
+    #[test]
+    fn scrolling_wrapped_lines() {
+        let fonts = FontManager::new().unwrap();
+        let mut stage = TextEdit::init(&[]).unwrap();
+        let text: String = (0..20).map(|_| "aaaaaaaaa bbbbbbbbb ccccccccc\n").collect();
+        stage.page = Page::from(text.as_str());
+        stage.view().wrap = Wrap::Word;
+        stage.view().wrap_indicator = String::new();
+        stage.view().gutter.numbers = LineNumbers::None;
+        stage.view().scroll_off = 0;
+
+        let column = fonts.fonts[0].metrics(' ', fonts.scale).advance_width.ceil();
+        let row = frame_rows(&mut stage, column, 5.0, &fonts)[0].1.row_height;
+
+        // Each line takes three rows, so the third line's first row is at the
+        // bottom of five rows with two rows of the first line scrolled off.
+        stage.cursor_y = 2;
+        let lines = frame_rows(&mut stage, column, 5.0, &fonts);
+        assert_eq!((stage.view().top, stage.view().top_row), (0, 2));
+        assert_eq!(lines[0].2, -row * 2.0);
+        assert_eq!(stage.view().page(), 4);
+
+        // The wheel and paging scroll by rows.
+        stage.view().scroll(MouseScrollDelta::PixelDelta((0.0, -row as f64).into()));
+        frame_rows(&mut stage, column, 5.0, &fonts);
+        assert_eq!((stage.view().top, stage.view().top_row), (1, 0));
+        stage.view().scroll_rows(4);
+        frame_rows(&mut stage, column, 5.0, &fonts);
+        assert_eq!((stage.view().top, stage.view().top_row), (2, 1));
+
+        // Paging moves the view and the cursor by the same rows.
+        stage.cursor_y = 0;
+        frame_rows(&mut stage, column, 6.0, &fonts);
+        stage.view().scroll_rows(-100);
+        frame_rows(&mut stage, column, 6.0, &fonts);
+        assert_eq!(stage.view().page(), 5);
+        stage.page_down();
+        frame_rows(&mut stage, column, 6.0, &fonts);
+        assert_eq!((stage.view().top, stage.view().top_row), (1, 2));
+        assert_eq!((stage.cursor_y, stage.cursor_x), (1, 20));
+        stage.page_down();
+        frame_rows(&mut stage, column, 6.0, &fonts);
+        assert_eq!((stage.view().top, stage.view().top_row), (3, 1));
+        assert_eq!((stage.cursor_y, stage.cursor_x), (3, 10));
+        stage.page_up();
+        frame_rows(&mut stage, column, 6.0, &fonts);
+        assert_eq!((stage.view().top, stage.view().top_row), (1, 2));
+        assert_eq!((stage.cursor_y, stage.cursor_x), (1, 20));
+    }
+
+    #[test]
+    fn clicking_the_gutter_selects_a_line() {
+        let fonts = FontManager::new().unwrap();
+        let mut stage = TextEdit::init(&[]).unwrap();
+        stage.page = Page::from("first\nsecond\nthird");
+        stage.view().gutter.numbers = LineNumbers::Absolute;
+
+        let line = fonts.line_height() as f32;
+        frame_rows(&mut stage, 20.0, 5.0, &fonts);
+        let gutter = stage.view().gutter_width();
+        assert!(gutter > 0.0);
+
+        // The text starts past the gutter, and clicks there select the line.
+        assert_eq!(stage.view().position_at(gutter + 1.0, line * 1.5), Some((1, 0)));
+        assert_eq!(stage.view().gutter_line_at(gutter + 1.0, line * 1.5), None);
+
+        stage.send_event(InputEvent::MouseMove(1, (line * 1.5) as usize));
+        stage.send_event(InputEvent::Press(Key::M1));
+        assert_eq!(stage.selection(), Some(((1, 0), (2, 0))));
+        assert_eq!(stage.get_line_spans(1)[0].range, 0..7);
+        assert!(stage.get_line_spans(2).is_empty());
+
+        // The last line has no line break to take along.
+        stage.send_event(InputEvent::MouseMove(1, (line * 2.5) as usize));
+        stage.send_event(InputEvent::Press(Key::M1));
+        assert_eq!(stage.selection(), Some(((2, 0), (2, 5))));
+    }
+
+    #[test]
+    fn mouse_selection() {
+        let fonts = FontManager::new().unwrap();
+        let mut stage = TextEdit::init(&[]).unwrap();
+        stage.page = Page::from("let foo = bar;\nsecond line\nthird");
+        stage.view().gutter.numbers = LineNumbers::None;
+        stage.view().scroll_off = 0;
+
+        let column = fonts.fonts[0].metrics(' ', fonts.scale).advance_width.ceil();
+        let line = fonts.line_height() as f32;
+        let at = |x: f32, y: f32| InputEvent::MouseMove((column * x) as usize, (line * y) as usize);
+        frame_rows(&mut stage, 40.0, 5.0, &fonts);
+
+        // Presses at a time in milliseconds, so they make double clicks or not.
+        let start = Instant::now();
+        let click = |stage: &mut TextEdit, ms: u64| stage.click(start + Duration::from_millis(ms));
+
+        // A click moves the cursor, without selecting.
+        stage.send_event(at(5.5, 0.5));
+        click(&mut stage, 0);
+        stage.send_event(InputEvent::Release(Key::M1));
+        assert_eq!((stage.cursor_y, stage.cursor_x, stage.selection()), (0, 5, None));
+
+        // Clicking again selects the word, then the line, then starts over.
+        click(&mut stage, 100);
+        assert_eq!(stage.selection(), Some(((0, 4), (0, 7))));
+        click(&mut stage, 200);
+        assert_eq!(stage.selection(), Some(((0, 0), (1, 0))));
+        click(&mut stage, 300);
+        stage.send_event(InputEvent::Release(Key::M1));
+        assert_eq!(stage.selection(), None);
+
+        // Dragging selects from the press, both ways, and past the last line.
+        stage.send_event(at(1.5, 0.5));
+        click(&mut stage, 1000);
+        stage.send_event(at(2.5, 2.5));
+        assert_eq!(stage.selection(), Some(((0, 1), (2, 2))));
+        stage.send_event(at(20.0, 9.0));
+        assert_eq!(stage.selection(), Some(((0, 1), (2, 5))));
+        stage.send_event(InputEvent::Release(Key::M1));
+        assert_eq!(stage.selection(), Some(((0, 1), (2, 5))));
+
+        // Dragging after a double click grows by words.
+        stage.send_event(at(8.5, 1.5));
+        click(&mut stage, 2000);
+        click(&mut stage, 2100);
+        stage.send_event(at(1.5, 0.5));
+        assert_eq!(stage.selection(), Some(((0, 0), (1, 11))));
+        stage.send_event(InputEvent::Release(Key::M1));
+
+        // Clicks land on the row of a wrapped line they hit.
+        stage.view().wrap = Wrap::Char;
+        stage.view().wrap_indicator = String::new();
+        frame_rows(&mut stage, column, 5.0, &fonts);
+        stage.send_event(at(2.5, 1.5));
+        click(&mut stage, 3000);
+        assert_eq!((stage.cursor_y, stage.cursor_x), (0, 12));
+    }
+
+    // Moves the cursor where paging left it, like rendering does.
+    fn paged(stage: &mut TextEdit) {
+        if let Some((line, column)) = stage.view().take_cursor() {
+            stage.set_cursor(line, column);
+        }
+    }
+
+    fn frame_rows(stage: &mut TextEdit, column: f32, rows: f32, fonts: &FontManager) -> Vec<(usize, Rc<LineLayout>, f32)> {
+        let mut view = std::mem::take(stage.view());
+        let height = rows * fonts.line_height() as f32;
+        let lines = view.lines(stage, column * 10.5, height, fonts);
+        *stage.view() = view;
+        paged(stage);
+        lines
+    }
+}
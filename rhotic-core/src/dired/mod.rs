@@ -3,7 +3,7 @@ use std::{path::PathBuf, str::FromStr, ffi::OsString, fs::{ReadDir, DirEntry}, i
 use anyhow::bail;
 use fontdue::layout::{Layout, TextStyle};
 
-use crate::{buffer::{text_buffer::Page, stage::{Stage, Render, layout, get_image, InputEvent, StateCommand}}, display::{font::FontManager, Rgba, image::MonoImage, event_loop::{Key}}};
+use crate::{buffer::{text_buffer::Page, textstage::TextEdit, stage::{Stage, Render, layout, get_image, InputEvent, StateCommand}, command::{self, Function, ArgSpec, ArgKind}}, display::{font::FontManager, Rgba, glyph_cache::GlyphImage}};
 
 mod theme;
 
@@ -34,7 +34,74 @@ struct FileEntry {
     file_type: FileType,
 }
 
+// The functions of Dired, for the `[Dired]` keymap and M-x.
+const FUNCTIONS: &[Function<Dired>] = &[
+    Function::new("move_down", |d, _| { d.move_down(); StateCommand::None }),
+    Function::new("move_up", |d, _| { d.move_up(); StateCommand::None }),
+    Function::new("parent_directory", |d, _| { d.parent_directory(); StateCommand::None }),
+    Function::new("open_entry", |d, _| d.open_entry()),
+    Function::with_args("goto_directory", &[ArgSpec::new("directory", ArgKind::Path)], |d, a| match a.path(0) {
+        Some(path) => d.goto_directory(path.clone()),
+        None => StateCommand::None
+    }),
+];
+
 impl Dired {
+    fn move_down(&mut self) {
+        if self.cursor + 1 < self.files.len() {
+            self.cursor += 1;
+
+            if self.cursor > self.scroll_top + self.scroll_window_len.saturating_sub(5) && self.cursor < self.files.len().saturating_sub(5) {
+                self.scroll_top += 1;
+            }
+        }
+    }
+
+    fn move_up(&mut self) {
+        if self.cursor != 0 {
+            self.cursor -= 1;
+
+            if self.cursor < self.scroll_top + 5 {
+                self.scroll_top = self.scroll_top.saturating_sub(1);
+            }
+        }
+    }
+
+    fn parent_directory(&mut self) {
+        if self.path.pop() {
+            self.cursor = 0;
+            self.scroll_top = 0;
+            self.update_files();
+        }
+    }
+
+    // Enters the directory under the cursor, or opens the file in a text stage.
+    fn open_entry(&mut self) -> StateCommand {
+        let selected = match self.files.get(self.cursor) {
+            Some(entry) => self.path.join(&entry.name),
+            None => return StateCommand::None
+        };
+
+        if selected.is_dir() {
+            self.goto_directory(selected)
+        } else {
+            StateCommand::StartStage(TextEdit::NAME.to_string(), vec![selected.display().to_string()])
+        }
+    }
+
+    fn goto_directory(&mut self, path: PathBuf) -> StateCommand {
+        match path.read_dir() {
+            Ok(_) => {
+                self.cursor = 0;
+                self.scroll_top = 0;
+                self.path = path;
+                self.update_files();
+                StateCommand::None
+            },
+            Err(e) => StateCommand::Log(format!("{e}"))
+        }
+    }
+
     fn update_files(&mut self) -> bool {
 
         self.files = match self.path.read_dir() {
@@ -147,71 +214,29 @@ impl Stage for Dired {
     }
 
     fn send_event(&mut self, input: InputEvent) -> StateCommand {
-
-        use Key::*;
-        use InputEvent::*;
-
         match input {
-            Press(k) | Echo(k) => match k {
-                Arrowdown => if self.cursor + 1 != self.files.len() {
-                    self.cursor += 1;
-
-                    if self.cursor > self.scroll_top + self.scroll_window_len.checked_sub(5).unwrap_or(0) && self.cursor < self.files.len().checked_sub(5).unwrap_or(0)  {
-                        self.scroll_top += 1;
-                    }
-                },
-                Arrowup => if self.cursor != 0 {
-                    self.cursor -= 1;
-
-                    if self.cursor < self.scroll_top + 5 {
-                        self.scroll_top = self.scroll_top.checked_sub(1).unwrap_or(0);
-                    }
-                },
-                Arrowleft => {
-                    if self.path.pop() {
-                        self.cursor = 0;
-                        self.scroll_top = 0;
-                        self.update_files();
-                    }
-                },
-                Arrowright => {
-                    let selected = {
-                        let mut new_path = self.path.clone();
-                        new_path.push(&self.files[self.cursor].name);
-                        new_path
-                    };
-
-                    if selected.is_dir() {
-                        match selected.read_dir() {
-                            Ok(_) => {
-                                self.cursor = 0;
-                                self.scroll_top = 0;
-                                self.path = selected;
-                                self.update_files();
-                            },
-                            Err(e) => {
-                                return StateCommand::Log(format!("{e}"));
-                            }
-                        }
-                    } else {
-                        return StateCommand::Log(String::from("The file {selected} is not a directory."));
-                    }
-                },
-                _ => {}
-            },
-            Text(_t) => {
+            InputEvent::Command(args) => command::run(self, args),
+            _ => StateCommand::None
+        }
+    }
 
-            },
-            _ => {}
+    fn functions() -> &'static [Function<Self>] {
+        FUNCTIONS
+    }
+
+    fn status_segment(&self, segment: &str) -> Option<String> {
+        match segment {
+            "file" => Some(self.path.display().to_string()),
+            "position" => Some(format!("{}/{}", self.cursor + 1, self.files.len())),
+            _ => None
         }
-        StateCommand::None
     }
 
     const NAME: &'static str = "Dired";
 }
 
 impl Render<&mut FontManager> for Dired {
-    fn render(&mut self, canvas: &mut crate::display::text_render::Canvas<&winit::window::Window, &winit::window::Window>, v: &mut FontManager) {
+    fn render(&mut self, canvas: &mut crate::display::text_render::Canvas, v: &mut FontManager) {
 
         let mut layout: Layout<FileType> = Layout::new(fontdue::layout::CoordinateSystem::PositiveYDown);
 
@@ -219,8 +244,8 @@ impl Render<&mut FontManager> for Dired {
 
         for i in self.scroll_top..(self.scroll_top + self.scroll_window_len) {
             if let Some(file) = self.files.get(i) {
-                layout.append(v.fonts.as_slice(), &file.get_text_style(v));
-                layout.append(v.fonts.as_slice(), &TextStyle { text: "\n", px: v.scale, font_index: 0, user_data: FileType::Other });
+                v.append(&mut layout, &file.get_text_style(v));
+                v.append(&mut layout, &TextStyle { text: "\n", px: v.scale, font_index: 0, user_data: FileType::Other });
             }
         }
 
@@ -266,10 +291,10 @@ impl Render<&mut FontManager> for Dired {
 
             let (_metrics, image) = get_image(glyph, v);
 
-            let val = canvas.draw_monochrome_image::<MonoImage, u8>(
+            let val = canvas.draw_monochrome_image::<GlyphImage, u8>(
                 glyph.x as isize,
                 glyph.y as isize,
-                image,
+                &image,
                 line_background_color,
                 match glyph.user_data {
                     FileType::File => self.theme.file_color,
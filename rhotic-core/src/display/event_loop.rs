@@ -1,9 +1,9 @@
-use std::{num::NonZeroU32, fmt::{Debug}};
+use std::{num::NonZeroU32, fmt::{Debug, Display}, error::Error, str::FromStr};
 use enum_iterator::{Sequence, all};
 use softbuffer::{Context, Surface};
 use winit::{
     window::{WindowBuilder},
-    event_loop::EventLoop, event::{ElementState}, dpi::PhysicalSize, keyboard::{PhysicalKey, KeyCode}};
+    event_loop::EventLoop, event::{ElementState}, dpi::PhysicalSize, keyboard::{PhysicalKey, KeyCode, NamedKey, SmolStr}};
 
 use crate::{state::application::State, buffer::stage::InputEvent};
 
@@ -26,6 +26,9 @@ pub fn start_event_loop() -> anyhow::Result<()> {
 
     let mut state = State::new()?;
 
+    // Lets input methods compose text, which arrives as `Ime` events.
+    window.set_ime_allowed(true);
+
     event_loop.run(|event, elwt| {
 
         elwt.set_control_flow(winit::event_loop::ControlFlow::Poll);
@@ -60,8 +63,29 @@ pub fn start_event_loop() -> anyhow::Result<()> {
                         window.request_redraw();
                     },
 
+                    Ime(ime) => {
+                        use winit::event::Ime::*;
+
+                        match ime {
+                            Preedit(text, cursor) => {
+                                state.send_event(InputEvent::Preedit(text.into(), cursor.map(|(start, _)| start)));
+                            },
+                            Commit(text) => {
+                                state.send_event(InputEvent::Preedit(SmolStr::default(), None));
+                                state.send_text(&text);
+                            },
+                            Disabled => {
+                                state.send_event(InputEvent::Preedit(SmolStr::default(), None));
+                            },
+                            Enabled => {}
+                        }
+                        window.request_redraw();
+                    },
+
                     CloseRequested => {
-                        elwt.exit();
+                        if state.request_exit() {
+                            elwt.exit();
+                        }
                     },
 
                     Focused(is) => {
@@ -75,6 +99,10 @@ pub fn start_event_loop() -> anyhow::Result<()> {
 
                     CursorMoved { device_id: _, position, } => {
                         state.input.mouse_position = Point::new(position.x as u32, position.y as u32);
+                        // Moving with the button held drags in the pane it was pressed in.
+                        if state.input[Key::M1] {
+                            state.send_mouse_position();
+                        }
                         window.request_redraw();
                     },
 
@@ -112,39 +140,49 @@ pub fn start_event_loop() -> anyhow::Result<()> {
                     },
                     KeyboardInput { device_id: _, event, is_synthetic: _ } => {
 
-                        if event.state == ElementState::Pressed {
-                            if let Some(_s) = event.logical_key.to_text() {
-                                //TODO
-                            }
-                        }
-
-                        let key = if let PhysicalKey::Code(k) = event.physical_key {
-                            k
-                        } else {
-                            return;
+                        let key = match event.physical_key {
+                            PhysicalKey::Code(k) => get_keycode_name(k),
+                            _ => None
                         };
 
-                        let key = if let Some(k) = get_keycode_name(key) {
-                            k
-                        } else {
-                            return;
+                        // On layouts with AltGr, right alt types chars like '@' or '€'
+                        // rather than being meta.
+                        let key = match (key, &event.logical_key) {
+                            (Some(Key::Alt), winit::keyboard::Key::Named(NamedKey::AltGraph)) => Some(Key::AltGr),
+                            _ => key
                         };
 
-                        let send_event = if event.state == ElementState::Pressed {
-                            state.input[key] = true;
-                            if event.repeat {
-                                InputEvent::Echo(key)
+                        let consumed = if let Some(key) = key {
+                            let send_event = if event.state == ElementState::Pressed {
+                                state.input[key] = true;
+                                if event.repeat {
+                                    InputEvent::Echo(key)
+                                } else {
+                                    InputEvent::Press(key)
+                                }
                             } else {
-                                InputEvent::Press(key)
-                            }
+                                state.input[key] = false;
+                                InputEvent::Release(key)
+                            };
+
+                            state.send_event(send_event)
                         } else {
-                            state.input[key] = false;
-                            InputEvent::Release(key)
+                            false
                         };
 
+                        // The text follows the keyboard layout, dead keys included,
+                        // unless the key was part of a binding.
+                        if event.state == ElementState::Pressed && !consumed {
+                            if let Some(text) = &event.text {
+                                state.send_text(text);
+                            }
+                        }
+
                         window.request_redraw();
 
-                        state.send_event(send_event);
+                        if state.should_exit {
+                            elwt.exit();
+                        }
                     },
                     _ => {}
                 }
@@ -158,7 +196,7 @@ pub fn start_event_loop() -> anyhow::Result<()> {
 #[derive(PartialEq, Debug)]
 pub struct Input {
     pub mouse_position: Pixel,
-    pub array: [bool; 85],
+    pub array: [bool; 86],
 }
 
 impl Input {
@@ -180,7 +218,7 @@ impl Default for Input {
 
         Self {
             mouse_position: Point::new(0, 0),
-            array: [false; 85],
+            array: [false; 86],
         }
     }
 }
@@ -395,7 +433,80 @@ pub enum Key {
     Control,
     Shift,
     Alt,
+    // Right alt on layouts that use it to type more chars. It isn't meta.
+    AltGr,
     M1,
     M2,
     M3
 }
+
+impl Key {
+    // Keys that also send an `InputEvent::Text` when typed.
+    pub fn produces_text(&self) -> bool {
+        use Key::*;
+        !matches!(self,
+            Backspace | Context | Delete | End | Help | Home | Insert | Pagedown | Pageup |
+            Arrowdown | Arrowleft | Arrowright | Arrowup | Numlock | Escape | Scrolllock |
+            F1 | F2 | F3 | F4 | F5 | F6 | F7 | F8 | F9 | F10 | F11 | F12 |
+            Control | Shift | Alt | AltGr | M1 | M2 | M3
+        )
+    }
+}
+
+// Keys are named by their variant in lowercase, like "arrowleft" or "f5".
+// Single characters on the key are accepted too, so "i", "7" or ";".
+impl FromStr for Key {
+    type Err = KeyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+
+        let mut chars = lower.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            let key = match c {
+                '`' => Some(Key::Grave),
+                '\\' => Some(Key::Backslash),
+                '[' => Some(Key::Bracketleft),
+                ']' => Some(Key::Bracketright),
+                ',' => Some(Key::Comma),
+                '=' => Some(Key::Equal),
+                '-' => Some(Key::Minus),
+                '.' => Some(Key::Period),
+                '\'' => Some(Key::Quote),
+                ';' => Some(Key::Semicolon),
+                '/' => Some(Key::Slash),
+                ' ' => Some(Key::Space),
+                '0'..='9' => all::<Key>().find(|k| format!("{k:?}") == format!("N{c}")),
+                _ => None
+            };
+
+            if let Some(k) = key {
+                return Ok(k);
+            }
+        }
+
+        match lower.as_str() {
+            "esc" => return Ok(Key::Escape),
+            "return" | "ret" => return Ok(Key::Enter),
+            "spc" => return Ok(Key::Space),
+            "del" => return Ok(Key::Delete),
+            "ctrl" => return Ok(Key::Control),
+            _ => {}
+        }
+
+        all::<Key>()
+            .find(|k| format!("{k:?}").to_lowercase() == lower)
+            .ok_or_else(|| KeyParseError(s.to_string()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyParseError(pub String);
+
+impl Error for KeyParseError {}
+
+impl Display for KeyParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"{}\" is not a key. Keys are single characters, or names like \"arrowleft\", \"escape\" or \"f5\".", self.0)
+    }
+}
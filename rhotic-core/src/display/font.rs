@@ -1,17 +1,32 @@
 use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::fmt::Display;
+use std::hash::{Hash, Hasher};
 use std::{fs::File, error::Error};
 use std::io::prelude::*;
 
-use fontdue::layout::GlyphRasterConfig;
-use fontdue::{Font, FontSettings, Metrics};
+use fontdue::layout::Layout;
+use fontdue::{Font, FontSettings};
 use toml::{Table, Value};
 
 use super::Rgba;
-use super::image::MonoImage;
+use super::glyph_cache::GlyphCache;
 use super::types::{TomlToRgbaError};
+use crate::buffer::stage::{Configurable, load_configuration};
+
+const MIN_SCALE: f32 = 6.0;
+const MAX_SCALE: f32 = 160.0;
+// The atlas takes a byte per pixel up front, so this is 64 MiB. That holds
+// far more glyphs than a screen shows.
+const MAX_ATLAS_SIZE: i64 = 8192;
 
 pub fn load_ttf(path: &str) -> anyhow::Result<Font> {
+    Ok(load_font_file(path)?.0)
+}
+
+// Loads a font, keeping the file's bytes for shaping.
+fn load_font_file(path: &str) -> anyhow::Result<(Font, Vec<u8>)> {
 
     let mut file = File::open(path)?;
 
@@ -20,19 +35,29 @@ pub fn load_ttf(path: &str) -> anyhow::Result<Font> {
 
     let font_settings = FontSettings::default();
 
-    let face = Font::from_bytes(buf, font_settings);
+    let face = Font::from_bytes(buf.as_slice(), font_settings);
     match face {
-        Ok(ok) => Ok(ok),
+        Ok(ok) => Ok((ok, buf)),
         Err(s) => {
             Err(FontOpenError { error: s })?
         }
     }
 }
 
+// The loaded fonts: the regular one first, then bold if there is one, then
+// the fallbacks, as configured in `fonts.toml`.
 pub struct FontManager {
     pub fonts: Vec<Font>,
-    pub cache: HashMap<GlyphRasterConfig, (Metrics, MonoImage)>,
+    // The files of `fonts`, which rustybuzz shapes from.
+    #[cfg(feature = "shaping")]
+    pub data: Vec<Vec<u8>>,
+    bold: Option<usize>,
+    // Tried in order for chars the styled font has no glyph for.
+    fallback: Vec<usize>,
+    pub cache: GlyphCache,
     pub scale: f32,
+    // The scale from `fonts.toml`, which zooming goes back to.
+    configured_scale: f32,
     pub fore: Rgba,
     pub back: Rgba,
 }
@@ -45,9 +70,11 @@ pub struct Face {
     pub scale: f32,
     pub style: Style,
     pub underline: Underline,
+    // Whether runs in this face are shaped, joining ligatures like `->`.
+    pub ligatures: bool,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Style {
     None,
     Bold,
@@ -55,7 +82,7 @@ pub enum Style {
     BoldOblique,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Underline {
     None,
     Normal(Rgba),
@@ -66,20 +93,32 @@ use fontdue::layout::TextStyle;
 
 
 impl Face {
-    fn to_style<'a>(&self, text: &'a str, manager: FontManager) -> TextStyle<'a> {
+    // The face rides along as the glyphs' user data, so the renderer knows
+    // how to draw each one.
+    pub fn to_style<'a>(&self, text: &'a str, manager: &FontManager) -> TextStyle<'a, Face> {
         TextStyle {
             text,
-            px: {
-                manager.scale * self.scale
-            },
-            font_index: match self.style {
-                Style::None => 0,
-                Style::Bold => 1,
-                _ => 2
-            },
-            user_data: ()
+            px: manager.scale * self.scale,
+            font_index: manager.font_index(self.style),
+            user_data: *self
         }
     }
+
+    pub fn is_oblique(&self) -> bool {
+        matches!(self.style, Style::Oblique | Style::BoldOblique)
+    }
+}
+
+// By hand, as the scale is a float. Faces key cached line layouts.
+impl Hash for Face {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.fore.hash(state);
+        self.back.hash(state);
+        self.scale.to_bits().hash(state);
+        self.style.hash(state);
+        self.underline.hash(state);
+        self.ligatures.hash(state);
+    }
 }
 
 
@@ -90,8 +129,7 @@ impl TryFrom<String> for Style {
 
         use Style::*;
 
-        value.to_lowercase();
-        Ok(match value.as_str() {
+        Ok(match value.to_lowercase().as_str() {
             "none" => Style::None,
             "bold" => Bold,
             "boldoblique" | "obliquebold" | "bold_oblique" | "oblique_bold" => BoldOblique,
@@ -101,6 +139,7 @@ impl TryFrom<String> for Style {
     }
 }
 
+#[derive(Debug)]
 pub struct StringToStyleError(String);
 
 impl TryFrom<Table> for Underline {
@@ -127,6 +166,7 @@ impl TryFrom<Table> for Underline {
     }
 }
 
+#[derive(Debug)]
 pub enum TomlToUnderlineError {
     ColorFieldMissing,
     TypeFieldMissing,
@@ -159,7 +199,8 @@ impl Default for Face {
             back: Rgba::BLACK,
             scale: 1.0,
             style: Default::default(),
-            underline: Default::default()
+            underline: Default::default(),
+            ligatures: true
         }
     }
 }
@@ -199,18 +240,22 @@ impl TryFrom<Table> for Face {
             Underline::None
         };
 
+        let ligatures = !matches!(value.get("ligatures"), Some(Value::Boolean(false)));
+
         Ok(
             Self {
                 fore,
                 back,
                 scale,
                 style,
-                underline
+                underline,
+                ligatures
             }
         )
     }
 }
 
+#[derive(Debug)]
 pub enum TomlFaceParseError {
     RgbaParse(TomlToRgbaError),
     FieldMissing(TomlFaceFields),
@@ -218,6 +263,19 @@ pub enum TomlFaceParseError {
     UnderLineParse(TomlToUnderlineError)
 }
 
+impl Error for TomlFaceParseError {}
+
+impl Display for TomlFaceParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TomlFaceParseError::RgbaParse(e) => write!(f, "Invalid color in face: {e:?}"),
+            TomlFaceParseError::FieldMissing(field) => write!(f, "A face is missing its {field:?} field."),
+            TomlFaceParseError::StringParse(s) => write!(f, "\"{s}\" is not a style. Use none, bold, oblique or bold_oblique."),
+            TomlFaceParseError::UnderLineParse(e) => write!(f, "Invalid underline in face: {e:?}")
+        }
+    }
+}
+
 impl From<TomlToUnderlineError> for TomlFaceParseError {
     fn from(value: TomlToUnderlineError) -> Self {
         TomlFaceParseError::UnderLineParse(value)
@@ -236,6 +294,7 @@ impl From<StringToStyleError> for TomlFaceParseError {
     }
 }
 
+#[derive(Debug)]
 pub enum TomlFaceFields {
     Foreground,
     Background,
@@ -243,22 +302,233 @@ pub enum TomlFaceFields {
 }
 
 impl FontManager {
+
+    // Changes the size of all text. Glyphs cached at the old size won't be
+    // drawn again, so they are dropped.
+    pub fn set_scale(&mut self, scale: f32) {
+        let scale = scale.clamp(MIN_SCALE, MAX_SCALE);
+        if scale != self.scale {
+            self.scale = scale;
+            self.cache.clear();
+        }
+    }
+
+    pub fn zoom(&mut self, factor: f32) {
+        self.set_scale((self.scale * factor).round());
+    }
+
+    pub fn reset_zoom(&mut self) {
+        self.set_scale(self.configured_scale);
+    }
+
+    // The height of one line of text at the current scale.
+    pub fn line_height(&self) -> usize {
+        self.fonts[0].horizontal_line_metrics(self.scale).map_or(self.scale, |m| m.new_line_size).ceil() as usize
+    }
+
+    // The font for a style: the regular font, or the bold one. There are no
+    // oblique fonts, so oblique glyphs are slanted when they are drawn.
+    pub fn font_index(&self, style: Style) -> usize {
+        match style {
+            Style::Bold | Style::BoldOblique => self.bold.unwrap_or(0),
+            _ => 0
+        }
+    }
+
+    // The font to draw `c` with: the preferred one if it has a glyph for it,
+    // or else the first fallback that does. When none do, the preferred font
+    // draws its missing glyph box.
+    pub fn font_for(&self, c: char, preferred: usize) -> usize {
+        if c.is_control() || self.fonts[preferred].lookup_glyph_index(c) != 0 {
+            return preferred;
+        }
+
+        self.fallback.iter().copied()
+            .find(|i| self.fonts[*i].lookup_glyph_index(c) != 0)
+            .unwrap_or(preferred)
+    }
+
+    // Appends text to a layout in the style's font, switching to a fallback
+    // font for each run of chars it has no glyphs for.
+    pub fn append<U: Copy>(&self, layout: &mut Layout<U>, style: &TextStyle<U>) {
+        let text = style.text;
+        let mut run: Option<(usize, usize)> = None;
+
+        let mut push = |range: Range<usize>, font_index: usize| {
+            layout.append(self.fonts.as_slice(), &TextStyle { text: &text[range], font_index, ..*style });
+        };
+
+        for (i, c) in text.char_indices() {
+            let font = self.font_for(c, style.font_index);
+
+            match run {
+                Some((start, current)) if current != font => {
+                    push(start..i, current);
+                    run = Some((i, font));
+                },
+                Some(_) => {},
+                None => run = Some((i, font))
+            }
+        }
+
+        if let Some((start, font)) = run {
+            push(start..text.len(), font);
+        }
+    }
+
+    // The face of text without a style of its own.
+    pub fn default_face(&self) -> Face {
+        Face { fore: self.fore, back: self.back, ..Default::default() }
+    }
+
+    // Loads the fonts from `fonts.toml`.
     pub fn new() -> anyhow::Result<Self> {
-        let fonts = vec![
-            load_ttf("./assets/fonts/FiraCode-Regular.ttf")?,
-            load_ttf("./assets/fonts/FiraCode-Bold.ttf")?,
-        ];
-
-        Ok(Self {
-            fonts,
-            cache: HashMap::new(),
+        let mut manager = Self {
+            fonts: Vec::new(),
+            #[cfg(feature = "shaping")]
+            data: Vec::new(),
+            bold: None,
+            fallback: Vec::new(),
+            cache: GlyphCache::default(),
             scale: 20.0,
+            configured_scale: 20.0,
             fore: Rgba::WHITE,
             back: Rgba::DARK_GRAY
-        })
+        };
+
+        manager.configure(load_configuration::<Self>()?)?;
+        Ok(manager)
     }
 }
 
+impl Configurable for FontManager {
+    fn configure(&mut self, config: Table) -> anyhow::Result<()> {
+        let names = |key: &str| -> Vec<String> {
+            match config.get(key) {
+                Some(Value::String(s)) => vec![s.clone()],
+                Some(Value::Array(a)) => a.iter().filter_map(|v| v.as_str().map(String::from)).collect(),
+                _ => Vec::new()
+            }
+        };
+
+        let directories: Vec<PathBuf> = names("directories").iter().filter_map(|d| font_directory(d)).collect();
+        let files = find_font_files(&directories);
+        let find = |name: &str| -> Option<PathBuf> {
+            if name.contains('/') {
+                return Some(PathBuf::from(name));
+            }
+            files.get(name).cloned()
+        };
+        let load = |path: PathBuf| load_font_file(&path.to_string_lossy());
+
+        let regular = match names("regular").first() {
+            Some(name) => match find(name) {
+                Some(path) => load(path)?,
+                None => anyhow::bail!("Can't find the font \"{name}\" in any of {directories:?}.")
+            },
+            None => anyhow::bail!("fonts.toml needs a `regular` font.")
+        };
+
+        let mut fonts = vec![regular];
+        let mut bold = None;
+
+        // Bold and the fallbacks are optional, as not every system has them.
+        if let Some(font) = names("bold").first().and_then(|name| find(name)).and_then(|path| load(path).ok()) {
+            fonts.push(font);
+            bold = Some(fonts.len() - 1);
+        }
+
+        let mut fallback = Vec::new();
+        for font in names("fallback").iter().filter_map(|name| find(name)).filter_map(|path| load(path).ok()) {
+            fonts.push(font);
+            fallback.push(fonts.len() - 1);
+        }
+
+        match config.get("scale") {
+            Some(Value::Float(f)) => self.configured_scale = *f as f32,
+            Some(Value::Integer(i)) => self.configured_scale = *i as f32,
+            _ => {}
+        }
+
+        match config.get("atlas_size") {
+            Some(Value::Integer(size)) if (1..=MAX_ATLAS_SIZE).contains(size) => self.cache.resize(*size as usize),
+            Some(_) => anyhow::bail!("`atlas_size` must be a number of pixels from 1 to {MAX_ATLAS_SIZE}."),
+            None => {}
+        }
+
+        let (fonts, _data): (Vec<Font>, Vec<Vec<u8>>) = fonts.into_iter().unzip();
+        self.fonts = fonts;
+        #[cfg(feature = "shaping")]
+        {
+            self.data = _data;
+        }
+        self.bold = bold;
+        self.fallback = fallback;
+        self.cache.clear();
+        self.scale = self.configured_scale;
+        Ok(())
+    }
+
+    fn default_configuration() -> Table {
+        include_str!("../../config/fonts.toml").parse().unwrap_or_default()
+    }
+
+    const CONFIG_FILE_NAME: &'static str = "fonts.toml";
+}
+
+// Expands a leading `~`. Relative directories are taken from the working
+// directory, or else from the crate, so the bundled fonts are found from
+// anywhere in a checkout.
+fn font_directory(directory: &str) -> Option<PathBuf> {
+    let path = match directory.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(std::env::var_os("HOME")?).join(rest),
+        None => PathBuf::from(directory)
+    };
+
+    if path.is_relative() && !path.is_dir() {
+        return Some(Path::new(env!("CARGO_MANIFEST_DIR")).join(path));
+    }
+    Some(path)
+}
+
+// Font files by file name. Directories are searched in order and a few
+// levels deep, as system font directories group fonts in subdirectories.
+// The first file found for a name wins.
+fn find_font_files(directories: &[PathBuf]) -> HashMap<String, PathBuf> {
+    const MAX_DEPTH: usize = 4;
+
+    fn visit(directory: &Path, depth: usize, out: &mut HashMap<String, PathBuf>) {
+        let entries = match directory.read_dir() {
+            Ok(e) => e,
+            Err(_) => return
+        };
+
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+
+            if path.is_dir() {
+                if depth < MAX_DEPTH {
+                    visit(&path, depth + 1, out);
+                }
+                continue;
+            }
+
+            let is_font = path.extension().and_then(|e| e.to_str())
+                .is_some_and(|e| matches!(e.to_lowercase().as_str(), "ttf" | "otf" | "ttc"));
+
+            if let (true, Some(name)) = (is_font, path.file_name().and_then(|n| n.to_str())) {
+                out.entry(name.to_string()).or_insert(path);
+            }
+        }
+    }
+
+    let mut out = HashMap::new();
+    for directory in directories {
+        visit(directory, 0, &mut out);
+    }
+    out
+}
+
 #[derive(Debug)]
 pub struct FontOpenError {
     error: &'static str
@@ -271,3 +541,75 @@ impl Display for FontOpenError {
         write!(f, "{}", self.error)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer::stage::{layout, get_image};
+
+    #[test]
+    fn fallback_per_glyph() {
+        let fonts = FontManager::new().unwrap();
+
+        // Which fallbacks have these depends on the system, but a char is
+        // only ever given to a font that has it.
+        for c in ['a', '☃', '漢', '∯'] {
+            let i = fonts.font_for(c, 0);
+            if fonts.fonts[0].lookup_glyph_index(c) != 0 {
+                assert_eq!(i, 0);
+            } else if i != 0 {
+                assert_ne!(fonts.fonts[i].lookup_glyph_index(c), 0);
+            }
+        }
+
+        let mut layout = Layout::new(fontdue::layout::CoordinateSystem::PositiveYDown);
+        fonts.append(&mut layout, &TextStyle::new("a☃b", 20.0, 0));
+
+        let glyphs = layout.glyphs();
+        assert_eq!(glyphs.len(), 3);
+        assert_eq!((glyphs[0].font_index, glyphs[2].font_index), (0, 0));
+        assert_eq!(glyphs[1].font_index, fonts.font_for('☃', 0));
+    }
+
+    #[test]
+    fn configured_fonts() {
+        let mut fonts = FontManager::new().unwrap();
+
+        let missing = "directories = [\"./assets/fonts\"]\nregular = \"Missing.ttf\"".parse().unwrap();
+        assert!(fonts.configure(missing).is_err());
+        let atlas = |size: usize| format!("directories = [\"./assets/fonts\"]\nregular = \"FiraCode-Regular.ttf\"\natlas_size = {size}").parse().unwrap();
+        assert!(fonts.configure(atlas(100000)).is_err());
+        fonts.configure(atlas(2048)).unwrap();
+
+        let config = "directories = [\"./assets/fonts\"]\nregular = \"FiraCode-Bold.ttf\"\nfallback = [\"FiraCode-Regular.ttf\", \"Missing.ttf\"]\nscale = 16"
+            .parse().unwrap();
+        fonts.configure(config).unwrap();
+
+        assert_eq!(fonts.fonts.len(), 2);
+        assert_eq!(fonts.font_index(Style::Bold), 0);
+        assert_eq!(fonts.scale, 16.0);
+    }
+
+    #[test]
+    fn zoom_drops_cached_glyphs() {
+        let mut fonts = FontManager::new().unwrap();
+        let text = layout("zoom", &[], &fonts);
+
+        for _ in 0..2 {
+            for glyph in text.glyphs() {
+                get_image(glyph, &mut fonts);
+            }
+        }
+        assert_eq!(fonts.cache.len(), 3);
+        assert_eq!((fonts.cache.stats().hits, fonts.cache.stats().misses), (5, 3));
+
+        fonts.zoom(1.5);
+        assert_eq!(fonts.scale, 30.0);
+        assert!(fonts.cache.is_empty());
+
+        fonts.zoom(100.0);
+        assert_eq!(fonts.scale, MAX_SCALE);
+        fonts.reset_zoom();
+        assert_eq!(fonts.scale, 20.0);
+    }
+}
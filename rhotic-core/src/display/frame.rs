@@ -0,0 +1,212 @@
+use std::path::Path;
+
+use image::RgbaImage;
+
+// Anything a `Canvas` can draw into: a row-major buffer of 0RGB pixels.
+pub trait PixelSink {
+    fn size(&self) -> (usize, usize);
+    fn pixels(&mut self) -> &mut [u32];
+}
+
+// An offscreen frame, for rendering without a window, like in tests and
+// screenshots.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Frame {
+    pixels: Vec<u32>,
+    width: usize,
+    height: usize
+}
+
+impl Frame {
+
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            pixels: vec![0; width * height],
+            width,
+            height
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Option<u32> {
+        if x >= self.width {
+            return None;
+        }
+        self.pixels.get(y * self.width + x).copied()
+    }
+
+    pub fn to_image(&self) -> RgbaImage {
+        RgbaImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let p = self.pixels[y as usize * self.width + x as usize];
+            image::Rgba([(p >> 16) as u8, (p >> 8) as u8, p as u8, 255])
+        })
+    }
+
+    pub fn from_image(image: &RgbaImage) -> Self {
+        Self {
+            pixels: image.pixels().map(|p| {
+                let [r, g, b, _] = p.0;
+                0xFF00_0000 | (r as u32) << 16 | (g as u32) << 8 | b as u32
+            }).collect(),
+            width: image.width() as usize,
+            height: image.height() as usize
+        }
+    }
+
+    pub fn save_png(&self, path: &Path) -> anyhow::Result<()> {
+        self.to_image().save(path)?;
+        Ok(())
+    }
+
+    pub fn load_png(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::from_image(&image::open(path)?.to_rgba8()))
+    }
+
+    // How many pixels have a different color. The alpha byte is ignored, as
+    // the window ignores it too.
+    pub fn count_differences(&self, other: &Frame) -> usize {
+        if (self.width, self.height) != (other.width, other.height) {
+            return self.pixels.len().max(other.pixels.len());
+        }
+
+        self.pixels.iter().zip(other.pixels.iter()).filter(|(a, b)| (*a ^ *b) & 0x00FF_FFFF != 0).count()
+    }
+}
+
+impl PixelSink for Frame {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn pixels(&mut self) -> &mut [u32] {
+        &mut self.pixels
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{buffer::{stage::{Stage, Render, InputEvent, TextStage, Span, CursorLook}, textstage::TextEdit, view::TextView, gutter::LineNumbers}, dired::Dired, display::{font::{FontManager, Face, Style, Underline}, text_render::Canvas, Rgba}};
+
+    // Compares a frame against `tests/golden/<name>.png`. Run the tests with
+    // RHOTIC_BLESS=1 to write the goldens after an intended change.
+    fn assert_golden(name: &str, frame: &Frame) {
+        let path = PathBuf::from(format!("./tests/golden/{name}.png"));
+
+        if std::env::var_os("RHOTIC_BLESS").is_some() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            frame.save_png(&path).unwrap();
+            return;
+        }
+
+        let golden = Frame::load_png(&path)
+            .unwrap_or_else(|e| panic!("Can't read {}: {e}. Run with RHOTIC_BLESS=1 to create it.", path.display()));
+
+        let differences = frame.count_differences(&golden);
+        if differences != 0 {
+            let actual = path.with_extension("actual.png");
+            frame.save_png(&actual).unwrap();
+            panic!("{differences} pixels differ from {}. This frame was written to {}.", path.display(), actual.display());
+        }
+    }
+
+    fn render<T: for<'a> Render<&'a mut FontManager>>(stage: &mut T, width: usize, height: usize) -> Frame {
+        let mut frame = Frame::new(width, height);
+        let mut fonts = FontManager::new().unwrap();
+
+        let mut canvas = Canvas::new(&mut frame);
+        canvas.fill(Rgba::DARK_GRAY);
+        stage.render(&mut canvas, &mut fonts);
+        frame
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let mut frame = Frame::new(3, 2);
+        frame.pixels()[4] = Rgba::new_opaque(10, 20, 30).into();
+
+        let path = std::env::temp_dir().join("rhotic_frame_round_trip.png");
+        frame.save_png(&path).unwrap();
+        let loaded = Frame::load_png(&path).unwrap();
+
+        assert_eq!(frame.count_differences(&loaded), 0);
+        assert_eq!(loaded.pixel(1, 1), Some(0xFF0A141E));
+    }
+
+    #[test]
+    fn text_stage_golden() {
+        let mut stage = TextEdit::init(&[]).unwrap();
+        Stage::send_event(&mut stage, InputEvent::Text("fn main() {\n\tprintln!(\"hi\");\n}".into()));
+
+        assert_golden("text_stage", &render(&mut stage, 320, 100));
+    }
+
+    #[test]
+    #[cfg(feature = "shaping")]
+    fn ligatures_golden() {
+        let mut stage = TextEdit::init(&[]).unwrap();
+        // Just the text, so the ligatures fill the frame.
+        stage.view().gutter.numbers = LineNumbers::None;
+        Stage::send_event(&mut stage, InputEvent::Text("a -> b != c\nx => y <= z".into()));
+
+        assert_golden("ligatures", &render(&mut stage, 200, 60));
+    }
+
+    #[test]
+    fn dired_golden() {
+        let dir = std::env::temp_dir().join("rhotic_dired_golden");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), "").unwrap();
+        std::fs::write(dir.join("README.md"), "").unwrap();
+
+        let mut stage = Dired::init(&[dir.to_str().unwrap()]).unwrap();
+
+        assert_golden("dired", &render(&mut stage, 240, 100));
+    }
+
+    #[derive(Default)]
+    struct Styled {
+        view: TextView
+    }
+
+    impl TextStage for Styled {
+        fn view(&mut self) -> &mut TextView {
+            &mut self.view
+        }
+
+        fn get_display_text(&self) -> String {
+            String::from("bold oblique plain\nBig warn error")
+        }
+
+        fn get_cursor(&self) -> (usize, usize, CursorLook) {
+            (0, 1, CursorLook::Box)
+        }
+
+        fn get_spans(&self) -> Vec<Span> {
+            let face = |fore| Face { fore, back: Rgba::new(0, 0, 0, 0), ..Default::default() };
+
+            vec![
+                Span { range: 0..4, face: Face { style: Style::Bold, ..face(Rgba::YELLOW) } },
+                Span { range: 5..12, face: Face { style: Style::Oblique, back: Rgba::BLUE, ..face(Rgba::WHITE) } },
+                Span { range: 19..22, face: Face { scale: 1.5, ..face(Rgba::GREEN) } },
+                Span { range: 23..27, face: Face { underline: Underline::Normal(Rgba::YELLOW), ..face(Rgba::WHITE) } },
+                Span { range: 28..33, face: Face { underline: Underline::Squiggly(Rgba::RED), ..face(Rgba::WHITE) } },
+            ]
+        }
+    }
+
+    #[test]
+    fn styled_spans_golden() {
+        assert_golden("styled_spans", &render(&mut Styled::default(), 240, 70));
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+
+use fontdue::{layout::GlyphRasterConfig, Metrics};
+
+use super::image::ColorRect;
+
+// Rasterized glyphs, packed into one atlas of coverage bitmaps so the memory
+// they take is bounded however many sizes and fonts get drawn. The atlas is
+// cut into shelves, rows as tall as the glyphs on them. When no shelf has
+// room, the one used least recently is emptied and reused.
+pub struct GlyphCache {
+    pixels: Vec<u8>,
+    size: usize,
+    shelves: Vec<Shelf>,
+    entries: HashMap<GlyphRasterConfig, Entry>,
+    // A glyph too big for the atlas. Only the last one is kept.
+    oversized: Option<(GlyphRasterConfig, Metrics, Vec<u8>)>,
+    // Counts lookups, to tell which shelf was used least recently.
+    clock: u64,
+    stats: CacheStats
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f32 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f32 / lookups as f32
+        }
+    }
+}
+
+struct Shelf {
+    y: usize,
+    height: usize,
+    // How far along the shelf is filled.
+    used: usize,
+    last_used: u64
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    metrics: Metrics,
+    // The shelf it's on and its x, or None for glyphs without pixels.
+    slot: Option<(usize, usize)>
+}
+
+// A glyph's coverage, as a rectangle of the atlas.
+pub struct GlyphImage<'a> {
+    bytes: &'a [u8],
+    width: usize,
+    height: usize,
+    stride: usize
+}
+
+impl ColorRect<u8, u8> for GlyphImage<'_> {
+    fn get_bytes(&self) -> &[u8] {
+        self.bytes
+    }
+
+    fn get_width(&self) -> usize {
+        self.width
+    }
+
+    fn get_height(&self) -> usize {
+        self.height
+    }
+
+    fn get_stride(&self) -> usize {
+        self.stride
+    }
+}
+
+impl Default for GlyphCache {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+impl GlyphCache {
+
+    // A cache with a square atlas `size` pixels wide.
+    pub fn new(size: usize) -> Self {
+        Self {
+            pixels: vec![0; size * size],
+            size,
+            shelves: Vec::new(),
+            entries: HashMap::new(),
+            oversized: None,
+            clock: 0,
+            stats: CacheStats::default()
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, key: &GlyphRasterConfig) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    // Drops every glyph, like when the scale changes and none of them will be
+    // drawn again. The atlas keeps its memory, and the stats keep counting.
+    pub fn clear(&mut self) {
+        self.stats.evictions += self.entries.len() as u64;
+        self.entries.clear();
+        self.shelves.clear();
+        self.oversized = None;
+    }
+
+    // Resizes the atlas, dropping every glyph.
+    pub fn resize(&mut self, size: usize) {
+        self.clear();
+        if size != self.size {
+            self.pixels = vec![0; size * size];
+            self.size = size;
+        }
+    }
+
+    // The glyph for `key`, rasterized by `rasterize` only when it isn't cached.
+    pub fn get_or_insert_with(
+        &mut self,
+        key: GlyphRasterConfig,
+        rasterize: impl FnOnce() -> (Metrics, Vec<u8>)
+    ) -> (Metrics, GlyphImage<'_>) {
+        self.clock += 1;
+
+        if let Some(entry) = self.entries.get(&key).copied() {
+            self.stats.hits += 1;
+            if let Some((shelf, _)) = entry.slot {
+                self.shelves[shelf].last_used = self.clock;
+            }
+            return (entry.metrics, self.image(entry));
+        }
+
+        if let Some((_, metrics, _)) = self.oversized.as_ref().filter(|(k, _, _)| *k == key) {
+            self.stats.hits += 1;
+            let metrics = *metrics;
+            return (metrics, self.oversized_image(metrics));
+        }
+
+        self.stats.misses += 1;
+        let (metrics, bytes) = rasterize();
+        let (width, height) = (metrics.width, metrics.height);
+
+        if width == 0 || height == 0 {
+            let entry = Entry { metrics, slot: None };
+            self.entries.insert(key, entry);
+            return (metrics, self.image(entry));
+        }
+
+        let slot = match self.allocate(width, height) {
+            Some(slot) => slot,
+            None => {
+                self.oversized = Some((key, metrics, bytes));
+                return (metrics, self.oversized_image(metrics));
+            }
+        };
+
+        let y = self.shelves[slot.0].y;
+        for row in 0..height {
+            let start = (y + row) * self.size + slot.1;
+            self.pixels[start..start + width].copy_from_slice(&bytes[row * width..(row + 1) * width]);
+        }
+
+        let entry = Entry { metrics, slot: Some(slot) };
+        self.entries.insert(key, entry);
+        (metrics, self.image(entry))
+    }
+
+    fn image(&self, entry: Entry) -> GlyphImage<'_> {
+        let Metrics { width, height, .. } = entry.metrics;
+
+        match entry.slot {
+            Some((shelf, x)) => {
+                let start = self.shelves[shelf].y * self.size + x;
+                let end = start + (height - 1) * self.size + width;
+                GlyphImage { bytes: &self.pixels[start..end], width, height, stride: self.size }
+            },
+            None => GlyphImage { bytes: &[], width, height: 0, stride: width }
+        }
+    }
+
+    fn oversized_image(&self, metrics: Metrics) -> GlyphImage<'_> {
+        let bytes = self.oversized.as_ref().map_or(&[][..], |(_, _, b)| b.as_slice());
+        GlyphImage { bytes, width: metrics.width, height: metrics.height, stride: metrics.width }
+    }
+
+    // Finds room for a glyph, as its shelf and x. Glyphs go on the flattest
+    // shelf they fit on that isn't much taller than them, or else on a new
+    // shelf. With the atlas full, the least recently used shelf that is tall
+    // enough gets emptied, and if none is, so does the whole atlas.
+    fn allocate(&mut self, width: usize, height: usize) -> Option<(usize, usize)> {
+        if width > self.size || height > self.size {
+            return None;
+        }
+
+        let fits = |s: &Shelf| s.height >= height && s.height <= height + height / 4 + 1 && self.size - s.used >= width;
+        let shelf = self.shelves.iter().enumerate()
+            .filter(|(_, s)| fits(s))
+            .min_by_key(|(_, s)| s.height)
+            .map(|(i, _)| i);
+
+        let shelf = match shelf {
+            Some(i) => i,
+            None => {
+                let top = self.shelves.last().map_or(0, |s| s.y + s.height);
+                if top + height <= self.size {
+                    self.shelves.push(Shelf { y: top, height, used: 0, last_used: 0 });
+                    self.shelves.len() - 1
+                } else {
+                    let oldest = self.shelves.iter().enumerate()
+                        .filter(|(_, s)| s.height >= height)
+                        .min_by_key(|(_, s)| s.last_used)
+                        .map(|(i, _)| i);
+
+                    match oldest {
+                        Some(i) => {
+                            self.evict(i);
+                            i
+                        },
+                        None => {
+                            self.clear();
+                            self.shelves.push(Shelf { y: 0, height, used: 0, last_used: 0 });
+                            0
+                        }
+                    }
+                }
+            }
+        };
+
+        let shelf_ref = &mut self.shelves[shelf];
+        let x = shelf_ref.used;
+        shelf_ref.used += width;
+        shelf_ref.last_used = self.clock;
+        Some((shelf, x))
+    }
+
+    fn evict(&mut self, shelf: usize) {
+        let before = self.entries.len();
+        self.entries.retain(|_, e| !matches!(e.slot, Some((s, _)) if s == shelf));
+        self.stats.evictions += (before - self.entries.len()) as u64;
+        self.shelves[shelf].used = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(glyph_index: u16, px: f32) -> GlyphRasterConfig {
+        GlyphRasterConfig { glyph_index, px, font_hash: 0 }
+    }
+
+    // A square glyph whose every pixel is its index.
+    fn glyph(index: u16, side: usize) -> impl FnOnce() -> (Metrics, Vec<u8>) {
+        move || (Metrics { width: side, height: side, ..Default::default() }, vec![index as u8; side * side])
+    }
+
+    fn pixels(image: &GlyphImage) -> Vec<u8> {
+        (0..image.height)
+            .flat_map(|row| image.bytes[row * image.stride..row * image.stride + image.width].to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn rasterizes_once() {
+        let mut cache = GlyphCache::new(64);
+        let mut rasterized = 0;
+
+        for _ in 0..3 {
+            let (_, image) = cache.get_or_insert_with(key(1, 20.0), || {
+                rasterized += 1;
+                glyph(1, 8)()
+            });
+            assert_eq!(pixels(&image), vec![1; 64]);
+        }
+
+        assert_eq!(rasterized, 1);
+        assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 1, evictions: 0 });
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        // Room for two shelves of 8 pixel glyphs, two to a shelf.
+        let mut cache = GlyphCache::new(16);
+        for i in 0..4 {
+            cache.get_or_insert_with(key(i, 20.0), glyph(i, 8));
+        }
+        assert_eq!(cache.len(), 4);
+
+        // Glyphs 0 and 1 share the first shelf, which is now the most recent.
+        cache.get_or_insert_with(key(0, 20.0), glyph(0, 8));
+        cache.get_or_insert_with(key(4, 20.0), glyph(4, 8));
+
+        assert!(cache.contains(&key(0, 20.0)) && cache.contains(&key(1, 20.0)));
+        assert!(!cache.contains(&key(2, 20.0)) && !cache.contains(&key(3, 20.0)));
+        assert_eq!(cache.stats().evictions, 2);
+
+        // The new glyph was drawn over the evicted one, and the others are intact.
+        let (_, image) = cache.get_or_insert_with(key(4, 20.0), glyph(4, 8));
+        assert_eq!(pixels(&image), vec![4; 64]);
+        let (_, image) = cache.get_or_insert_with(key(1, 20.0), glyph(1, 8));
+        assert_eq!(pixels(&image), vec![1; 64]);
+    }
+
+    #[test]
+    fn stays_bounded_across_scales() {
+        let mut cache = GlyphCache::new(32);
+
+        for px in 10..60 {
+            for i in 0..20 {
+                let side = px as usize / 4;
+                let (metrics, image) = cache.get_or_insert_with(key(i, px as f32), glyph(i, side));
+                assert_eq!(metrics.width, side);
+                assert_eq!(pixels(&image), vec![i as u8; side * side]);
+            }
+        }
+        assert!(cache.len() <= 32 * 32 / 4);
+
+        // Too big to pack, but still drawn.
+        let (_, image) = cache.get_or_insert_with(key(0, 400.0), glyph(3, 40));
+        assert_eq!(pixels(&image), vec![3; 1600]);
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -99,6 +99,11 @@ pub trait ColorRect<C: Into<R>, R = u32> {
     fn get_bytes(&self) -> &[C];
     fn get_width(&self) -> usize;
     fn get_height(&self) -> usize;
+    // How far apart rows are in the bytes, for rectangles cut out of a
+    // bigger image.
+    fn get_stride(&self) -> usize {
+        self.get_width()
+    }
 }
 
 pub struct Image {
@@ -5,6 +5,9 @@ pub mod types;
 pub mod image;
 pub mod font;
 pub mod text_render;
+pub mod frame;
+pub mod shaping;
+pub mod glyph_cache;
 
 pub use types::{Point, Rgba};
 pub use render::render;
@@ -5,7 +5,23 @@ use winit::window::Window;
 
 use crate::{state::application::State};
 
-use super::{types::Pixel, Rgba, text_render::{Canvas}};
+use super::{types::Pixel, Rgba, text_render::{Canvas}, frame::PixelSink};
+
+// The window's softbuffer, which doesn't know its own size.
+struct WindowBuffer<'a> {
+    pixels: &'a mut [u32],
+    size: Pixel
+}
+
+impl PixelSink for WindowBuffer<'_> {
+    fn size(&self) -> (usize, usize) {
+        (self.size.x as usize, self.size.y as usize)
+    }
+
+    fn pixels(&mut self) -> &mut [u32] {
+        self.pixels
+    }
+}
 
 
 // IF THE WINDOW BORDERS RESIZE FASTER THAN THE WINDOW ITSELF THEN IT'S BECAUSE
@@ -17,10 +33,9 @@ pub fn render(mut buffer: Buffer<&Window, &Window>, window_size: Pixel, state: &
 
     buffer.fill(Rgba::DARK_GRAY.into());
 
-    let mut canvas = Canvas::new(buffer, window_size.x as usize, window_size.y as usize);
-    use crate::buffer::stage::Render;
-    state.stage.render(&mut canvas, &mut state.font_manager);
+    let mut sink = WindowBuffer { pixels: &mut buffer, size: window_size };
+    state.render(&mut Canvas::new(&mut sink));
 
-    canvas.destroy().present().unwrap();
+    buffer.present().unwrap();
 }
 
@@ -0,0 +1,144 @@
+use fontdue::{layout::GlyphPosition, Font};
+
+use super::font::FontManager;
+
+// Replaces laid out glyphs with the ones OpenType shaping picks, so that
+// ligatures like FiraCode's `->` show up. Every char keeps a glyph and its
+// position, so whatever maps chars to glyphs, like the cursor, still works:
+// the glyphs of a ligature go to its first chars, and the rest are blank.
+//
+// Runs split at line breaks, and where the font, size, or whether
+// `shaped(user_data)` holds changes.
+pub fn shape<U: Copy>(fonts: &FontManager, glyphs: &mut [GlyphPosition<U>], shaped: impl Fn(&U) -> bool) {
+    let mut start = 0;
+
+    while start < glyphs.len() {
+        let first = glyphs[start];
+        let end = glyphs[start..].iter()
+            .position(|g| g.parent == '\n'
+                || g.font_index != first.font_index
+                || g.key.px != first.key.px
+                || shaped(&g.user_data) != shaped(&first.user_data))
+            .map_or(glyphs.len(), |len| start + len);
+
+        // A line break is a run of its own, and never shaped.
+        if end == start {
+            start += 1;
+            continue;
+        }
+
+        if shaped(&first.user_data) {
+            shape_run(fonts, &mut glyphs[start..end]);
+        }
+        start = end;
+    }
+}
+
+#[cfg(feature = "shaping")]
+fn shape_run<U: Copy>(fonts: &FontManager, run: &mut [GlyphPosition<U>]) {
+    let font_index = run[0].font_index;
+    let font = &fonts.fonts[font_index];
+    let face = match rustybuzz::Face::from_slice(&fonts.data[font_index], 0) {
+        Some(f) => f,
+        None => return
+    };
+
+    let text: String = run.iter().map(|g| g.parent).collect();
+    let offsets: Vec<usize> = text.char_indices().map(|(b, _)| b).collect();
+    let char_at = |byte: u32| offsets.binary_search(&(byte as usize)).ok();
+
+    let mut buffer = rustybuzz::UnicodeBuffer::new();
+    buffer.push_str(&text);
+    buffer.guess_segment_properties();
+    let output = rustybuzz::shape(&face, &[], buffer);
+
+    let mut clusters: Vec<u32> = output.glyph_infos().iter().map(|i| i.cluster).collect();
+    clusters.sort_unstable();
+    clusters.dedup();
+
+    // The glyphs of a cluster go to its chars in order. Chars left over are
+    // covered by a ligature, and glyphs left over are dropped.
+    let mut replacements: Vec<Option<(u16, i32, i32)>> = vec![None; run.len()];
+    let mut nth = 0;
+    let mut previous = None;
+
+    for (info, position) in output.glyph_infos().iter().zip(output.glyph_positions()) {
+        nth = if previous == Some(info.cluster) { nth + 1 } else { 0 };
+        previous = Some(info.cluster);
+
+        let start = match char_at(info.cluster) {
+            Some(i) => i,
+            None => continue
+        };
+        let end = clusters.iter().find(|c| **c > info.cluster).and_then(|c| char_at(*c)).unwrap_or(run.len());
+
+        if start + nth < end {
+            replacements[start + nth] = Some((info.glyph_id as u16, position.x_offset, position.y_offset));
+        }
+    }
+
+    let scale = run[0].key.px / face.units_per_em() as f32;
+    let blank = font.lookup_glyph_index(' ');
+
+    for (glyph, replacement) in run.iter_mut().zip(replacements) {
+        if glyph.char_data.is_control() {
+            continue;
+        }
+
+        let (id, dx, dy) = replacement.unwrap_or((blank, 0, 0));
+        if (id, dx, dy) != (glyph.key.glyph_index, 0, 0) {
+            replace_glyph(font, glyph, id, dx as f32 * scale, dy as f32 * scale);
+        }
+    }
+}
+
+#[cfg(not(feature = "shaping"))]
+fn shape_run<U: Copy>(_fonts: &FontManager, _run: &mut [GlyphPosition<U>]) {}
+
+// Swaps a glyph for another at the same pen position and baseline, which are
+// found by undoing fontdue's placement of the old one.
+#[cfg_attr(not(feature = "shaping"), allow(dead_code))]
+fn replace_glyph<U: Copy>(font: &Font, glyph: &mut GlyphPosition<U>, id: u16, dx: f32, dy: f32) {
+    let px = glyph.key.px;
+    let old = font.metrics_indexed(glyph.key.glyph_index, px);
+    let new = font.metrics_indexed(id, px);
+
+    let pen = glyph.x - old.bounds.xmin.floor();
+    let baseline = glyph.y - (-old.bounds.height - old.bounds.ymin).floor();
+
+    glyph.key.glyph_index = id;
+    glyph.x = (pen + new.bounds.xmin + dx).floor();
+    glyph.y = baseline + (-new.bounds.height - new.bounds.ymin - dy).floor();
+    glyph.width = new.width;
+    glyph.height = new.height;
+}
+
+#[cfg(all(test, feature = "shaping"))]
+mod test {
+    use crate::{buffer::stage::{layout, Span}, display::font::{FontManager, Face}};
+
+    #[test]
+    fn ligatures_keep_a_glyph_per_char() {
+        let fonts = FontManager::new().unwrap();
+        let text = "a -> b != c";
+        let plain = Face { ligatures: false, ..fonts.default_face() };
+
+        let shaped = layout(text, &[], &fonts);
+        let unshaped = layout(text, &[Span { range: 0..11, face: plain }], &fonts);
+        let (shaped, unshaped) = (shaped.glyphs(), unshaped.glyphs());
+
+        assert_eq!(shaped.len(), text.chars().count());
+        assert_eq!(unshaped.len(), text.chars().count());
+
+        let id = |glyphs: &[fontdue::layout::GlyphPosition<Face>], i: usize| glyphs[i].key.glyph_index;
+
+        // FiraCode draws `->` and `!=` with other glyphs, and leaves letters
+        // where they were.
+        assert_ne!((id(shaped, 2), id(shaped, 3)), (id(unshaped, 2), id(unshaped, 3)));
+        assert_ne!((id(shaped, 7), id(shaped, 8)), (id(unshaped, 7), id(unshaped, 8)));
+        for i in [0, 5, 10] {
+            assert_eq!(id(shaped, i), id(unshaped, i));
+            assert_eq!(shaped[i].x, unshaped[i].x);
+        }
+    }
+}
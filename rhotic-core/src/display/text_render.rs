@@ -1,13 +1,18 @@
-use softbuffer::{Buffer};
-use winit::{raw_window_handle::{HasDisplayHandle, HasWindowHandle}};
-
-
-
-use super::{image::ColorRect, Rgba};
-
-
-pub struct Canvas<'a, D, W> {
-    buffer: Buffer<'a, D, W>,
+use super::{image::ColorRect, frame::PixelSink, Rgba};
+
+
+// A drawing surface over a pixel buffer. A canvas can hand out viewports,
+// child canvases over a rectangle of it, which take coordinates relative to
+// their own corner and clip everything to it. Viewports nest, so a pane, and
+// a gutter inside that pane, can each draw as if they were the whole window.
+pub struct Canvas<'a> {
+    buffer: &'a mut [u32],
+    stride: usize,
+    // Where the canvas' (0, 0) is in the buffer. It can be outside the buffer
+    // when a viewport hangs off the edge of its parent.
+    origin: (isize, isize),
+    // The part of the buffer that may be drawn to, as (left, top, right, bottom).
+    clip: (isize, isize, isize, isize),
     width: usize,
     height: usize
 }
@@ -19,18 +24,57 @@ pub enum ImageCompletion {
     Complete
 }
 
-impl<'a, D: HasDisplayHandle, W: HasWindowHandle> Canvas<'a, D, W> {
+impl<'a> Canvas<'a> {
+
+    pub fn new<S: PixelSink + ?Sized>(sink: &'a mut S) -> Self {
+        let (width, height) = sink.size();
+        let buffer = sink.pixels();
+        let height = height.min(buffer.len() / width.max(1));
 
-    pub fn new(buffer: Buffer<'a, D, W>, width: usize, height: usize) -> Self {
         Self {
             buffer,
+            stride: width,
+            origin: (0, 0),
+            clip: (0, 0, width as isize, height as isize),
+            width,
+            height
+        }
+    }
+
+    // A child canvas over the rectangle at (x, y) of this one. Its drawing is
+    // clipped to both the rectangle and this canvas' own clip.
+    pub fn viewport(&mut self, x: isize, y: isize, width: usize, height: usize) -> Canvas<'_> {
+        let origin = (self.origin.0 + x, self.origin.1 + y);
+
+        let clip = (
+            self.clip.0.max(origin.0),
+            self.clip.1.max(origin.1),
+            self.clip.2.min(origin.0 + width as isize),
+            self.clip.3.min(origin.1 + height as isize)
+        );
+
+        Canvas {
+            buffer: self.buffer,
+            stride: self.stride,
+            origin,
+            clip,
             width,
             height
         }
     }
 
-    pub fn destroy(self) -> Buffer<'a, D, W> {
-        self.buffer
+    pub fn fill(&mut self, color: Rgba) {
+        self.draw_rectangle(0, 0, self.width, self.height, color);
+    }
+
+    // Index in the buffer of a point of the canvas, if it isn't clipped.
+    fn index(&self, x: isize, y: isize) -> Option<usize> {
+        let (bx, by) = (self.origin.0 + x, self.origin.1 + y);
+
+        if bx < self.clip.0 || by < self.clip.1 || bx >= self.clip.2 || by >= self.clip.3 {
+            return None;
+        }
+        Some(by as usize * self.stride + bx as usize)
     }
 
     pub fn width(&self) -> usize {
@@ -49,10 +93,8 @@ pub fn draw_image<R: ColorRect<Rgba>>(&mut self, x: isize, y: isize, image: &R)
 
     for counter in 0..image.get_bytes().len() {
 
-        let (nx, ny) = (gx as usize, gy as usize);
-
-        if nx < self.width && ny < self.height && gx >= 0 && gy >= 0 {
-            self.buffer[ny * self.width + nx] = bytes[counter].into();
+        if let Some(i) = self.index(gx, gy) {
+            self.buffer[i] = bytes[counter].into();
         }
 
         if gx == image.get_width() as isize + x - 1 {
@@ -79,42 +121,31 @@ pub fn draw_monochrome_image<R: ColorRect<u8, u8>, C: Into<u32>>
         white: Rgba
     ) -> ImageCompletion {
 
-        if self.width < x as usize || self.height < y as usize  {
+        if x >= self.width as isize || y >= self.height as isize {
             return ImageCompletion::None;
         }
 
         let mut comp = ImageCompletion::Complete;
 
         let bytes = image.get_bytes();
+        let stride = image.get_stride();
 
-        let mut gx = x;
-        let mut gy = y;
-
-        for counter in 0..image.get_bytes().len() {
-
-            if gx >= 0 && gy >= 0 {
-                let (nx, ny) = (gx as usize, gy as usize);
+        for row in 0..image.get_height() {
+            for column in 0..image.get_width() {
+                let (gx, gy) = (x + column as isize, y + row as isize);
 
-                if nx < self.width() && ny < self.height() {
-
-                    let color = match bytes[counter] {
+                if let Some(i) = self.index(gx, gy) {
+                    let color = match bytes[row * stride + column] {
                         0 => { black },
                         255 => {white},
                         b => { black.blend(white, b) }
                     };
 
-                    self.buffer[ny * self.width + nx] = color.into();
+                    self.buffer[i] = color.into();
                 } else {
                     comp = ImageCompletion::Partial;
                 }
             }
-
-            if gx == image.get_width() as isize + x - 1 {
-                gx = x;
-                gy += 1;
-            } else {
-                gx += 1;
-            }
         }
         comp
     }
@@ -125,8 +156,8 @@ pub fn draw_monochrome_image<R: ColorRect<u8, u8>, C: Into<u32>>
 
     for _ in 0..(rect_width * rect_height) {
 
-        if gx >= 0 && gy >= 0  && gx < self.width as isize && gy < self.height as isize {
-            self.buffer[gy as usize * self.width + gx as usize] = color.into();
+        if let Some(i) = self.index(gx, gy) {
+            self.buffer[i] = color.into();
         }
 
         if gx == rect_width as isize + x - 1 {
@@ -138,5 +169,55 @@ pub fn draw_monochrome_image<R: ColorRect<u8, u8>, C: Into<u32>>
     }
 }
 
+    // Draws a glyph slanted to the right, for oblique text without an oblique
+    // font. Rows shift right the higher they are, and only covered pixels are
+    // drawn, so the slant doesn't cut into the neighbouring glyphs.
+    pub fn draw_oblique_image<R: ColorRect<u8, u8>>(&mut self, x: isize, y: isize, image: &R, black: Rgba, white: Rgba) {
+        const SHEAR: f32 = 0.2;
+
+        let bytes = image.get_bytes();
+        let (width, height, stride) = (image.get_width(), image.get_height(), image.get_stride());
+
+        for row in 0..height {
+            let shift = ((height - row) as f32 * SHEAR) as isize;
+
+            for column in 0..width {
+                let byte = bytes[row * stride + column];
+                if byte == 0 {
+                    continue;
+                }
+
+                if let Some(i) = self.index(x + column as isize + shift, y + row as isize) {
+                    self.buffer[i] = black.blend(white, byte).into();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::display::frame::Frame;
 
+    #[test]
+    fn nested_viewports_clip() {
+        let mut frame = Frame::new(8, 4);
+        let mut canvas = Canvas::new(&mut frame);
+
+        {
+            let mut pane = canvas.viewport(2, 1, 4, 2);
+            assert_eq!((pane.width(), pane.height()), (4, 2));
+
+            // Hangs off the pane's right edge, so only its first column shows.
+            let mut inner = pane.viewport(3, -1, 3, 3);
+            inner.fill(Rgba::WHITE);
+            inner.draw_rectangle(-5, 0, 2, 1, Rgba::RED);
+        }
+
+        let white: u32 = Rgba::WHITE.into();
+        let lit: Vec<usize> = frame.pixels().iter().enumerate().filter(|(_, p)| **p != 0).map(|(i, _)| i).collect();
+        assert_eq!(lit, [8 + 5, 16 + 5]);
+        assert_eq!(frame.pixel(5, 1), Some(white));
+    }
 }
@@ -18,7 +18,7 @@ pub enum Color {
     Alpha = 3
 }
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
 pub struct Rgba {
     value: [u8; 4]
 }
@@ -165,6 +165,7 @@ impl TryFrom<Value> for Rgba {
     }
 }
 
+#[derive(Debug)]
 pub enum TomlToRgbaError {
     InsufficientStrLen(usize),
     InvalidStr(String),
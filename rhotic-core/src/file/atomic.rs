@@ -0,0 +1,81 @@
+use std::{fs::{self, File}, io::Write, path::Path};
+
+use anyhow::bail;
+
+// Writes `bytes` to a temporary file next to `path`, then renames it over
+// `path`, so a crash mid-write never leaves a half written file behind.
+// Symlinks are written through, to the file they point to.
+pub fn atomic_write(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+
+    let path = &match fs::canonicalize(path) {
+        Ok(path) => path,
+        // A link to a file that doesn't exist yet creates it.
+        Err(_) => match fs::read_link(path) {
+            Ok(target) => path.parent().unwrap_or(Path::new("")).join(target),
+            Err(_) => path.to_path_buf()
+        }
+    };
+
+    let file_name = match path.file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => bail!("Cannot write to {}; it is not a file path.", path.display())
+    };
+
+    let temp = path.with_file_name(format!(".{file_name}.rhotic-tmp"));
+
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&temp, metadata.permissions())?;
+
+            // Keep the owner and group too. Only root may give a file to
+            // another user, so for anyone else that part quietly fails.
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                let _ = std::os::unix::fs::chown(&temp, Some(metadata.uid()), Some(metadata.gid()));
+            }
+        }
+
+        fs::rename(&temp, path)
+    })();
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn writes_through_symlinks() {
+        let dir = std::env::temp_dir().join("rhotic_writes_through_symlinks");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let (file, link) = (dir.join("file"), dir.join("link"));
+        fs::write(&file, "old").unwrap();
+        std::os::unix::fs::symlink("file", &link).unwrap();
+
+        atomic_write(&link, b"new").unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+
+        // A dangling link creates the file it points to.
+        let (missing, dangling) = (dir.join("missing"), dir.join("dangling"));
+        std::os::unix::fs::symlink("missing", &dangling).unwrap();
+        atomic_write(&dangling, b"made").unwrap();
+        assert_eq!(fs::read_to_string(&missing).unwrap(), "made");
+        assert!(fs::symlink_metadata(&dangling).unwrap().file_type().is_symlink());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
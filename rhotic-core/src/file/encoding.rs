@@ -0,0 +1,351 @@
+use std::{error::Error, fmt::Display, str::FromStr};
+
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Latin1
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    Cr
+}
+
+// How a file was stored on disk, so it can be written back the same way.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct TextFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    pub trailing_newline: bool,
+    // Whether UTF-16 text starts with a byte order mark. Other encodings
+    // ignore it; UTF-8 has its own `Utf8Bom`.
+    pub bom: bool,
+    // The file mixed line endings, which saving makes all `line_ending`.
+    pub mixed_line_endings: bool
+}
+
+impl Default for TextFormat {
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Lf,
+            trailing_newline: true,
+            bom: true,
+            mixed_line_endings: false
+        }
+    }
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r"
+        }
+    }
+}
+
+// Decodes a file into text with '\n' line breaks and no final line break,
+// along with the format it was stored in.
+pub fn decode(bytes: &[u8]) -> (String, TextFormat) {
+
+    let (encoding, body) = detect_encoding(bytes);
+    let bom = body.len() != bytes.len();
+
+    let text = match encoding {
+        Encoding::Utf8 | Encoding::Utf8Bom => String::from_utf8_lossy(body).into_owned(),
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            let units: Vec<u16> = body.chunks(2).map(|pair| {
+                let pair = [pair[0], *pair.get(1).unwrap_or(&0)];
+                if encoding == Encoding::Utf16Le {
+                    u16::from_le_bytes(pair)
+                } else {
+                    u16::from_be_bytes(pair)
+                }
+            }).collect();
+            String::from_utf16_lossy(&units)
+        },
+        Encoding::Latin1 => body.iter().map(|b| *b as char).collect()
+    };
+
+    let (line_ending, mixed_line_endings) = detect_line_ending(&text);
+
+    let mut text = if text.contains('\r') {
+        text.replace("\r\n", "\n").replace('\r', "\n")
+    } else {
+        text
+    };
+
+    let trailing_newline = text.ends_with('\n');
+    if trailing_newline {
+        text.pop();
+    }
+
+    // Text in other encodings gets a BOM if it's converted to UTF-16.
+    let bom = bom || !matches!(encoding, Encoding::Utf16Le | Encoding::Utf16Be);
+
+    (text, TextFormat { encoding, line_ending, trailing_newline, bom, mixed_line_endings })
+}
+
+// The inverse of `decode`. Fails if the text has chars the encoding can't hold.
+pub fn encode(text: &str, format: &TextFormat) -> Result<Vec<u8>, EncodeError> {
+
+    let mut text = if format.line_ending == LineEnding::Lf {
+        text.to_string()
+    } else {
+        text.replace('\n', format.line_ending.as_str())
+    };
+
+    if format.trailing_newline {
+        text.push_str(format.line_ending.as_str());
+    }
+
+    Ok(match format.encoding {
+        Encoding::Utf8 => text.into_bytes(),
+        Encoding::Utf8Bom => {
+            let mut out = vec![0xEF, 0xBB, 0xBF];
+            out.extend_from_slice(text.as_bytes());
+            out
+        },
+        Encoding::Utf16Le => {
+            let mut out = if format.bom { vec![0xFF, 0xFE] } else { Vec::new() };
+            text.encode_utf16().for_each(|u| out.extend_from_slice(&u.to_le_bytes()));
+            out
+        },
+        Encoding::Utf16Be => {
+            let mut out = if format.bom { vec![0xFE, 0xFF] } else { Vec::new() };
+            text.encode_utf16().for_each(|u| out.extend_from_slice(&u.to_be_bytes()));
+            out
+        },
+        Encoding::Latin1 => {
+            let mut out = Vec::with_capacity(text.len());
+            for (line, l) in text.split(format.line_ending.as_str()).enumerate() {
+                if line != 0 {
+                    out.extend_from_slice(format.line_ending.as_str().as_bytes());
+                }
+                for c in l.chars() {
+                    match u8::try_from(c as u32) {
+                        Ok(b) => out.push(b),
+                        Err(_) => return Err(EncodeError::Unrepresentable { encoding: Encoding::Latin1, c, line: line + 1 })
+                    }
+                }
+            }
+            out
+        }
+    })
+}
+
+fn detect_encoding(bytes: &[u8]) -> (Encoding, &[u8]) {
+    if let Some(body) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        return (Encoding::Utf8Bom, body);
+    }
+    if let Some(body) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        return (Encoding::Utf16Le, body);
+    }
+    if let Some(body) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        return (Encoding::Utf16Be, body);
+    }
+
+    let utf8 = std::str::from_utf8(bytes).is_ok();
+    if utf8 && !bytes.contains(&0) {
+        return (Encoding::Utf8, bytes);
+    }
+
+    // UTF-16 without a BOM. Mostly ASCII text leaves every other byte zero.
+    if bytes.len().is_multiple_of(2) {
+        let pairs = bytes.len() / 2;
+        let even_zeros = bytes.iter().step_by(2).filter(|b| **b == 0).count();
+        let odd_zeros = bytes.iter().skip(1).step_by(2).filter(|b| **b == 0).count();
+
+        if odd_zeros * 2 > pairs && even_zeros * 8 < pairs {
+            return (Encoding::Utf16Le, bytes);
+        }
+        if even_zeros * 2 > pairs && odd_zeros * 8 < pairs {
+            return (Encoding::Utf16Be, bytes);
+        }
+    }
+
+    if utf8 {
+        (Encoding::Utf8, bytes)
+    } else {
+        (Encoding::Latin1, bytes)
+    }
+}
+
+// The most common line ending wins. Files without any are treated as LF.
+// Also returns whether there was more than one kind.
+fn detect_line_ending(text: &str) -> (LineEnding, bool) {
+    let (mut lf, mut crlf, mut cr) = (0, 0, 0);
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' if chars.peek() == Some(&'\n') => {
+                chars.next();
+                crlf += 1;
+            },
+            '\r' => cr += 1,
+            '\n' => lf += 1,
+            _ => {}
+        }
+    }
+
+    let ending = if crlf > lf && crlf >= cr {
+        LineEnding::CrLf
+    } else if cr > lf && cr > crlf {
+        LineEnding::Cr
+    } else {
+        LineEnding::Lf
+    };
+    let kinds = [lf, crlf, cr].iter().filter(|n| **n > 0).count();
+    (ending, kinds > 1)
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf8Bom => "utf-8-bom",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "latin-1"
+        })
+    }
+}
+
+impl Display for LineEnding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            LineEnding::Lf => "lf",
+            LineEnding::CrLf => "crlf",
+            LineEnding::Cr => "cr"
+        })
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = FormatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().replace('_', "-").as_str() {
+            "utf-8" | "utf8" => Encoding::Utf8,
+            "utf-8-bom" | "utf8-bom" => Encoding::Utf8Bom,
+            "utf-16le" | "utf-16-le" | "utf16le" => Encoding::Utf16Le,
+            "utf-16be" | "utf-16-be" | "utf16be" => Encoding::Utf16Be,
+            "latin-1" | "latin1" | "iso-8859-1" => Encoding::Latin1,
+            _ => return Err(FormatParseError::Encoding(s.to_string()))
+        })
+    }
+}
+
+impl FromStr for LineEnding {
+    type Err = FormatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "lf" | "unix" => LineEnding::Lf,
+            "crlf" | "dos" | "windows" => LineEnding::CrLf,
+            "cr" | "mac" => LineEnding::Cr,
+            _ => return Err(FormatParseError::LineEnding(s.to_string()))
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FormatParseError {
+    Encoding(String),
+    LineEnding(String)
+}
+
+impl Error for FormatParseError {}
+
+impl Display for FormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatParseError::Encoding(s) => {
+                write!(f, "\"{s}\" is not an encoding. Use utf-8, utf-8-bom, utf-16le, utf-16be or latin-1.")
+            },
+            FormatParseError::LineEnding(s) => {
+                write!(f, "\"{s}\" is not a line ending. Use lf, crlf or cr.")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum EncodeError {
+    Unrepresentable {
+        encoding: Encoding,
+        c: char,
+        line: usize
+    }
+}
+
+impl Error for EncodeError {}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncodeError::Unrepresentable { encoding, c, line } => {
+                write!(f, "Line {line} has '{c}', which can't be written as {encoding}. Convert the file to another encoding first.")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> TextFormat {
+        let (text, format) = decode(bytes);
+        assert_eq!(encode(&text, &format).unwrap(), bytes);
+        format
+    }
+
+    #[test]
+    fn utf8_line_endings() {
+        let format = round_trip(b"one\r\ntwo\r\n");
+        assert_eq!(format, TextFormat {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::CrLf,
+            trailing_newline: true,
+            bom: true,
+            mixed_line_endings: false
+        });
+
+        let format = round_trip(b"one\rtwo");
+        assert_eq!((format.line_ending, format.trailing_newline), (LineEnding::Cr, false));
+
+        let (text, format) = decode(b"a\r\nb\n");
+        assert_eq!(text, "a\nb");
+        assert!(format.mixed_line_endings);
+    }
+
+    #[test]
+    fn bom_and_utf16() {
+        assert_eq!(round_trip(b"\xEF\xBB\xBFhi\n").encoding, Encoding::Utf8Bom);
+        assert_eq!(round_trip(b"\xFF\xFEh\0i\0\n\0").encoding, Encoding::Utf16Le);
+        assert_eq!(round_trip(b"\xFE\xFF\0h\0i").encoding, Encoding::Utf16Be);
+        assert_eq!(decode(b"h\0i\0\n\0\n\0").0, "hi\n");
+
+        // Files without a BOM are written back without one.
+        assert!(!round_trip(b"h\0i\0\n\0").bom);
+        assert!(!round_trip(b"\0h\0i\0\n").bom);
+    }
+
+    #[test]
+    fn latin1() {
+        let format = round_trip(b"caf\xE9\n");
+        assert_eq!(format.encoding, Encoding::Latin1);
+        assert_eq!(decode(b"caf\xE9").0, "café");
+        assert!(encode("日本", &format).is_err());
+    }
+}
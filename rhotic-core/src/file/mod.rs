@@ -1 +1,5 @@
 pub mod toml;
+pub mod atomic;
+pub mod encoding;
+
+pub use atomic::atomic_write;
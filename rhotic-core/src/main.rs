@@ -5,6 +5,7 @@ pub mod state;
 pub mod buffer;
 pub mod file;
 pub mod dired;
+pub mod undotree;
 
 fn main() -> anyhow::Result<()> {
 
@@ -1,41 +1,642 @@
 
 
 use crate::dired::Dired;
-use crate::{buffer::stage::*, display::font::FontManager};
+use crate::{buffer::{stage::*, keybind::{SequenceMatcher, MatchResult}, command::{ArgSpec, ArgKind}}, display::{font::FontManager, text_render::Canvas, event_loop::Key, Rgba}};
+
+use super::{keymap::{Keymaps, GLOBAL_FUNCTIONS}, registry::StageRegistry, window::{WindowTree, Rect, Split, Direction}, minibuffer::{Minibuffer, Prompt, Completion, Submit}, status::StatusLine};
 
 
 use crate::{display::event_loop::Input};
 
+// How much of a split one resize command moves the divider by.
+const WINDOW_RESIZE_STEP: f32 = 0.05;
+// How much one zoom command scales text by.
+const ZOOM_STEP: f32 = 1.1;
+
 // A singeton that contains all data of the application.
 pub struct State {
     pub input: Input,
     pub is_focused: bool,
     pub font_manager: FontManager,
-    pub stage: Dired,
+    // Open stages, and the panes showing them.
+    pub stages: Vec<Box<dyn DynStage>>,
+    pub windows: WindowTree,
+    // The part of the window the panes were last laid out in.
+    pub area: Rect,
+    pub registry: StageRegistry,
+    pub keymaps: Keymaps,
+    pub minibuffer: Minibuffer,
+    pub status_line: StatusLine,
+    matcher: SequenceMatcher,
+    // What the edits came to when warning about unsaved changes, so asking
+    // again goes ahead only if nothing was edited since. For closing, also
+    // which stage it was.
+    exit_warned: Option<usize>,
+    close_warned: Option<(usize, usize)>,
+    // Stages opened by another with `OpenStage`, and the stage that did.
+    openers: Vec<(usize, usize)>,
+    // Set once the application was asked to close and allowed it.
+    pub should_exit: bool,
 }
 
 impl State {
 
     pub fn new() -> anyhow::Result<Self> {
+        let registry = StageRegistry::builtin();
+        let dired = registry.create(Dired::NAME, &["/home/james/.config"])?;
+        Self::with_stage(dired)
+    }
+
+    // The application with one pane, showing `stage`.
+    pub fn with_stage(stage: Box<dyn DynStage>) -> anyhow::Result<Self> {
+
+        let mut keymaps = Keymaps::default();
+        keymaps.configure(load_configuration::<Keymaps>()?)?;
+
+        let mut matcher = SequenceMatcher::default();
+        if let Some(timeout) = keymaps.sequence_timeout {
+            matcher.timeout = timeout;
+        }
+
+        let mut status_line = StatusLine::default();
+        status_line.configure(load_configuration::<StatusLine>()?)?;
 
         Ok(Self {
             is_focused: false,
             font_manager: FontManager::new()?,
             input: Input::default(),
-            stage: Stage::init(["/home/james/.config"].as_slice())?,
+            stages: vec![stage],
+            windows: WindowTree::new(0),
+            area: Rect::new(0, 0, 0, 0),
+            registry: StageRegistry::builtin(),
+            keymaps,
+            minibuffer: Minibuffer::default(),
+            status_line,
+            matcher,
+            exit_warned: None,
+            close_warned: None,
+            openers: Vec::new(),
+            should_exit: false,
         })
     }
 
-    pub fn send_event(&mut self, event: InputEvent) {
+    // Returns whether the application may close. With unsaved changes the
+    // first request only warns, and the second one goes through.
+    pub fn request_exit(&mut self) -> bool {
+        let edits = self.stages.iter().map(|s| s.edits()).sum();
+        if !self.stages.iter().any(|s| s.has_unsaved_changes()) || self.exit_warned == Some(edits) {
+            return true;
+        }
+
+        self.exit_warned = Some(edits);
+        self.minibuffer.show_message(String::from("There are unsaved changes! Close again to discard them."));
+        false
+    }
+
+    // The stage in the focused pane.
+    pub fn stage(&mut self) -> &mut dyn DynStage {
+        self.stages[self.windows.focused_stage()].as_mut()
+    }
+
+    // Draws each pane's stage clipped to its part of the canvas, with the
+    // dividers showing through between them, and the minibuffer below.
+    pub fn render(&mut self, canvas: &mut Canvas) {
+        let strip = Minibuffer::height(&self.font_manager).min(canvas.height());
+        self.area = Rect::new(0, 0, canvas.width(), canvas.height() - strip);
+        canvas.fill(Rgba::GRAY);
+
+        let mut view = canvas.viewport(0, self.area.height as isize, canvas.width(), strip);
+        view.fill(Rgba::BLACK);
+        self.minibuffer.render(&mut view, &mut self.font_manager);
+
+        for pane in self.windows.panes(self.area) {
+            let r = pane.rect;
+            let text = self.text_rect(r);
+
+            let mut view = canvas.viewport(r.x as isize, r.y as isize, r.width, text.height);
+            view.fill(Rgba::DARK_GRAY);
+            self.stages[pane.stage].render(&mut view, &mut self.font_manager);
+
+            let mut view = canvas.viewport(r.x as isize, (r.y + text.height) as isize, r.width, r.height - text.height);
+            let focused = pane.id == self.windows.focused();
+            self.status_line.render(&mut view, self.stages[pane.stage].as_ref(), focused, &mut self.font_manager);
+        }
+    }
+
+    // Keys are first resolved through the keymaps. A bound sequence reaches
+    // the stage as a command, and keys bound nowhere are passed on as is.
+    // Returns whether a keymap used the key, so its text shouldn't be typed.
+    pub fn send_event(&mut self, event: InputEvent) -> bool {
+
+        let mut consumed = true;
+
+        // Clicking a pane focuses it before the click is handled, and tells
+        // its stage where in the pane the click is. Clicks on a status line
+        // or the minibuffer don't reach a stage.
+        if let InputEvent::Press(Key::M1 | Key::M2 | Key::M3) = event {
+            let position = self.input.mouse_position;
+            let (x, y) = (position.x as usize, position.y as usize);
+            let pane = self.windows.panes(self.area).into_iter().find(|p| p.rect.contains(x, y));
+
+            if let Some(pane) = &pane {
+                self.windows.focus(pane.id);
+            }
+            if !pane.is_some_and(|p| self.text_rect(p.rect).contains(x, y)) {
+                return true;
+            }
+            self.send_mouse_position();
+        }
+
+        // Messages stay up until the next key.
+        if let InputEvent::Press(_) = event {
+            self.minibuffer.clear_message();
+        }
+
+        let active = self.minibuffer.is_active();
+
+        let command = match event {
+            InputEvent::Press(_) | InputEvent::Echo(_) | InputEvent::Release(_) => {
+                let stage = (!active).then(|| self.stage().name());
+                let mode = self.stage().mode();
+                let keymaps = &self.keymaps;
+
+                match self.matcher.feed(&event, |chords| keymaps.lookup(stage, mode, chords)) {
+                    MatchResult::Matched(function) => self.run_function(&function),
+                    MatchResult::Pending(_) => StateCommand::None,
+                    MatchResult::Cancelled(_) => StateCommand::Log(String::from("Cancelled.")),
+                    MatchResult::Unbound(s) if s.0.len() > 1 => StateCommand::Log(format!("{s} is undefined.")),
+                    MatchResult::Unbound(_) | MatchResult::Ignored => {
+                        consumed = false;
+                        if active {
+                            StateCommand::None
+                        } else {
+                            self.stage().send_event(event)
+                        }
+                    }
+                }
+            },
+            // While the minibuffer reads a line, typing goes there.
+            InputEvent::Text(text) if active => {
+                consumed = false;
+                self.minibuffer.insert(&text);
+                StateCommand::None
+            },
+            InputEvent::Preedit(..) if active => {
+                consumed = false;
+                StateCommand::None
+            },
+            // The wheel scrolls the pane under the mouse, focused or not.
+            InputEvent::Scroll(_) => {
+                consumed = false;
+                let position = self.input.mouse_position;
+                let stage = self.windows.panes(self.area).into_iter()
+                    .find(|p| p.rect.contains(position.x as usize, position.y as usize))
+                    .map_or(self.windows.focused_stage(), |p| p.stage);
+                self.stages[stage].send_event(event)
+            },
+            _ => {
+                consumed = false;
+                self.stage().send_event(event)
+            }
+        };
+
+        self.run_command(command);
+        consumed
+    }
+
+    // The part of a pane its stage draws in, above its status line.
+    fn text_rect(&self, pane: Rect) -> Rect {
+        let status = StatusLine::height(&self.font_manager).min(pane.height);
+        Rect::new(pane.x, pane.y, pane.width, pane.height - status)
+    }
+
+    // Sends the focused stage where the mouse is, from the top left of its
+    // pane. Positions left of or above the pane are at its edge.
+    pub fn send_mouse_position(&mut self) {
+        let position = self.input.mouse_position;
+        let focused = self.windows.focused();
+
+        if let Some(pane) = self.windows.panes(self.area).into_iter().find(|p| p.id == focused) {
+            let x = (position.x as usize).saturating_sub(pane.rect.x);
+            let y = (position.y as usize).saturating_sub(pane.rect.y);
+            self.stages[pane.stage].send_event(InputEvent::MouseMove(x, y));
+        }
+    }
+
+    // Sends typed text, dropping control characters other than line breaks
+    // and tabs. Keys held with control or alt are commands, not text.
+    pub fn send_text(&mut self, text: &str) {
+        let modifiers = self.matcher.modifiers();
+        if modifiers.control || modifiers.alt {
+            return;
+        }
+
+        let text: String = text.chars().filter(|c| !c.is_control() || matches!(c, '\r' | '\n' | '\t')).collect();
+        if !text.is_empty() {
+            self.send_event(InputEvent::Text(text.into()));
+        }
+    }
+
+    fn run_function(&mut self, function: &str) -> StateCommand {
+        match function {
+            "keyboard_quit" => {
+                self.matcher.cancel();
+                StateCommand::Log(String::from("Quit"))
+            },
+            "quit" => {
+                if self.request_exit() {
+                    self.should_exit = true;
+                }
+                StateCommand::None
+            },
+            "next_stage" => {
+                self.windows.set_stage((self.windows.focused_stage() + 1) % self.stages.len());
+                StateCommand::None
+            },
+            "close_stage" => self.close_stage(),
+            "split_horizontal" => {
+                self.windows.split(Split::Horizontal);
+                StateCommand::None
+            },
+            "split_vertical" => {
+                self.windows.split(Split::Vertical);
+                StateCommand::None
+            },
+            "close_window" => {
+                if self.windows.close() {
+                    StateCommand::None
+                } else {
+                    StateCommand::Log(String::from("Can't close the only window."))
+                }
+            },
+            "close_other_windows" => {
+                self.windows.close_others();
+                StateCommand::None
+            },
+            "other_window" => {
+                self.windows.focus_next();
+                StateCommand::None
+            },
+            "grow_window_width" => self.resize_window(Split::Horizontal, WINDOW_RESIZE_STEP),
+            "shrink_window_width" => self.resize_window(Split::Horizontal, -WINDOW_RESIZE_STEP),
+            "grow_window_height" => self.resize_window(Split::Vertical, WINDOW_RESIZE_STEP),
+            "shrink_window_height" => self.resize_window(Split::Vertical, -WINDOW_RESIZE_STEP),
+            "balance_windows" => {
+                self.windows.balance();
+                StateCommand::None
+            },
+            "focus_left" => self.focus_window(Direction::Left),
+            "focus_right" => self.focus_window(Direction::Right),
+            "focus_up" => self.focus_window(Direction::Up),
+            "focus_down" => self.focus_window(Direction::Down),
+            "zoom_in" | "zoom_out" => {
+                self.font_manager.zoom(if function == "zoom_in" { ZOOM_STEP } else { 1.0 / ZOOM_STEP });
+                StateCommand::Log(format!("Text size {}px", self.font_manager.scale))
+            },
+            "zoom_reset" => {
+                self.font_manager.reset_zoom();
+                StateCommand::None
+            },
+            "glyph_cache_stats" => {
+                let stats = self.font_manager.cache.stats();
+                StateCommand::Log(format!(
+                    "{} glyphs cached, {} hits, {} misses ({:.0}% hit), {} evicted",
+                    self.font_manager.cache.len(), stats.hits, stats.misses, stats.hit_rate() * 100.0, stats.evictions
+                ))
+            },
+            "execute_command" => {
+                let mut names: Vec<String> = GLOBAL_FUNCTIONS.iter().copied()
+                    .chain(self.stage().function_specs().into_iter().map(|(name, _)| name))
+                    .map(String::from)
+                    .collect();
+                names.sort();
+                names.dedup();
+
+                self.open_prompt(Prompt {
+                    label: String::from("M-x"),
+                    default: None,
+                    completion: Completion::List(names),
+                    submit: Submit::CommandLine
+                });
+                StateCommand::None
+            },
+            "minibuffer_submit" => {
+                self.keymaps.remove_overriding("minibuffer");
+                match self.minibuffer.submit() {
+                    Some((answer, Submit::CommandLine)) => {
+                        let line = self.split_line(&answer);
+                        self.run_line(line)
+                    },
+                    Some((answer, Submit::Args(line))) => {
+                        let mut line: Vec<&str> = line.iter().map(String::as_str).collect();
+                        line.push(&answer);
+                        self.run_line(line)
+                    },
+                    None => StateCommand::None
+                }
+            },
+            "minibuffer_cancel" => {
+                self.keymaps.remove_overriding("minibuffer");
+                self.minibuffer.close();
+                StateCommand::Log(String::from("Quit"))
+            },
+            "minibuffer_backspace" => {
+                self.minibuffer.backspace();
+                StateCommand::None
+            },
+            "minibuffer_left" | "minibuffer_right" => {
+                self.minibuffer.move_cursor(function == "minibuffer_right");
+                StateCommand::None
+            },
+            "minibuffer_complete" => {
+                self.minibuffer.complete();
+                StateCommand::None
+            },
+            "minibuffer_next" | "minibuffer_previous" => {
+                self.minibuffer.select(function == "minibuffer_next");
+                StateCommand::None
+            },
+            _ => self.stage().send_event(InputEvent::Command(&[function]))
+        }
+    }
+
+    fn run_command(&mut self, command: StateCommand) {
 
         use StateCommand::*;
 
-        match self.stage.send_event(event) {
-            StartStage(_) => {},
+        match command {
+            StartStage(name, args) => {
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+                match self.registry.create(&name, &args) {
+                    Ok(stage) => self.open_stage(stage),
+                    Err(e) => self.minibuffer.show_message(e.to_string())
+                }
+            },
+            OpenStage(stage) => {
+                self.openers.push((self.stages.len(), self.windows.focused_stage()));
+                self.open_stage(stage);
+            },
+            Return(line) => {
+                let index = self.windows.focused_stage();
+                match self.openers.iter().find(|(opened, _)| *opened == index) {
+                    Some(&(_, opener)) => {
+                        self.remove_stage(index, opener);
+                        let line: Vec<&str> = line.iter().map(String::as_str).collect();
+                        let command = self.run_line(line);
+                        self.run_command(command);
+                    },
+                    Option::None => self.minibuffer.show_message(String::from("The stage that opened this one is closed."))
+                }
+            },
+            Prompt(prompt) => self.open_prompt(prompt),
             None => {}
-            Log(s) => {
-                println!("{s}");
+            Log(s) => self.minibuffer.show_message(s)
+        }
+    }
+
+    // Shows a new stage in the focused pane.
+    fn open_stage(&mut self, mut stage: Box<dyn DynStage>) {
+        if let Some(message) = stage.take_message() {
+            self.minibuffer.show_message(message);
+        }
+        self.stages.push(stage);
+        self.windows.set_stage(self.stages.len() - 1);
+    }
+
+    fn open_prompt(&mut self, prompt: Prompt) {
+        self.matcher.cancel();
+        self.minibuffer.open(prompt);
+        self.keymaps.push_overlay("minibuffer");
+    }
+
+    // Runs a command line from the minibuffer. A lone global function runs
+    // here, and anything else goes to the focused stage. If the stage's
+    // function needs more arguments than were given, the next one is asked
+    // for first.
+    fn run_line(&mut self, args: Vec<&str>) -> StateCommand {
+        let (name, given) = match args.split_first() {
+            Some((name, _)) if args.len() == 1 && GLOBAL_FUNCTIONS.contains(name) => return self.run_function(name),
+            Some(split) => split,
+            None => return StateCommand::None
+        };
+
+        let missing = self.stage().function_specs().into_iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, specs)| specs.get(given.len()).filter(|spec| !spec.optional).copied());
+
+        match missing {
+            Some(spec) => {
+                self.open_prompt(argument_prompt(name, spec, &args));
+                StateCommand::None
+            },
+            None => self.stage().send_event(InputEvent::Command(&args))
+        }
+    }
+
+    // Splits a command line into words. A stage function whose last argument
+    // is a path or text takes the rest of the line as it, spaces and all.
+    fn split_line<'a>(&mut self, line: &'a str) -> Vec<&'a str> {
+        let name = line.split_whitespace().next().unwrap_or_default();
+        let words = self.stage().function_specs().into_iter()
+            .find(|(n, _)| *n == name)
+            .filter(|(_, specs)| matches!(specs.last(), Some(ArgSpec { kind: ArgKind::Path | ArgKind::Text, .. })))
+            .map_or(usize::MAX, |(_, specs)| specs.len());
+
+        let mut args = Vec::new();
+        let mut rest = line.trim();
+        while !rest.is_empty() {
+            if args.len() == words {
+                args.push(rest);
+                break;
             }
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            args.push(&rest[..end]);
+            rest = rest[end..].trim_start();
+        }
+        args
+    }
+
+    // Closes the focused stage, warning once first if it has unsaved changes.
+    // The last stage stays open.
+    fn close_stage(&mut self) -> StateCommand {
+        if self.stages.len() == 1 {
+            return StateCommand::Log(String::from("Can't close the only stage."));
         }
+
+        let index = self.windows.focused_stage();
+        let warned = Some((index, self.stage().edits()));
+        if self.stage().has_unsaved_changes() && self.close_warned != warned {
+            self.close_warned = warned;
+            return StateCommand::Log(String::from("This stage has unsaved changes! Close it again to discard them."));
+        }
+
+        self.close_warned = None;
+
+        // Panes go back to the stage that opened this one, if it did.
+        let replacement = self.openers.iter()
+            .find(|(opened, _)| *opened == index)
+            .map_or(index.checked_sub(1).unwrap_or(1), |(_, opener)| *opener);
+        self.remove_stage(index, replacement);
+        StateCommand::None
+    }
+
+    // Panes showing the stage switch to `replacement`, an index from before.
+    fn remove_stage(&mut self, index: usize, replacement: usize) {
+        self.stages.remove(index);
+        self.windows.remove_stage(index, replacement);
+
+        let shift = |i: usize| if i > index { i - 1 } else { i };
+        self.openers = self.openers.iter()
+            .filter(|(opened, opener)| *opened != index && *opener != index)
+            .map(|(opened, opener)| (shift(*opened), shift(*opener)))
+            .collect();
+    }
+
+    fn resize_window(&mut self, split: Split, delta: f32) -> StateCommand {
+        if self.windows.resize(split, delta) {
+            StateCommand::None
+        } else {
+            StateCommand::Log(String::from("There is no split to resize in that direction."))
+        }
+    }
+
+    fn focus_window(&mut self, direction: Direction) -> StateCommand {
+        self.windows.focus_direction(self.area, direction);
+        StateCommand::None
+    }
+}
+
+// Asks for one argument of a function, completing it by its kind.
+fn argument_prompt(function: &str, spec: ArgSpec, line: &[&str]) -> Prompt {
+    Prompt {
+        label: format!("{} for {function}", spec.name),
+        default: None,
+        completion: match spec.kind {
+            ArgKind::Path => Completion::Files,
+            ArgKind::Choice(choices) => Completion::List(choices.iter().map(|c| c.to_string()).collect()),
+            ArgKind::Bool => Completion::List(vec![String::from("true"), String::from("false")]),
+            ArgKind::Text | ArgKind::Integer => Completion::None
+        },
+        submit: Submit::Args(line.iter().map(|a| a.to_string()).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{buffer::textstage::TextEdit, display::{frame::Frame, Point}};
+
+    #[test]
+    fn altgr_types_text() {
+        let mut state = State::with_stage(Box::new(TextEdit::init(&[]).unwrap())).unwrap();
+        let position = |state: &mut State| state.stage().status_segment("position");
+
+        // Some platforms send AltGr with a control press, and it is still text.
+        state.send_event(InputEvent::Press(Key::AltGr));
+        state.send_event(InputEvent::Press(Key::Control));
+        state.send_text("@");
+        state.send_event(InputEvent::Release(Key::Control));
+        state.send_event(InputEvent::Release(Key::AltGr));
+        assert_eq!(position(&mut state).as_deref(), Some("1:2"));
+
+        // Control on its own makes a chord, not text.
+        state.send_event(InputEvent::Press(Key::Control));
+        state.send_text("q");
+        state.send_event(InputEvent::Release(Key::Control));
+        assert_eq!(position(&mut state).as_deref(), Some("1:2"));
+    }
+
+    #[test]
+    fn undo_tree_goes_to_a_state() {
+        let mut state = State::with_stage(Box::new(TextEdit::init(&[]).unwrap())).unwrap();
+        state.send_text("ab");
+
+        let command = state.run_function("undo_tree");
+        state.run_command(command);
+        assert_eq!(state.stage().name(), "Undo Tree");
+
+        // The oldest state is the empty page.
+        state.send_event(InputEvent::Press(Key::Arrowdown));
+        state.send_event(InputEvent::Press(Key::Enter));
+        assert_eq!(state.stages.len(), 1);
+        assert_eq!(state.stage().name(), TextEdit::NAME);
+        assert_eq!(state.stage().status_segment("position").as_deref(), Some("1:1"));
+        assert_eq!(state.stage().status_segment("modified"), None);
+    }
+
+    #[test]
+    fn warnings_reset_on_edits() {
+        let mut state = State::with_stage(Box::new(TextEdit::init(&[]).unwrap())).unwrap();
+        let position = |state: &mut State| state.stage().status_segment("position");
+        state.send_text("a");
+
+        // An edit after the warning needs another one.
+        assert!(!state.request_exit());
+        state.send_text("b");
+        assert!(!state.request_exit());
+        assert!(state.request_exit());
+
+        let path = std::env::temp_dir().join("rhotic_warnings_reset_on_edits.txt");
+        let open = ["open", path.to_str().unwrap()];
+        let command = state.run_line(open.to_vec());
+        state.run_command(command);
+        state.send_text("c");
+        let command = state.run_line(open.to_vec());
+        state.run_command(command);
+        assert_eq!(position(&mut state).as_deref(), Some("1:4"));
+
+        let command = state.run_line(open.to_vec());
+        state.run_command(command);
+        assert_eq!(position(&mut state).as_deref(), Some("1:1"));
+
+        state.open_stage(Box::new(TextEdit::init(&[]).unwrap()));
+        state.send_text("d");
+        state.close_stage();
+        state.send_text("e");
+        state.close_stage();
+        assert_eq!(state.stages.len(), 2);
+        state.close_stage();
+        assert_eq!(state.stages.len(), 1);
+    }
+
+    #[test]
+    fn paths_keep_their_spaces() {
+        let mut state = State::with_stage(Box::new(TextEdit::init(&[]).unwrap())).unwrap();
+        state.send_text("a");
+
+        let path = std::env::temp_dir().join("rhotic paths keep  their spaces.txt");
+        let line = format!("  save_as {} ", path.display());
+        let args = state.split_line(&line);
+        assert_eq!(args, ["save_as", path.to_str().unwrap()]);
+        let command = state.run_line(args);
+        state.run_command(command);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\n");
+        std::fs::remove_file(&path).unwrap();
+
+        // Other functions still split on every space.
+        assert_eq!(state.split_line("undo  tree x"), ["undo", "tree", "x"]);
+    }
+
+    #[test]
+    fn clicks_outside_the_text_stay_there() {
+        let mut state = State::with_stage(Box::new(TextEdit::init(&[]).unwrap())).unwrap();
+        state.send_text("ab\ncd");
+
+        let mut frame = Frame::new(300, 200);
+        state.render(&mut Canvas::new(&mut frame));
+
+        let click = |state: &mut State, x: u32, y: u32| {
+            state.input.mouse_position = Point::new(x, y);
+            state.send_event(InputEvent::Press(Key::M1));
+            state.send_event(InputEvent::Release(Key::M1));
+            state.stage().status_segment("position")
+        };
+
+        // The status line is at the bottom of the pane, then the minibuffer.
+        let status = state.area.height as u32 - 2;
+        assert_eq!(click(&mut state, 30, status).as_deref(), Some("2:3"));
+        assert_eq!(click(&mut state, 30, 198).as_deref(), Some("2:3"));
+        assert_eq!(click(&mut state, 150, 2).as_deref(), Some("1:3"));
     }
 }
@@ -0,0 +1,249 @@
+use std::{collections::HashMap, time::Duration};
+
+use toml::{Table, Value};
+
+use crate::buffer::{keybind::{parse_keybinds, KeyChord, Keymap, KeybindError, KeybindErrors, Lookup}, stage::{Stage, Configurable}};
+
+use super::registry::StageRegistry;
+
+// Functions the State handles itself, whatever stage is focused.
+pub const GLOBAL_FUNCTIONS: &[&str] = &[
+    "keyboard_quit", "quit", "next_stage", "close_stage",
+    "split_horizontal", "split_vertical", "close_window", "close_other_windows", "other_window",
+    "grow_window_width", "shrink_window_width", "grow_window_height", "shrink_window_height", "balance_windows",
+    "focus_left", "focus_right", "focus_up", "focus_down",
+    "zoom_in", "zoom_out", "zoom_reset", "glyph_cache_stats",
+    "execute_command"
+];
+
+// Keymaps that are only pushed over the others while something is active,
+// with the functions they can bind.
+pub const OVERLAYS: &[(&str, &[&str])] = &[
+    ("minibuffer", &[
+        "minibuffer_submit", "minibuffer_cancel", "minibuffer_backspace", "minibuffer_left", "minibuffer_right",
+        "minibuffer_complete", "minibuffer_next", "minibuffer_previous"
+    ])
+];
+
+// What a stage can have keys bound to.
+#[derive(Clone)]
+pub struct StageKeys {
+    pub name: &'static str,
+    pub functions: Vec<&'static str>,
+    pub modes: &'static [&'static str]
+}
+
+impl StageKeys {
+    pub fn of<T: Stage>() -> Self {
+        Self {
+            name: T::NAME,
+            functions: T::function_names(),
+            modes: T::MODES
+        }
+    }
+}
+
+// Keys are resolved through layers, from the first that binds them:
+// overriding maps (newest first), the focused stage's current mode, the
+// stage itself, and lastly the global keymap.
+#[derive(Default)]
+pub struct Keymaps {
+    global: Keymap<String>,
+    stages: HashMap<String, Keymap<String>>,
+    modes: HashMap<(String, String), Keymap<String>>,
+    overriding: Vec<(String, Keymap<String>)>,
+    overlays: HashMap<String, Keymap<String>>,
+    pub sequence_timeout: Option<Duration>
+}
+
+impl Keymaps {
+
+    // Reads a keymap file. `[global]` binds global functions, a table named
+    // after an overlay like `[minibuffer]` binds that overlay's, a table named
+    // after a `Stage::NAME` binds that stage's functions, and tables inside it
+    // named after a mode apply only in that mode:
+    //
+    //     ["Text Stage".command]
+    //     insert_mode = "i"
+    pub fn load(config: &Table, stages: &[StageKeys]) -> Result<Self, KeybindErrors> {
+        let mut out = Self::default();
+        let mut errors = Vec::new();
+
+        for (name, value) in config.iter() {
+            let table = match (name.as_str(), value) {
+                ("sequence_timeout", Value::Integer(ms)) => {
+                    out.sequence_timeout = Some(Duration::from_millis((*ms).max(0) as u64));
+                    continue;
+                },
+                (_, Value::Table(t)) => t,
+                _ => {
+                    errors.push(KeybindError::InvalidValue(name.clone()));
+                    continue;
+                }
+            };
+
+            if name == "global" {
+                out.global = parse_names(table, GLOBAL_FUNCTIONS, &mut errors);
+                continue;
+            }
+
+            if let Some((_, functions)) = OVERLAYS.iter().find(|(n, _)| n == name) {
+                out.overlays.insert(name.clone(), parse_names(table, functions, &mut errors));
+                continue;
+            }
+
+            let stage = match stages.iter().find(|s| s.name == name) {
+                Some(s) => s,
+                None => {
+                    errors.push(KeybindError::UnknownStage(name.clone()));
+                    continue;
+                }
+            };
+
+            let (modes, binds): (Table, Table) = table.clone().into_iter().partition(|(_, v)| v.is_table());
+            out.stages.insert(name.clone(), parse_names(&binds, &stage.functions, &mut errors));
+
+            for (mode, value) in modes {
+                match value {
+                    Value::Table(t) if stage.modes.contains(&mode.as_str()) => {
+                        let keymap = parse_names(&t, &stage.functions, &mut errors);
+                        out.modes.insert((name.clone(), mode), keymap);
+                    },
+                    _ => errors.push(KeybindError::UnknownMode { stage: name.clone(), mode })
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(out)
+        } else {
+            Err(KeybindErrors(errors))
+        }
+    }
+
+    // Without a stage, like while the minibuffer has the input, only the
+    // overriding and global layers are used.
+    pub fn lookup(&self, stage: Option<&str>, mode: Option<&str>, chords: &[KeyChord]) -> Lookup<String> {
+
+        let overriding = self.overriding.iter().rev().map(|(_, k)| k);
+        let mode = stage.zip(mode).and_then(|(s, m)| self.modes.get(&(s.to_string(), m.to_string())));
+        let stage = stage.and_then(|s| self.stages.get(s));
+
+        for keymap in overriding.chain(mode).chain(stage).chain(Some(&self.global)) {
+            match keymap.lookup(chords) {
+                Lookup::None => continue,
+                found => return found
+            }
+        }
+        Lookup::None
+    }
+
+    // Puts a keymap above every other layer until it is removed, like for the
+    // duration of a prompt.
+    pub fn push_overriding(&mut self, name: &str, keymap: Keymap<String>) {
+        self.remove_overriding(name);
+        self.overriding.push((name.to_string(), keymap));
+    }
+
+    pub fn remove_overriding(&mut self, name: &str) {
+        self.overriding.retain(|(n, _)| n != name);
+    }
+
+    // Pushes one of the `OVERLAYS` as an overriding keymap.
+    pub fn push_overlay(&mut self, name: &str) {
+        let keymap = self.overlays.get(name).cloned().unwrap_or_default();
+        self.push_overriding(name, keymap);
+    }
+}
+
+impl Configurable for Keymaps {
+    fn configure(&mut self, config: Table) -> anyhow::Result<()> {
+        *self = Self::load(&config, &StageRegistry::builtin().keys())?;
+        Ok(())
+    }
+
+    fn default_configuration() -> Table {
+        include_str!("../../config/keymap.toml").parse().unwrap_or_default()
+    }
+
+    const CONFIG_FILE_NAME: &'static str = "keymap.toml";
+}
+
+// Parses one layer, adding its errors to the rest so they are all reported at once.
+fn parse_names(table: &Table, functions: &[&str], errors: &mut Vec<KeybindError>) -> Keymap<String> {
+    match parse_keybinds(table, |name| functions.contains(&name).then(|| name.to_string())) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            errors.extend(e.0);
+            Keymap::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{buffer::textstage::TextEdit, dired::Dired};
+
+    fn chords(s: &str) -> Vec<KeyChord> {
+        s.parse::<crate::buffer::keybind::KeySequence>().unwrap().0
+    }
+
+    fn found(l: Lookup<String>) -> Option<String> {
+        match l {
+            Lookup::Found(f) => Some(f),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn layers_resolve_in_order() {
+        let config: Table = r#"
+            [global]
+            quit = "C-x C-c"
+
+            ["Text Stage"]
+            undo = "u"
+
+            ["Text Stage".command]
+            insert_mode = "i"
+            redo = "u"
+        "#.parse().unwrap();
+
+        let mut keymaps = Keymaps::load(&config, &StageRegistry::builtin().keys()).unwrap();
+        let text = TextEdit::NAME;
+
+        assert_eq!(found(keymaps.lookup(Some(text), Some("insert"), &chords("u"))).as_deref(), Some("undo"));
+        assert_eq!(found(keymaps.lookup(Some(text), Some("command"), &chords("u"))).as_deref(), Some("redo"));
+        assert!(found(keymaps.lookup(Some(text), Some("insert"), &chords("i"))).is_none());
+        assert!(matches!(keymaps.lookup(Some(Dired::NAME), None, &chords("C-x")), Lookup::Prefix));
+
+        let mut prompt = Keymap::default();
+        prompt.insert("u".parse().unwrap(), String::from("quit"));
+        keymaps.push_overriding("prompt", prompt);
+        assert_eq!(found(keymaps.lookup(Some(text), Some("command"), &chords("u"))).as_deref(), Some("quit"));
+
+        keymaps.remove_overriding("prompt");
+        assert_eq!(found(keymaps.lookup(Some(text), Some("command"), &chords("u"))).as_deref(), Some("redo"));
+    }
+
+    #[test]
+    fn unknown_names_are_reported() {
+        let config: Table = r#"
+            [Nowhere]
+            a = "a"
+
+            ["Text Stage".visual]
+            undo = "u"
+
+            ["Text Stage"]
+            fly = "f"
+        "#.parse().unwrap();
+
+        let errors = Keymaps::load(&config, &StageRegistry::builtin().keys()).err().unwrap();
+        assert_eq!(errors.0.len(), 3);
+
+        let default = Keymaps::load(&Keymaps::default_configuration(), &StageRegistry::builtin().keys());
+        assert!(default.is_ok());
+    }
+}
@@ -0,0 +1,353 @@
+use std::path::Path;
+
+use fontdue::layout::{Layout, LayoutSettings, TextStyle};
+
+use crate::{buffer::{stage::{Render, get_image}, command::fuzzy_score}, display::{font::FontManager, text_render::Canvas, glyph_cache::GlyphImage, Rgba}};
+
+// Where the candidates of a prompt come from.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Completion {
+    None,
+    // Fuzzy matched, best match first.
+    List(Vec<String>),
+    // Paths, listed from the directory typed so far.
+    Files
+}
+
+// What a prompt does with its answer.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Submit {
+    // The answer is a whole command line, split on whitespace.
+    CommandLine,
+    // The answer is the next argument of this partial command line.
+    Args(Vec<String>)
+}
+
+#[derive(Clone, Debug)]
+pub struct Prompt {
+    pub label: String,
+    pub default: Option<String>,
+    pub completion: Completion,
+    pub submit: Submit
+}
+
+// The strip at the bottom of the window. It shows messages, and reads a line
+// of input for prompts and commands.
+#[derive(Default)]
+pub struct Minibuffer {
+    prompt: Option<Prompt>,
+    input: String,
+    // In chars.
+    cursor: usize,
+    candidates: Vec<String>,
+    selected: Option<usize>,
+    message: Option<String>
+}
+
+impl Minibuffer {
+
+    pub fn is_active(&self) -> bool {
+        self.prompt.is_some()
+    }
+
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+
+    pub fn candidates(&self) -> &[String] {
+        &self.candidates
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    pub fn show_message(&mut self, message: String) {
+        self.message = Some(message);
+    }
+
+    pub fn clear_message(&mut self) {
+        self.message = None;
+    }
+
+    pub fn open(&mut self, prompt: Prompt) {
+        self.prompt = Some(prompt);
+        self.input.clear();
+        self.cursor = 0;
+        self.message = None;
+        self.update_candidates();
+    }
+
+    pub fn close(&mut self) {
+        self.prompt = None;
+        self.input.clear();
+        self.cursor = 0;
+        self.candidates.clear();
+        self.selected = None;
+    }
+
+    // Closes the prompt, returning the answer and what to do with it. The
+    // selected candidate wins over the input, and an empty input takes the
+    // default.
+    pub fn submit(&mut self) -> Option<(String, Submit)> {
+        let prompt = self.prompt.take()?;
+
+        let answer = match (self.selected.and_then(|i| self.candidates.get(i)), self.input.is_empty()) {
+            (Some(candidate), _) => candidate.clone(),
+            (None, true) => prompt.default.clone().unwrap_or_default(),
+            (None, false) => self.input.clone()
+        };
+
+        self.close();
+        Some((answer, prompt.submit))
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        let at = self.byte_index(self.cursor);
+        let text: String = text.chars().filter(|c| !c.is_control()).collect();
+
+        self.input.insert_str(at, &text);
+        self.cursor += text.chars().count();
+        self.update_candidates();
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor == 0 {
+            return;
+        }
+
+        self.cursor -= 1;
+        let at = self.byte_index(self.cursor);
+        self.input.remove(at);
+        self.update_candidates();
+    }
+
+    pub fn move_cursor(&mut self, right: bool) {
+        if right {
+            self.cursor = (self.cursor + 1).min(self.input.chars().count());
+        } else {
+            self.cursor = self.cursor.saturating_sub(1);
+        }
+    }
+
+    // Moves the selection through the candidates, wrapping around.
+    pub fn select(&mut self, forward: bool) {
+        let len = self.candidates.len();
+        if len == 0 {
+            return;
+        }
+
+        self.selected = Some(match (self.selected, forward) {
+            (None, true) => 0,
+            (None, false) => len - 1,
+            (Some(i), true) => (i + 1) % len,
+            (Some(i), false) => (i + len - 1) % len
+        });
+    }
+
+    // Takes the selected candidate, or else extends the input to the longest
+    // prefix all candidates share.
+    pub fn complete(&mut self) {
+        let completed = match self.selected.and_then(|i| self.candidates.get(i)) {
+            Some(candidate) => candidate.clone(),
+            None => match self.candidates.split_first() {
+                Some((first, rest)) => rest.iter().fold(first.clone(), |prefix, c| common_prefix(&prefix, c).to_string()),
+                None => return
+            }
+        };
+
+        if completed.len() > self.input.len() || self.selected.is_some() {
+            self.input = completed;
+            self.cursor = self.input.chars().count();
+            self.update_candidates();
+        }
+    }
+
+    fn update_candidates(&mut self) {
+        self.selected = None;
+
+        let completion = match &self.prompt {
+            Some(p) => &p.completion,
+            None => return
+        };
+
+        self.candidates = match completion {
+            Completion::None => Vec::new(),
+            Completion::List(list) if self.input.is_empty() => list.clone(),
+            Completion::List(list) => {
+                let mut scored: Vec<(i64, &String)> = list.iter()
+                    .filter_map(|c| Some((fuzzy_score(&self.input, c)?, c)))
+                    .collect();
+                scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+                scored.into_iter().map(|(_, c)| c.clone()).collect()
+            },
+            Completion::Files => file_candidates(&self.input)
+        };
+    }
+
+    fn byte_index(&self, chars: usize) -> usize {
+        self.input.char_indices().nth(chars).map_or(self.input.len(), |(i, _)| i)
+    }
+
+    fn label(&self) -> String {
+        match &self.prompt {
+            Some(Prompt { label, default: Some(d), .. }) => format!("{label} (default {d}): "),
+            Some(Prompt { label, .. }) => format!("{label}: "),
+            None => String::new()
+        }
+    }
+}
+
+fn common_prefix<'a>(a: &'a str, b: &str) -> &'a str {
+    let len = a.char_indices().zip(b.chars()).take_while(|((_, x), y)| x == y).last().map_or(0, |((i, c), _)| i + c.len_utf8());
+    &a[..len]
+}
+
+// Entries of the directory part of `input` whose names start with the rest.
+// Directories end with a '/' so completing one continues into it.
+fn file_candidates(input: &str) -> Vec<String> {
+    let (dir, name) = match input.rfind('/') {
+        Some(i) => (&input[..=i], &input[i + 1..]),
+        None => ("", input)
+    };
+
+    let entries = match Path::new(if dir.is_empty() { "." } else { dir }).read_dir() {
+        Ok(e) => e,
+        Err(_) => return Vec::new()
+    };
+
+    let mut out: Vec<String> = entries.filter_map(|e| {
+        let e = e.ok()?;
+        let file_name = e.file_name().into_string().ok()?;
+        if !file_name.starts_with(name) {
+            return None;
+        }
+
+        let slash = if e.path().is_dir() { "/" } else { "" };
+        Some(format!("{dir}{file_name}{slash}"))
+    }).collect();
+
+    out.sort();
+    out
+}
+
+const TEXT_COLOR: Rgba = Rgba::WHITE;
+const LABEL_COLOR: Rgba = Rgba::new_opaque(0x60, 0xAF, 0xFF);
+const CANDIDATE_COLOR: Rgba = Rgba::GRAY;
+const SELECTED_COLOR: Rgba = Rgba::new_opaque(0xFF, 0xD7, 0x00);
+
+impl Minibuffer {
+
+    // The height of the strip, one line of text.
+    pub fn height(fonts: &FontManager) -> usize {
+        fonts.line_height()
+    }
+}
+
+impl Render<&mut FontManager> for Minibuffer {
+    fn render(&mut self, canvas: &mut Canvas, v: &mut FontManager) {
+
+        let mut layout: Layout<Rgba> = Layout::new(fontdue::layout::CoordinateSystem::PositiveYDown);
+        layout.reset(&LayoutSettings { x: 4.0, ..Default::default() });
+
+        let mut append = |text: &str, color: Rgba| {
+            v.append(&mut layout, &TextStyle { text, px: v.scale, font_index: 0, user_data: color });
+        };
+
+        if !self.is_active() {
+            if let Some(message) = &self.message {
+                append(message, TEXT_COLOR);
+            }
+        } else {
+            let label = self.label();
+            append(&label, LABEL_COLOR);
+            append(&self.input, TEXT_COLOR);
+
+            if !self.candidates.is_empty() {
+                append("  {", CANDIDATE_COLOR);
+                for (i, candidate) in self.candidates.iter().enumerate() {
+                    if i != 0 {
+                        append(" | ", CANDIDATE_COLOR);
+                    }
+                    append(candidate, if Some(i) == self.selected { SELECTED_COLOR } else { CANDIDATE_COLOR });
+                }
+                append("}", CANDIDATE_COLOR);
+            }
+        }
+
+        // The cursor goes before the glyph at its index, or after the last one.
+        let cursor_glyph = self.label().chars().count() + self.cursor;
+        let mut cursor_x = 4.0;
+
+        for (i, glyph) in layout.glyphs().iter().enumerate() {
+            if i < cursor_glyph {
+                cursor_x = glyph.x + v.fonts[glyph.font_index].metrics_indexed(glyph.key.glyph_index, glyph.key.px).advance_width;
+            }
+
+            if !glyph.char_data.rasterize() {
+                continue;
+            }
+
+            let (_metrics, image) = get_image(glyph, v);
+            canvas.draw_monochrome_image::<GlyphImage, u8>(glyph.x as isize, glyph.y as isize, &image, Rgba::BLACK, glyph.user_data);
+        }
+
+        if self.is_active() {
+            canvas.draw_rectangle(cursor_x as isize, 0, 2, canvas.height(), LABEL_COLOR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn prompt(completion: Completion) -> Prompt {
+        Prompt { label: String::from("Pick"), default: Some(String::from("b")), completion, submit: Submit::CommandLine }
+    }
+
+    #[test]
+    fn input_and_defaults() {
+        let mut m = Minibuffer::default();
+        m.open(prompt(Completion::None));
+
+        m.insert("añc");
+        m.move_cursor(false);
+        m.backspace();
+        assert_eq!(m.input(), "ac");
+
+        m.close();
+        m.open(prompt(Completion::None));
+        assert_eq!(m.submit(), Some((String::from("b"), Submit::CommandLine)));
+        assert!(!m.is_active());
+    }
+
+    #[test]
+    fn completion() {
+        let list = ["save", "save_as", "set_encoding", "undo"].map(String::from).to_vec();
+
+        let mut m = Minibuffer::default();
+        m.open(prompt(Completion::List(list)));
+        assert_eq!(m.candidates().len(), 4);
+
+        m.insert("s");
+        m.complete();
+        assert_eq!(m.input(), "s");
+
+        m.insert("a");
+        m.complete();
+        assert_eq!(m.input(), "save");
+        assert_eq!(m.candidates(), ["save", "save_as"]);
+
+        m.select(false);
+        assert_eq!(m.submit().map(|(a, _)| a).as_deref(), Some("save_as"));
+
+        assert_eq!(common_prefix("añb", "añc"), "añ");
+
+        m.open(prompt(Completion::List(["move_left", "mode", "undo"].map(String::from).to_vec())));
+        m.insert("ml");
+        assert_eq!(m.candidates(), ["move_left"]);
+        m.complete();
+        assert_eq!(m.input(), "move_left");
+    }
+}
@@ -1 +1,6 @@
 pub mod application;
+pub mod keymap;
+pub mod registry;
+pub mod window;
+pub mod minibuffer;
+pub mod status;
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+
+use crate::{buffer::{stage::{Stage, DynStage, Render}, textstage::TextEdit}, display::font::FontManager, dired::Dired, undotree::UndoTreeView};
+
+use super::keymap::StageKeys;
+
+pub type StageConstructor = fn(&[&str]) -> anyhow::Result<Box<dyn DynStage>>;
+
+struct StageEntry {
+    keys: StageKeys,
+    init: StageConstructor
+}
+
+// Every kind of stage the State can start, by `Stage::NAME`.
+#[derive(Default)]
+pub struct StageRegistry {
+    entries: HashMap<&'static str, StageEntry>
+}
+
+impl StageRegistry {
+
+    pub fn builtin() -> Self {
+        let mut registry = Self::default();
+        registry.register::<TextEdit>();
+        registry.register::<Dired>();
+        registry.register::<UndoTreeView>();
+        registry
+    }
+
+    pub fn register<T>(&mut self) where T: Stage + for<'a> Render<&'a mut FontManager> + 'static {
+        self.entries.insert(T::NAME, StageEntry {
+            keys: StageKeys::of::<T>(),
+            init: |args| Ok(Box::new(T::init(args)?))
+        });
+    }
+
+    pub fn create(&self, name: &str, args: &[&str]) -> anyhow::Result<Box<dyn DynStage>> {
+        match self.entries.get(name) {
+            Some(entry) => (entry.init)(args),
+            None => Err(anyhow!("There is no stage called \"{name}\"."))
+        }
+    }
+
+    pub fn keys(&self) -> Vec<StageKeys> {
+        self.entries.values().map(|e| e.keys.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn create_by_name() {
+        let registry = StageRegistry::builtin();
+
+        let stage = registry.create(TextEdit::NAME, &[]).unwrap();
+        assert_eq!(stage.name(), TextEdit::NAME);
+        assert_eq!(stage.mode(), Some("insert"));
+
+        assert!(registry.create("Nowhere", &[]).is_err());
+        assert!(registry.create(Dired::NAME, &[]).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use fontdue::layout::{Layout, LayoutSettings};
+use toml::{Table, Value};
+
+use crate::{buffer::stage::{Configurable, DynStage, get_image}, display::{font::{FontManager, Face}, text_render::Canvas, glyph_cache::GlyphImage, Rgba}};
+
+// The line at the bottom of every pane, showing what its stage is doing.
+// Which segments it shows, and in which faces, comes from `status.toml`.
+pub struct StatusLine {
+    left: Vec<String>,
+    right: Vec<String>,
+    default_face: Face,
+    // Used for every segment of panes without focus.
+    inactive_face: Face,
+    faces: HashMap<String, Face>
+}
+
+impl Default for StatusLine {
+    fn default() -> Self {
+        Self {
+            left: ["name", "mode", "file", "modified"].map(String::from).to_vec(),
+            right: ["position", "encoding"].map(String::from).to_vec(),
+            default_face: Face { fore: Rgba::WHITE, back: Rgba::BLACK, ..Default::default() },
+            inactive_face: Face { fore: Rgba::GRAY, back: Rgba::BLACK, ..Default::default() },
+            faces: HashMap::new()
+        }
+    }
+}
+
+pub type Segment = (String, Face);
+
+// The text of a segment. The stage gets the first say, so it can rename or
+// hide any segment, then the name and mode fill in.
+pub fn segment_text(stage: &dyn DynStage, segment: &str) -> Option<String> {
+    stage.status_segment(segment).or_else(|| match segment {
+        "name" => Some(stage.name().to_string()),
+        "mode" => stage.mode().map(str::to_uppercase),
+        _ => None
+    })
+}
+
+impl StatusLine {
+
+    pub fn height(fonts: &FontManager) -> usize {
+        fonts.line_height()
+    }
+
+    // The shown segments with their text and face, for each side.
+    pub fn segments(&self, stage: &dyn DynStage, focused: bool) -> (Vec<Segment>, Vec<Segment>) {
+        let resolve = |names: &[String]| names.iter()
+            .filter_map(|name| {
+                let text = segment_text(stage, name)?;
+                let face = match (focused, self.faces.get(name)) {
+                    (false, _) => self.inactive_face,
+                    (true, Some(face)) => *face,
+                    (true, None) => self.default_face
+                };
+                Some((text, face))
+            })
+            .collect();
+
+        (resolve(&self.left), resolve(&self.right))
+    }
+
+    pub fn render(&self, canvas: &mut Canvas, stage: &dyn DynStage, focused: bool, v: &mut FontManager) {
+        let background = if focused { self.default_face.back } else { self.inactive_face.back };
+        canvas.fill(background);
+
+        let (left, right) = self.segments(stage, focused);
+
+        let mut x = 0.0;
+        for (text, face) in &left {
+            x += draw_segment(canvas, x, text, face, background, v);
+        }
+
+        // Right segments are measured first, then drawn against the edge.
+        let width: f32 = right.iter().map(|(text, face)| segment_layout(text, face, v).1).sum();
+        let mut x = canvas.width() as f32 - width;
+        for (text, face) in &right {
+            x += draw_segment(canvas, x, text, face, background, v);
+        }
+    }
+}
+
+// Lays out a segment padded by a space on each side, returning its width.
+fn segment_layout(text: &str, face: &Face, v: &FontManager) -> (Layout<Face>, f32) {
+    let mut layout = Layout::new(fontdue::layout::CoordinateSystem::PositiveYDown);
+    layout.reset(&LayoutSettings::default());
+    v.append(&mut layout, &face.to_style(&format!(" {text} "), v));
+
+    let width = layout.glyphs().last()
+        .map_or(0.0, |g| g.x + v.fonts[g.font_index].metrics_indexed(g.key.glyph_index, g.key.px).advance_width);
+    (layout, width)
+}
+
+fn draw_segment(canvas: &mut Canvas, x: f32, text: &str, face: &Face, background: Rgba, v: &mut FontManager) -> f32 {
+    let (layout, width) = segment_layout(text, face, v);
+
+    // A face without a background shows the line's.
+    let back = if face.back[3] == 0 { background } else { face.back };
+    canvas.draw_rectangle(x as isize, 0, width.ceil() as usize, canvas.height(), back);
+
+    for glyph in layout.glyphs() {
+        if !glyph.char_data.rasterize() {
+            continue;
+        }
+
+        let (_metrics, image) = get_image(glyph, v);
+        canvas.draw_monochrome_image::<GlyphImage, u8>((x + glyph.x) as isize, glyph.y as isize, &image, back, face.fore);
+    }
+    width
+}
+
+impl Configurable for StatusLine {
+    fn configure(&mut self, config: Table) -> anyhow::Result<()> {
+        let names = |key: &str| -> anyhow::Result<Option<Vec<String>>> {
+            match config.get(key) {
+                None => Ok(None),
+                Some(Value::Array(a)) => a.iter()
+                    .map(|v| v.as_str().map(String::from).ok_or_else(|| anyhow::anyhow!("Status segments in \"{key}\" must be strings.")))
+                    .collect::<anyhow::Result<_>>()
+                    .map(Some),
+                Some(_) => anyhow::bail!("\"{key}\" must be a list of status segments.")
+            }
+        };
+
+        if let Some(left) = names("left")? {
+            self.left = left;
+        }
+        if let Some(right) = names("right")? {
+            self.right = right;
+        }
+
+        if let Some(Value::Table(faces)) = config.get("faces") {
+            for (name, face) in faces {
+                let face = match face {
+                    Value::Table(t) => Face::try_from(t.clone())?,
+                    _ => anyhow::bail!("The face of \"{name}\" must be a table.")
+                };
+
+                match name.as_str() {
+                    "default" => self.default_face = face,
+                    "inactive" => self.inactive_face = face,
+                    _ => { self.faces.insert(name.clone(), face); }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn default_configuration() -> Table {
+        include_str!("../../config/status.toml").parse().unwrap_or_default()
+    }
+
+    const CONFIG_FILE_NAME: &'static str = "status.toml";
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer::{stage::{Stage, InputEvent}, textstage::TextEdit};
+
+    #[test]
+    fn segments_from_stage() {
+        let mut status = StatusLine::default();
+        status.configure(StatusLine::default_configuration()).unwrap();
+
+        let mut stage = TextEdit::init(&[]).unwrap();
+        Stage::send_event(&mut stage, InputEvent::Text("ab\ncd".into()));
+
+        let (left, right) = status.segments(&stage, true);
+        let text = |side: &[Segment]| side.iter().map(|(t, _)| t.clone()).collect::<Vec<_>>();
+
+        assert_eq!(text(&left), ["Text Stage", "INSERT", "[No file]", "[+]"]);
+        assert_eq!(text(&right), ["2:3", "UTF-8 LF"]);
+        assert_ne!(left[0].1, left[2].1);
+
+        let (left, _) = status.segments(&stage, false);
+        assert!(left.iter().all(|(_, face)| *face == status.inactive_face));
+    }
+}
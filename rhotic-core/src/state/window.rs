@@ -0,0 +1,435 @@
+// Space left between panes for the divider.
+pub const DIVIDER: usize = 2;
+
+// The smallest share of a split either side can be resized to.
+const MIN_RATIO: f32 = 0.1;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self { x, y, width, height }
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+
+    // Cuts the rect in two along `split`, with `ratio` of it going to the first
+    // half and a divider between them.
+    fn split(&self, split: Split, ratio: f32) -> (Rect, Rect) {
+        match split {
+            Split::Horizontal => {
+                let space = self.width.saturating_sub(DIVIDER);
+                let first = (space as f32 * ratio).round() as usize;
+                (
+                    Rect::new(self.x, self.y, first, self.height),
+                    Rect::new(self.x + first + DIVIDER, self.y, space - first, self.height)
+                )
+            },
+            Split::Vertical => {
+                let space = self.height.saturating_sub(DIVIDER);
+                let first = (space as f32 * ratio).round() as usize;
+                (
+                    Rect::new(self.x, self.y, self.width, first),
+                    Rect::new(self.x, self.y + first + DIVIDER, self.width, space - first)
+                )
+            }
+        }
+    }
+}
+
+// Like in Emacs, a horizontal split puts panes side by side, and a vertical
+// split stacks them.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Split {
+    Horizontal,
+    Vertical
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down
+}
+
+#[derive(Debug)]
+enum Node {
+    Pane {
+        id: usize,
+        // Index into the State's stages.
+        stage: usize
+    },
+    Split {
+        split: Split,
+        ratio: f32,
+        first: Box<Node>,
+        second: Box<Node>
+    }
+}
+
+#[derive(Debug)]
+pub struct Pane {
+    pub id: usize,
+    pub stage: usize,
+    pub rect: Rect
+}
+
+// The tiled layout of panes, each showing one stage. One pane is focused and
+// gets the input.
+#[derive(Debug)]
+pub struct WindowTree {
+    root: Node,
+    focused: usize,
+    next_id: usize
+}
+
+impl WindowTree {
+
+    pub fn new(stage: usize) -> Self {
+        Self {
+            root: Node::Pane { id: 0, stage },
+            focused: 0,
+            next_id: 1
+        }
+    }
+
+    pub fn focused(&self) -> usize {
+        self.focused
+    }
+
+    pub fn focused_stage(&self) -> usize {
+        let mut stage = 0;
+        self.root.visit(&mut |id, s| if id == self.focused { stage = s });
+        stage
+    }
+
+    // Shows another stage in the focused pane.
+    pub fn set_stage(&mut self, stage: usize) {
+        let focused = self.focused;
+        self.root.visit_mut(&mut |id, s| if id == focused { *s = stage });
+    }
+
+    // Every pane with the part of `area` it covers.
+    pub fn panes(&self, area: Rect) -> Vec<Pane> {
+        let mut out = Vec::new();
+        self.root.layout(area, &mut out);
+        out
+    }
+
+    pub fn pane_at(&self, area: Rect, x: usize, y: usize) -> Option<usize> {
+        self.panes(area).into_iter().find(|p| p.rect.contains(x, y)).map(|p| p.id)
+    }
+
+    pub fn focus(&mut self, id: usize) -> bool {
+        let mut exists = false;
+        self.root.visit(&mut |i, _| exists |= i == id);
+
+        if exists {
+            self.focused = id;
+        }
+        exists
+    }
+
+    // Focuses the next pane in layout order, wrapping around.
+    pub fn focus_next(&mut self) {
+        let mut ids = Vec::new();
+        self.root.visit(&mut |id, _| ids.push(id));
+
+        let i = ids.iter().position(|id| *id == self.focused).unwrap_or(0);
+        self.focused = ids[(i + 1) % ids.len()];
+    }
+
+    // Focuses the closest pane on that side of the focused one, among those
+    // that overlap it across the direction of motion.
+    pub fn focus_direction(&mut self, area: Rect, direction: Direction) -> bool {
+        let panes = self.panes(area);
+        let current = match panes.iter().find(|p| p.id == self.focused) {
+            Some(p) => p.rect,
+            None => return false
+        };
+
+        let overlaps = |a: (usize, usize), b: (usize, usize)| a.0 < b.0 + b.1 && b.0 < a.0 + a.1;
+
+        let target = panes.iter().filter_map(|p| {
+            let r = p.rect;
+            let distance = match direction {
+                Direction::Left if r.x + r.width <= current.x => current.x - (r.x + r.width),
+                Direction::Right if r.x >= current.x + current.width => r.x - (current.x + current.width),
+                Direction::Up if r.y + r.height <= current.y => current.y - (r.y + r.height),
+                Direction::Down if r.y >= current.y + current.height => r.y - (current.y + current.height),
+                _ => return None
+            };
+
+            let across = match direction {
+                Direction::Left | Direction::Right => overlaps((r.y, r.height), (current.y, current.height)),
+                Direction::Up | Direction::Down => overlaps((r.x, r.width), (current.x, current.width))
+            };
+
+            across.then_some((distance, p.id))
+        }).min();
+
+        match target {
+            Some((_, id)) => {
+                self.focused = id;
+                true
+            },
+            None => false
+        }
+    }
+
+    // Splits the focused pane in two, both showing its stage. The focus stays
+    // in the first half.
+    pub fn split(&mut self, split: Split) -> usize {
+        let (focused, id) = (self.focused, self.next_id);
+        self.next_id += 1;
+
+        self.root.replace(focused, &mut |node| {
+            let stage = match node {
+                Node::Pane { stage, .. } => stage,
+                Node::Split { .. } => unreachable!()
+            };
+
+            Node::Split {
+                split,
+                ratio: 0.5,
+                first: Box::new(node),
+                second: Box::new(Node::Pane { id, stage })
+            }
+        });
+        id
+    }
+
+    // Closes the focused pane, giving its space to its sibling. The last pane
+    // can't be closed.
+    pub fn close(&mut self) -> bool {
+        if matches!(self.root, Node::Pane { .. }) {
+            return false;
+        }
+
+        let focused = self.focused;
+        // The pane that takes over the space gets the focus.
+        self.focused = self.root.sibling(focused).unwrap_or(0);
+
+        let root = std::mem::replace(&mut self.root, Node::Pane { id: 0, stage: 0 });
+        self.root = root.remove(focused).unwrap_or(Node::Pane { id: 0, stage: 0 });
+        true
+    }
+
+    // Closes every pane but the focused one.
+    pub fn close_others(&mut self) {
+        let stage = self.focused_stage();
+        self.root = Node::Pane { id: self.focused, stage };
+    }
+
+    // Grows the focused pane by `delta` of its innermost split of that kind,
+    // or shrinks it for a negative `delta`.
+    pub fn resize(&mut self, split: Split, delta: f32) -> bool {
+        self.root.resize(self.focused, split, delta).unwrap_or(false)
+    }
+
+    // Gives every pane in a row or column of same-kind splits an equal share.
+    pub fn balance(&mut self) {
+        self.root.balance();
+    }
+
+    // Keeps pane stage indices valid after the stage at `removed` is closed.
+    // Panes that showed it switch to `replacement`, an index from before.
+    pub fn remove_stage(&mut self, removed: usize, replacement: usize) {
+        let replacement = if replacement > removed { replacement - 1 } else { replacement };
+
+        self.root.visit_mut(&mut |_, stage| {
+            if *stage == removed {
+                *stage = replacement;
+            } else if *stage > removed {
+                *stage -= 1;
+            }
+        });
+    }
+}
+
+impl Node {
+
+    fn visit(&self, f: &mut impl FnMut(usize, usize)) {
+        match self {
+            Node::Pane { id, stage } => f(*id, *stage),
+            Node::Split { first, second, .. } => {
+                first.visit(f);
+                second.visit(f);
+            }
+        }
+    }
+
+    fn visit_mut(&mut self, f: &mut impl FnMut(usize, &mut usize)) {
+        match self {
+            Node::Pane { id, stage } => f(*id, stage),
+            Node::Split { first, second, .. } => {
+                first.visit_mut(f);
+                second.visit_mut(f);
+            }
+        }
+    }
+
+    fn layout(&self, area: Rect, out: &mut Vec<Pane>) {
+        match self {
+            Node::Pane { id, stage } => out.push(Pane { id: *id, stage: *stage, rect: area }),
+            Node::Split { split, ratio, first, second } => {
+                let (a, b) = area.split(*split, *ratio);
+                first.layout(a, out);
+                second.layout(b, out);
+            }
+        }
+    }
+
+    fn contains(&self, pane: usize) -> bool {
+        let mut found = false;
+        self.visit(&mut |id, _| found |= id == pane);
+        found
+    }
+
+    fn first_pane(&self) -> usize {
+        match self {
+            Node::Pane { id, .. } => *id,
+            Node::Split { first, .. } => first.first_pane()
+        }
+    }
+
+    // The first pane of the other half of the split right above `pane`.
+    fn sibling(&self, pane: usize) -> Option<usize> {
+        match self {
+            Node::Pane { .. } => None,
+            Node::Split { first, second, .. } => match (first.as_ref(), second.as_ref()) {
+                (Node::Pane { id, .. }, other) | (other, Node::Pane { id, .. }) if *id == pane => Some(other.first_pane()),
+                _ => first.sibling(pane).or_else(|| second.sibling(pane))
+            }
+        }
+    }
+
+    fn replace(&mut self, pane: usize, f: &mut impl FnMut(Node) -> Node) {
+        match self {
+            Node::Pane { id, .. } if *id == pane => {
+                let node = std::mem::replace(self, Node::Pane { id: pane, stage: 0 });
+                *self = f(node);
+            },
+            Node::Pane { .. } => {},
+            Node::Split { first, second, .. } => {
+                first.replace(pane, f);
+                second.replace(pane, f);
+            }
+        }
+    }
+
+    // The tree without the pane, or None if the pane was all of it.
+    fn remove(self, pane: usize) -> Option<Node> {
+        match self {
+            Node::Pane { id, .. } if id == pane => None,
+            Node::Pane { .. } => Some(self),
+            Node::Split { split, ratio, first, second } => match (first.remove(pane), second.remove(pane)) {
+                (Some(first), Some(second)) => Some(Node::Split { split, ratio, first: Box::new(first), second: Box::new(second) }),
+                (Some(only), None) | (None, Some(only)) => Some(only),
+                (None, None) => None
+            }
+        }
+    }
+
+    // None if the pane isn't in this node. Otherwise whether a split of that
+    // kind above it was found and resized.
+    fn resize(&mut self, pane: usize, kind: Split, delta: f32) -> Option<bool> {
+        match self {
+            Node::Pane { id, .. } => (*id == pane).then_some(false),
+            Node::Split { split, ratio, first, second } => {
+                let in_first = first.contains(pane);
+                let inner = if in_first {
+                    first.resize(pane, kind, delta)
+                } else {
+                    second.resize(pane, kind, delta)
+                }?;
+
+                if inner || *split != kind {
+                    return Some(inner);
+                }
+
+                let delta = if in_first { delta } else { -delta };
+                *ratio = (*ratio + delta).clamp(MIN_RATIO, 1.0 - MIN_RATIO);
+                Some(true)
+            }
+        }
+    }
+
+    // How many panes share the space along a chain of `kind` splits.
+    fn weight(&self, kind: Split) -> usize {
+        match self {
+            Node::Split { split, first, second, .. } if *split == kind => first.weight(kind) + second.weight(kind),
+            _ => 1
+        }
+    }
+
+    fn balance(&mut self) {
+        if let Node::Split { split, ratio, first, second } = self {
+            let (a, b) = (first.weight(*split), second.weight(*split));
+            *ratio = a as f32 / (a + b) as f32;
+
+            first.balance();
+            second.balance();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const AREA: Rect = Rect { x: 0, y: 0, width: 202, height: 100 };
+
+    #[test]
+    fn split_close_and_focus() {
+        let mut tree = WindowTree::new(0);
+
+        let right = tree.split(Split::Horizontal);
+        let panes = tree.panes(AREA);
+        assert_eq!(panes[0].rect, Rect::new(0, 0, 100, 100));
+        assert_eq!(panes[1].rect, Rect::new(102, 0, 100, 100));
+
+        assert!(tree.focus_direction(AREA, Direction::Right));
+        assert_eq!(tree.focused(), right);
+        assert!(!tree.focus_direction(AREA, Direction::Right));
+
+        let below = tree.split(Split::Vertical);
+        tree.set_stage(1);
+        assert_eq!(tree.pane_at(AREA, 150, 90), Some(below));
+        assert!(tree.focus_direction(AREA, Direction::Left));
+        assert_eq!(tree.focused(), 0);
+
+        tree.focus(right);
+        assert_eq!(tree.focused_stage(), 1);
+        assert!(tree.close());
+        assert_eq!(tree.panes(AREA).iter().map(|p| p.id).collect::<Vec<_>>(), [0, below]);
+        assert_eq!(tree.focused(), below);
+    }
+
+    #[test]
+    fn resize_and_balance() {
+        let mut tree = WindowTree::new(0);
+        tree.split(Split::Horizontal);
+        tree.split(Split::Horizontal);
+
+        tree.balance();
+        let widths: Vec<usize> = tree.panes(Rect::new(0, 0, 304, 10)).iter().map(|p| p.rect.width).collect();
+        assert!(widths.iter().all(|w| w.abs_diff(100) <= 1));
+
+        assert!(tree.resize(Split::Horizontal, 0.25));
+        assert!(!tree.resize(Split::Vertical, 0.25));
+        assert!(tree.panes(Rect::new(0, 0, 304, 10))[0].rect.width > 100);
+
+        tree.remove_stage(0, 0);
+        assert!(tree.panes(AREA).iter().all(|p| p.stage == 0));
+    }
+}
@@ -0,0 +1,368 @@
+use std::{fs, path::PathBuf, str::FromStr};
+
+use anyhow::bail;
+use fontdue::layout::{Layout, LayoutSettings, TextStyle};
+use ropey::Rope;
+
+use crate::{file::encoding::decode, buffer::{diff::{diff_lines, DiffKind}, history::History, stage::{Stage, Render, get_image, InputEvent, StateCommand}}, display::{font::FontManager, Rgba, glyph_cache::GlyphImage, event_loop::Key}};
+
+mod theme;
+
+// Lines of unchanged text shown around each change in the preview.
+const CONTEXT_LINES: usize = 2;
+
+// Browses an undo tree. Each state is listed with the time it was made, and
+// the selected one is previewed as a diff against the current text. Opened
+// from a text stage it shows that page, and Enter takes the page to the
+// selected state; started by path it shows the file's saved tree.
+pub struct UndoTreeView {
+    name: String,
+    text: Rope,
+    history: History,
+    // Whether a text stage opened it, so Enter can go back to it.
+    from_stage: bool,
+    entries: Vec<Entry>,
+    cursor: usize,
+    scroll_top: usize,
+    scroll_window_len: usize,
+    preview: Option<(usize, Vec<(DiffKind, String)>)>,
+    theme: theme::UndoTreeTheme
+}
+
+struct Entry {
+    node: usize,
+    column: usize
+}
+
+impl UndoTreeView {
+
+    // The tree of a page with `text` as its current state.
+    pub fn new(name: String, text: Rope, history: History) -> Self {
+        let mut view = Self {
+            name,
+            text,
+            history,
+            from_stage: true,
+            entries: Vec::new(),
+            cursor: 0,
+            scroll_top: 0,
+            scroll_window_len: 40,
+            preview: None,
+            theme: Default::default()
+        };
+
+        view.update_entries();
+        view.update_preview();
+        view
+    }
+
+    // Newest state first. A node's first child continues its column, and each
+    // later one starts a branch in a column of its own.
+    fn update_entries(&mut self) {
+        let nodes = self.history.nodes();
+        let mut columns = vec![0; nodes.len()];
+        let mut next_column = 1;
+
+        for (index, node) in nodes.iter().enumerate() {
+            for (i, child) in node.children.iter().enumerate() {
+                columns[*child] = if i == 0 {
+                    columns[index]
+                } else {
+                    next_column += 1;
+                    next_column - 1
+                };
+            }
+        }
+
+        self.entries = (0..nodes.len()).rev().map(|node| Entry { node, column: columns[node] }).collect();
+
+        if let Some(i) = self.entries.iter().position(|e| e.node == self.history.current()) {
+            self.cursor = i;
+        }
+    }
+
+    fn entry_text(&self, entry: &Entry) -> String {
+        let node = &self.history.nodes()[entry.node];
+
+        let marker = if entry.node == self.history.current() { '*' } else { 'o' };
+        let time = format_time(node.time);
+
+        let (mut added, mut removed) = (0, 0);
+        for edit in node.transaction.edits.iter() {
+            use crate::buffer::history::Edit;
+            match edit {
+                Edit::Insert { text, .. } => added += text.chars().count(),
+                Edit::Remove { text, .. } => removed += text.chars().count()
+            }
+        }
+
+        if entry.node == 0 {
+            format!("{}{marker} #0  {time}  original", "| ".repeat(entry.column))
+        } else {
+            format!("{}{marker} #{}  {time}  +{added} -{removed}", "| ".repeat(entry.column), entry.node)
+        }
+    }
+
+    fn update_preview(&mut self) {
+        let node = match self.entries.get(self.cursor) {
+            Some(e) => e.node,
+            None => return
+        };
+
+        if let Some((n, _)) = &self.preview {
+            if *n == node {
+                return;
+            }
+        }
+
+        let state = self.history.text_at(&self.text, node).to_string();
+        let current = self.text.to_string();
+
+        let old: Vec<&str> = current.split('\n').collect();
+        let new: Vec<&str> = state.split('\n').collect();
+        let diff = match diff_lines(&old, &new) {
+            Some(diff) => diff,
+            None => {
+                let text = format!("{} lines become {}, too many changes to show.", old.len(), new.len());
+                self.preview = Some((node, vec![(DiffKind::Same, text)]));
+                return;
+            }
+        };
+
+        let mut out = Vec::new();
+        let mut old_line = 0;
+        let mut new_line = 0;
+        let mut last_shown = None;
+
+        for (i, line) in diff.iter().enumerate() {
+            let near_change = diff[i.saturating_sub(CONTEXT_LINES)..(i + CONTEXT_LINES + 1).min(diff.len())]
+                .iter()
+                .any(|l| l.kind != DiffKind::Same);
+
+            if near_change {
+                if last_shown.map(|l| l + 1) != Some(i) {
+                    out.push((DiffKind::Same, format!("@@ -{} +{} @@", old_line + 1, new_line + 1)));
+                }
+                let prefix = match line.kind {
+                    DiffKind::Same => ' ',
+                    DiffKind::Added => '+',
+                    DiffKind::Removed => '-'
+                };
+                out.push((line.kind, format!("{prefix}{}", line.text)));
+                last_shown = Some(i);
+            }
+
+            match line.kind {
+                DiffKind::Same => {
+                    old_line += 1;
+                    new_line += 1;
+                },
+                DiffKind::Added => new_line += 1,
+                DiffKind::Removed => old_line += 1
+            }
+        }
+
+        if out.is_empty() {
+            out.push((DiffKind::Same, String::from("No changes from the current text.")));
+        }
+
+        self.preview = Some((node, out));
+    }
+}
+
+impl Stage for UndoTreeView {
+
+    fn init(init_args: &[&str]) -> anyhow::Result<Self> {
+
+        let path = if let Some(path) = init_args.first() {
+            PathBuf::from_str(path)?
+        } else {
+            bail!("Tried to open the Undo Tree without a path. A file path is needed!")
+        };
+
+        let text = Rope::from_str(&decode(&fs::read(&path)?).0);
+
+        let history = match History::load(&path, &text)? {
+            Some(h) => h,
+            None => bail!("There is no saved undo history for {}.", path.display())
+        };
+
+        Ok(Self { from_stage: false, ..Self::new(path.display().to_string(), text, history) })
+    }
+
+    fn send_event(&mut self, input: InputEvent) -> StateCommand {
+
+        use Key::*;
+        use InputEvent::*;
+
+        match input {
+            Press(k) | Echo(k) => match k {
+                Arrowdown if self.cursor + 1 < self.entries.len() => {
+                    self.cursor += 1;
+
+                    if self.cursor >= self.scroll_top + self.scroll_window_len {
+                        self.scroll_top += 1;
+                    }
+                },
+                Arrowup if self.cursor != 0 => {
+                    self.cursor -= 1;
+
+                    if self.cursor < self.scroll_top {
+                        self.scroll_top = self.cursor;
+                    }
+                },
+                Enter => match self.entries.get(self.cursor) {
+                    Some(entry) if self.from_stage => {
+                        return StateCommand::Return(vec![String::from("goto_state"), entry.node.to_string()]);
+                    },
+                    Some(entry) => {
+                        return StateCommand::Log(format!(
+                            "State #{} of {}. Use `goto_state {}` in its text stage to restore it.",
+                            entry.node, self.name, entry.node
+                        ));
+                    },
+                    None => {}
+                },
+                _ => {}
+            },
+            _ => {}
+        }
+
+        self.update_preview();
+        StateCommand::None
+    }
+
+    const NAME: &'static str = "Undo Tree";
+}
+
+impl Render<&mut FontManager> for UndoTreeView {
+    fn render(&mut self, canvas: &mut crate::display::text_render::Canvas, v: &mut FontManager) {
+
+        let half = canvas.width() / 2;
+
+        let mut list: Layout<Rgba> = Layout::new(fontdue::layout::CoordinateSystem::PositiveYDown);
+
+        for i in self.scroll_top..(self.scroll_top + self.scroll_window_len).min(self.entries.len()) {
+            let entry = &self.entries[i];
+            let color = if entry.node == self.history.current() { self.theme.current_color } else { self.theme.entry_color };
+
+            let text = self.entry_text(entry) + "\n";
+            v.append(&mut list, &TextStyle { text: &text, px: v.scale, font_index: 0, user_data: color });
+        }
+
+        if let Some(lines) = list.lines() {
+            if let Some(first) = lines.first() {
+                self.scroll_window_len = (canvas.height() / first.max_new_line_size.max(1.0) as usize).max(1);
+            }
+
+            if let Some(line) = lines.get(self.cursor - self.scroll_top) {
+                canvas.draw_rectangle(
+                    0,
+                    line.baseline_y as isize - line.max_ascent as isize,
+                    half,
+                    line.max_new_line_size as usize,
+                    self.theme.select_color
+                );
+            }
+        }
+
+        let selected_line = self.cursor - self.scroll_top;
+        let mut line = 0;
+
+        for glyph in list.glyphs() {
+            if glyph.parent == '\n' {
+                line += 1;
+            }
+
+            if !glyph.char_data.rasterize() {
+                continue;
+            }
+
+            let back = if line == selected_line { self.theme.select_color } else { Rgba::DARK_GRAY };
+            let (_metrics, image) = get_image(glyph, v);
+            canvas.draw_monochrome_image::<GlyphImage, u8>(glyph.x as isize, glyph.y as isize, &image, back, glyph.user_data);
+        }
+
+        let mut preview: Layout<Rgba> = Layout::new(fontdue::layout::CoordinateSystem::PositiveYDown);
+        preview.reset(&LayoutSettings { x: half as f32 + 10.0, ..Default::default() });
+
+        let header = format!("{}\n", self.name);
+        v.append(&mut preview, &TextStyle { text: &header, px: v.scale, font_index: 0, user_data: self.theme.header_color });
+
+        if let Some((_, lines)) = &self.preview {
+            for (kind, text) in lines.iter().take(self.scroll_window_len.saturating_sub(1)) {
+                let color = match kind {
+                    DiffKind::Same if text.starts_with("@@") => self.theme.header_color,
+                    DiffKind::Same => self.theme.context_color,
+                    DiffKind::Added => self.theme.added_color,
+                    DiffKind::Removed => self.theme.removed_color
+                };
+
+                let text = format!("{text}\n");
+                v.append(&mut preview, &TextStyle { text: &text, px: v.scale, font_index: 0, user_data: color });
+            }
+        }
+
+        for glyph in preview.glyphs() {
+            if !glyph.char_data.rasterize() {
+                continue;
+            }
+
+            let (_metrics, image) = get_image(glyph, v);
+            canvas.draw_monochrome_image::<GlyphImage, u8>(glyph.x as isize, glyph.y as isize, &image, Rgba::DARK_GRAY, glyph.user_data);
+        }
+    }
+}
+
+// Formats seconds since the unix epoch as a UTC "YYYY-MM-DD HH:MM:SS".
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Days to a civil date, from Howard Hinnant's date algorithms.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}", rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer::text_buffer::Page;
+
+    #[test]
+    fn branches_and_preview() {
+        let mut page = Page::from("ab");
+        page.insert_char(0, 2, 'c').unwrap();
+        page.undo();
+        page.insert_char(0, 0, 'x').unwrap();
+        page.undo();
+        page.insert_char(0, 0, 'y').unwrap();
+
+        let mut view = UndoTreeView::new(String::from("page"), page.rope().clone(), page.history().clone());
+
+        // Each branch off the root gets a column of its own.
+        let columns: Vec<(usize, usize)> = view.entries.iter().map(|e| (e.node, e.column)).collect();
+        assert_eq!(columns, [(3, 2), (2, 1), (1, 0), (0, 0)]);
+        assert_eq!(view.entries[view.cursor].node, 3);
+
+        // The preview is against the text of the page, which was never saved.
+        view.send_event(InputEvent::Press(Key::Arrowdown));
+        let lines: Vec<&str> = view.preview.as_ref().unwrap().1.iter().map(|(_, l)| l.as_str()).collect();
+        assert_eq!(lines, ["@@ -1 +1 @@", "-yab", "+xab"]);
+
+        // Enter goes back to the page, at the selected state.
+        match view.send_event(InputEvent::Press(Key::Enter)) {
+            StateCommand::Return(line) => assert_eq!(line, ["goto_state", "2"]),
+            _ => panic!("Enter should return to the page.")
+        }
+    }
+}
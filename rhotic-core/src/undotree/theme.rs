@@ -0,0 +1,27 @@
+use crate::display::types::Rgba;
+
+
+#[derive(Debug)]
+pub struct UndoTreeTheme {
+    pub entry_color: Rgba,
+    pub current_color: Rgba,
+    pub select_color: Rgba,
+    pub added_color: Rgba,
+    pub removed_color: Rgba,
+    pub context_color: Rgba,
+    pub header_color: Rgba
+}
+
+impl Default for UndoTreeTheme {
+    fn default() -> Self {
+        Self {
+            entry_color: Rgba::WHITE,
+            current_color: Rgba::YELLOW,
+            select_color: Rgba::new_opaque(0x60, 0xAF, 0xFF),
+            added_color: Rgba::GREEN,
+            removed_color: Rgba::RED,
+            context_color: Rgba::GRAY,
+            header_color: Rgba::CYAN
+        }
+    }
+}